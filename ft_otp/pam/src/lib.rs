@@ -0,0 +1,260 @@
+//! `pam_ft_otp.so`: TOTP/HOTP second factor for Linux-PAM.
+//!
+//! ```text
+//! auth required pam_ft_otp.so [file=/etc/ft_otp/users/%u.key]
+//!     [passphrase_file=/etc/ft_otp/pam.passphrase] [window=1] [nullok]
+//! ```
+//!
+//! Keys are written by `ft_otp pam enroll USER`, which prints the QR code to
+//! scan and the recovery codes. Accepted codes are recorded in the key file,
+//! so each one works once.
+
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use ft_otp::{
+    SystemClock,
+    login::{self, Config, Outcome},
+};
+use zeroize::Zeroize;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_SERVICE_ERR: c_int = 3;
+const PAM_SYSTEM_ERR: c_int = 4;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_IGNORE: c_int = 25;
+
+const PAM_CONV: c_int = 5;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+
+const PROMPT: &CStr = c"Verification code: ";
+
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = unsafe extern "C" fn(
+    c_int,
+    *mut *const PamMessage,
+    *mut *mut PamResponse,
+    *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: Option<ConvFn>,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "libpam.so.0", kind = "dylib", modifiers = "+verbatim")]
+unsafe extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char)
+    -> c_int;
+    fn pam_get_item(pamh: *const PamHandle, item_type: c_int, item: *mut *const c_void) -> c_int;
+}
+
+/// Logs to the authentication facility, without the code or any secret.
+fn log(message: &str) {
+    if let Ok(message) = CString::new(format!("pam_ft_otp: {}", message)) {
+        unsafe {
+            libc::syslog(
+                libc::LOG_AUTHPRIV | libc::LOG_NOTICE,
+                c"%s".as_ptr(),
+                message.as_ptr(),
+            )
+        };
+    }
+}
+
+fn user(pamh: *mut PamHandle) -> Result<String, c_int> {
+    let mut user = ptr::null();
+    let status = unsafe { pam_get_user(pamh, &mut user, ptr::null()) };
+    if status != PAM_SUCCESS || user.is_null() {
+        return Err(status);
+    }
+    let user = unsafe { CStr::from_ptr(user) };
+    user.to_str().map(str::to_string).map_err(|_| PAM_AUTH_ERR)
+}
+
+/// Asks the application to prompt for the code without echo. The answer is
+/// wiped and freed here.
+fn ask_code(pamh: *mut PamHandle) -> Result<String, c_int> {
+    let mut item = ptr::null();
+    let status = unsafe { pam_get_item(pamh, PAM_CONV, &mut item) };
+    if status != PAM_SUCCESS || item.is_null() {
+        return Err(PAM_CONV_ERR);
+    }
+    let conversation = unsafe { &*(item as *const PamConv) };
+    let conv = conversation.conv.ok_or(PAM_CONV_ERR)?;
+
+    let message = PamMessage {
+        msg_style: PAM_PROMPT_ECHO_OFF,
+        msg: PROMPT.as_ptr(),
+    };
+    let mut messages = [&message as *const PamMessage];
+    let mut response: *mut PamResponse = ptr::null_mut();
+    let status = unsafe {
+        conv(
+            1,
+            messages.as_mut_ptr(),
+            &mut response,
+            conversation.appdata_ptr,
+        )
+    };
+    if status != PAM_SUCCESS || response.is_null() {
+        return Err(PAM_CONV_ERR);
+    }
+
+    let answer = unsafe { (*response).resp };
+    let code = if answer.is_null() {
+        Err(PAM_CONV_ERR)
+    } else {
+        let text = unsafe { CStr::from_ptr(answer) };
+        let code = text.to_str().map(str::to_string).map_err(|_| PAM_AUTH_ERR);
+        unsafe {
+            let bytes = std::slice::from_raw_parts_mut(answer as *mut u8, text.count_bytes());
+            bytes.zeroize();
+            libc::free(answer as *mut c_void);
+        }
+        code
+    };
+    unsafe { libc::free(response as *mut c_void) };
+    code
+}
+
+fn args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a str> {
+    (0..argc.max(0) as usize)
+        .filter_map(|i| {
+            let arg = unsafe { *argv.add(i) };
+            (!arg.is_null()).then(|| unsafe { CStr::from_ptr(arg) })
+        })
+        .filter_map(|arg| arg.to_str().ok())
+        .collect()
+}
+
+fn authenticate(pamh: *mut PamHandle, args: Vec<&str>) -> c_int {
+    let config = match Config::parse(args) {
+        Ok(config) => config,
+        Err(e) => {
+            log(&e.to_string());
+            return PAM_SERVICE_ERR;
+        }
+    };
+    let user = match user(pamh) {
+        Ok(user) => user,
+        Err(status) => return status,
+    };
+    let path = match login::key_path(&config.key_path, &user, || login::home_dir(&user)) {
+        Ok(path) => path,
+        Err(e) => {
+            log(&e.to_string());
+            return PAM_AUTH_ERR;
+        }
+    };
+    if config.nullok {
+        match login::key_file_exists(&path) {
+            Ok(false) => return PAM_IGNORE,
+            Ok(true) => {}
+            Err(e) => {
+                log(&format!("{}: {}", user, e));
+                return PAM_AUTHINFO_UNAVAIL;
+            }
+        }
+    }
+
+    // Users without a key are still asked for a code, so the prompt does not
+    // tell who is enrolled.
+    let mut code = match ask_code(pamh) {
+        Ok(code) => code,
+        Err(status) => return status,
+    };
+    let passphrase = match login::read_passphrase(&config.passphrase_file) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            code.zeroize();
+            log(&e.to_string());
+            return PAM_AUTHINFO_UNAVAIL;
+        }
+    };
+    let outcome = login::verify(
+        &path,
+        &user,
+        passphrase.expose(),
+        &code,
+        config.window,
+        &SystemClock,
+    );
+    code.zeroize();
+
+    match outcome {
+        Ok(Outcome::Accepted) => PAM_SUCCESS,
+        Ok(Outcome::Recovered { remaining }) => {
+            log(&format!(
+                "recovery code used by {}, {} left",
+                user, remaining
+            ));
+            PAM_SUCCESS
+        }
+        Ok(Outcome::Rejected) => {
+            log(&format!("invalid code for {}", user));
+            PAM_AUTH_ERR
+        }
+        Ok(Outcome::Replayed) => {
+            log(&format!("replayed code for {}", user));
+            PAM_AUTH_ERR
+        }
+        Ok(Outcome::NotEnrolled) => {
+            log(&format!("no key for {} at {}", user, path.display()));
+            PAM_AUTH_ERR
+        }
+        Err(e) => {
+            log(&format!("{}: {}", user, e));
+            PAM_AUTHINFO_UNAVAIL
+        }
+    }
+}
+
+/// # Safety
+///
+/// Called by libpam with a valid handle and `argc` argument strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let args = args(argc, argv);
+    panic::catch_unwind(AssertUnwindSafe(|| authenticate(pamh, args))).unwrap_or(PAM_SYSTEM_ERR)
+}
+
+/// # Safety
+///
+/// Called by libpam; there are no credentials to set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
@@ -0,0 +1,242 @@
+//! Drives the module through libpam the way `pamtester` would, with
+//! throwaway services in a private configuration directory
+//! (`pam_start_confdir`), so nothing under /etc/pam.d is touched.
+
+use std::{
+    ffi::{CString, c_char, c_int, c_void},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use ft_otp::{KeyFile, TotpParams, login, totp};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_SERVICE_ERR: c_int = 3;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: unsafe extern "C" fn(
+        c_int,
+        *mut *const PamMessage,
+        *mut *mut PamResponse,
+        *mut c_void,
+    ) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "libpam.so.0", kind = "dylib", modifiers = "+verbatim")]
+unsafe extern "C" {
+    fn pam_start_confdir(
+        service: *const c_char,
+        user: *const c_char,
+        conv: *const PamConv,
+        confdir: *const c_char,
+        pamh: *mut *mut c_void,
+    ) -> c_int;
+    fn pam_authenticate(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_end(pamh: *mut c_void, status: c_int) -> c_int;
+}
+
+/// Answers every prompt with the code in `appdata`, like a user typing it.
+unsafe extern "C" fn answer(
+    count: c_int,
+    _messages: *mut *const PamMessage,
+    responses: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int {
+    unsafe {
+        let code = appdata as *const c_char;
+        let array = libc::calloc(count as usize, size_of::<PamResponse>()) as *mut PamResponse;
+        for i in 0..count as usize {
+            (*array.add(i)).resp = libc::strdup(code);
+        }
+        *responses = array;
+    }
+    PAM_SUCCESS
+}
+
+struct Setup {
+    dir: PathBuf,
+    secret: Vec<u8>,
+}
+
+impl Setup {
+    fn new(name: &str) -> Setup {
+        let dir = std::env::temp_dir().join(format!("pam_ft_otp-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("pam.d")).unwrap();
+
+        let passphrase = dir.join("pam.passphrase");
+        fs::write(&passphrase, "system passphrase\n").unwrap();
+        fs::set_permissions(&passphrase, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let secret = b"12345678901234567890".to_vec();
+        let key = KeyFile::new(secret.clone(), TotpParams::default());
+        login::enroll(
+            &dir.join("alice.key"),
+            "alice",
+            "system passphrase",
+            &key,
+            false,
+        )
+        .unwrap();
+        Setup { dir, secret }
+    }
+
+    /// Writes `/etc/pam.d`-style service `name` in the private directory.
+    fn service(&self, name: &str, lines: &str) {
+        let module = module_path();
+        let content = lines
+            .replace("MODULE", &module.to_string_lossy())
+            .replace("DIR", &self.dir.to_string_lossy());
+        fs::write(self.dir.join("pam.d").join(name), content).unwrap();
+    }
+
+    fn authenticate(&self, service: &str, user: &str, code: &str) -> c_int {
+        let code = CString::new(code).unwrap();
+        let conv = PamConv {
+            conv: answer,
+            appdata_ptr: code.as_ptr() as *mut c_void,
+        };
+        let service = CString::new(service).unwrap();
+        let user = CString::new(user).unwrap();
+        let confdir = CString::new(self.dir.join("pam.d").to_string_lossy().as_bytes()).unwrap();
+        let mut pamh = ptr::null_mut();
+        unsafe {
+            let status = pam_start_confdir(
+                service.as_ptr(),
+                user.as_ptr(),
+                &conv,
+                confdir.as_ptr(),
+                &mut pamh,
+            );
+            assert_eq!(status, PAM_SUCCESS, "pam_start_confdir failed");
+            let status = pam_authenticate(pamh, 0);
+            pam_end(pamh, status);
+            status
+        }
+    }
+
+    fn code(&self) -> String {
+        totp::totp(&self.secret, &TotpParams::default()).unwrap()
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The module built next to this test, in `target/<profile>/`.
+fn module_path() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let profile = exe.parent().and_then(Path::parent).unwrap();
+    profile.join("libpam_ft_otp.so")
+}
+
+const SERVICE: &str =
+    "auth required MODULE file=DIR/%u.key passphrase_file=DIR/pam.passphrase window=1\n";
+
+#[test]
+fn accepts_a_code_once() {
+    let setup = Setup::new("once");
+    setup.service("ft_otp-test", SERVICE);
+
+    let code = setup.code();
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", &code),
+        PAM_SUCCESS
+    );
+    // Replay protection: the step is recorded in the key file.
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", &code),
+        PAM_AUTH_ERR
+    );
+
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", wrong),
+        PAM_AUTH_ERR
+    );
+}
+
+#[test]
+fn accepts_a_recovery_code_once() {
+    let setup = Setup::new("recovery");
+    setup.service("ft_otp-test", SERVICE);
+    let codes = login::regenerate_recovery_codes(
+        &setup.dir.join("alice.key"),
+        "alice",
+        "system passphrase",
+        2,
+    )
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", &codes[1]),
+        PAM_SUCCESS
+    );
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", &codes[1]),
+        PAM_AUTH_ERR
+    );
+}
+
+#[test]
+fn users_without_a_key() {
+    let setup = Setup::new("nullok");
+    setup.service("ft_otp-test", SERVICE);
+    setup.service(
+        "ft_otp-nullok",
+        &format!(
+            "{}auth required pam_permit.so\n",
+            SERVICE.replace("window=1", "window=1 nullok")
+        ),
+    );
+
+    let code = setup.code();
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "bob", &code),
+        PAM_AUTH_ERR
+    );
+    assert_eq!(
+        setup.authenticate("ft_otp-nullok", "bob", &code),
+        PAM_SUCCESS
+    );
+}
+
+#[test]
+fn refuses_bad_configurations() {
+    let setup = Setup::new("config");
+    setup.service("ft_otp-typo", "auth required MODULE windw=1\n");
+    assert_eq!(
+        setup.authenticate("ft_otp-typo", "alice", &setup.code()),
+        PAM_SERVICE_ERR
+    );
+
+    setup.service("ft_otp-test", SERVICE);
+    let passphrase = setup.dir.join("pam.passphrase");
+    fs::set_permissions(&passphrase, fs::Permissions::from_mode(0o644)).unwrap();
+    assert_eq!(
+        setup.authenticate("ft_otp-test", "alice", &setup.code()),
+        PAM_AUTHINFO_UNAVAIL
+    );
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    error::Result,
+    keyfile::{KeyFile, OtpKind},
+    storage::Lock,
+    vault::Vault,
+};
+
+/// An account opened from a standalone key file or from a vault entry, so
+/// commands that update it (HOTP counters, ...) save it back where it came from.
+pub enum Account {
+    File {
+        path: PathBuf,
+        key: KeyFile,
+    },
+    Vault {
+        path: PathBuf,
+        vault: Vault,
+        label: String,
+        index: usize,
+    },
+}
+
+impl Account {
+    pub fn open_file(path: &Path, passphrase: &str) -> Result<Account> {
+        Ok(Account::File {
+            path: path.to_path_buf(),
+            key: KeyFile::load(path, passphrase)?,
+        })
+    }
+
+    pub fn open_vault(path: &Path, label: &str, passphrase: &str) -> Result<Account> {
+        let vault = Vault::load(path, passphrase)?;
+        let index = vault.position(label)?;
+        Ok(Account::Vault {
+            path: path.to_path_buf(),
+            vault,
+            label: label.to_string(),
+            index,
+        })
+    }
+
+    /// The key file or vault the account is stored in.
+    pub fn path(&self) -> &Path {
+        match self {
+            Account::File { path, .. } | Account::Vault { path, .. } => path,
+        }
+    }
+
+    pub fn key(&self) -> &KeyFile {
+        match self {
+            Account::File { key, .. } => key,
+            Account::Vault { vault, index, .. } => &vault.entries[*index].key,
+        }
+    }
+
+    pub fn key_mut(&mut self) -> &mut KeyFile {
+        match self {
+            Account::File { key, .. } => key,
+            Account::Vault { vault, index, .. } => &mut vault.entries[*index].key,
+        }
+    }
+
+    pub fn save(&self, passphrase: &str) -> Result<()> {
+        match self {
+            Account::File { path, key } => key.save(path, passphrase),
+            Account::Vault { path, vault, .. } => vault.save(path, passphrase),
+        }
+    }
+
+    /// Reads the account again from its store, dropping what was loaded
+    /// before.
+    fn reload(&mut self, passphrase: &str) -> Result<()> {
+        *self = match self {
+            Account::File { path, .. } => Account::open_file(path, passphrase)?,
+            Account::Vault { path, label, .. } => Account::open_vault(path, label, passphrase)?,
+        };
+        Ok(())
+    }
+
+    /// Locks the store, reloads the account from it, applies `change` and
+    /// saves the result, when it changed, before the lock is released.
+    /// Everything that writes an account back goes through here, so a copy
+    /// loaded earlier, or by another process, never overwrites a newer one.
+    pub fn update<T>(
+        &mut self,
+        passphrase: &str,
+        change: impl FnOnce(&mut KeyFile) -> Result<T>,
+    ) -> Result<T> {
+        let _lock = Lock::acquire(self.path())?;
+        self.reload(passphrase)?;
+        let before = self.key().to_bytes()?;
+        let value = change(self.key_mut())?;
+        if *self.key().to_bytes()? != *before {
+            self.save(passphrase)?;
+        }
+        Ok(value)
+    }
+
+    /// Generates the next code, persisting the moved HOTP counter before it is
+    /// returned so a code is never handed out twice.
+    pub fn next_code(&mut self, passphrase: &str) -> Result<String> {
+        self.next_code_with(passphrase, &SystemClock)
+    }
+
+    /// Like `next_code`, reading the time of TOTP keys from `clock`.
+    pub fn next_code_with(&mut self, passphrase: &str, clock: &dyn Clock) -> Result<String> {
+        match self.key().kind {
+            OtpKind::Totp => self.key_mut().next_code_with(clock),
+            OtpKind::Hotp => self.update(passphrase, |key| key.next_code_with(clock)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::{
+        clock::FixedClock,
+        keyfile::Verification,
+        totp::{self, TotpParams},
+    };
+
+    #[test]
+    fn concurrent_hotp_codes_never_repeat() {
+        let path = std::env::temp_dir().join(format!(
+            "ft_otp-account-{}-concurrent.key",
+            std::process::id()
+        ));
+        let mut key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        key.kind = OtpKind::Hotp;
+        key.save(&path, "pass").unwrap();
+
+        // Every thread opens the account before any of them moves the counter.
+        let accounts: Vec<_> = (0..4)
+            .map(|_| Account::open_file(&path, "pass").unwrap())
+            .collect();
+        let handles: Vec<_> = accounts
+            .into_iter()
+            .map(|mut account| thread::spawn(move || account.next_code("pass").unwrap()))
+            .collect();
+        let mut codes: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        codes.sort();
+
+        let mut expected = ["755224", "287082", "359152", "969429"];
+        expected.sort();
+        assert_eq!(codes, expected);
+        assert_eq!(KeyFile::load(&path, "pass").unwrap().counter, 4);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_verifications_accept_a_code_once() {
+        let path =
+            std::env::temp_dir().join(format!("ft_otp-account-{}-verify.key", std::process::id()));
+        let key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        key.save(&path, "pass").unwrap();
+        let code = totp::totp_with(key.secret.expose(), &key.params, &FixedClock::new(59)).unwrap();
+
+        let accounts: Vec<_> = (0..2)
+            .map(|_| Account::open_file(&path, "pass").unwrap())
+            .collect();
+        let handles: Vec<_> = accounts
+            .into_iter()
+            .map(|mut account| {
+                let code = code.clone();
+                thread::spawn(move || {
+                    account
+                        .update("pass", |key| key.verify_at(&code, 1, 59))
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        results.sort_by_key(|result| matches!(result, Verification::Replayed));
+
+        assert_eq!(
+            results,
+            [Verification::Accepted { offset: 0 }, Verification::Replayed]
+        );
+        assert_eq!(KeyFile::load(&path, "pass").unwrap().last_step, Some(1));
+        fs::remove_file(path).unwrap();
+    }
+}
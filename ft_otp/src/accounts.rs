@@ -0,0 +1,472 @@
+//! Commands on the accounts of a key file or vault, run under the audit log
+//! of the store they open.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use std::path::{Path, PathBuf};
+
+use ft_otp::{
+    Clock, Passphrase,
+    account::Account,
+    backup::{self, Backup, Merged},
+    keyfile::{OtpKind, Verification},
+    migration, ocra, otpauth, shamir, storage,
+    totp::Encoding,
+    vault::{Entry, Vault},
+};
+
+use crate::{
+    Command, RecoveryAction, TimeArgs,
+    audit_log::{audit_path, audited, file_label},
+    import,
+    keys::{check_key_length, print_recovery_codes, read_key},
+    passphrase, qr, tui,
+};
+
+/// One line describing an account, without its secret.
+fn summary(entry: &Entry) -> String {
+    let key = &entry.key;
+    let moving_factor = match key.kind {
+        OtpKind::Totp => format!("{}s", key.params.period),
+        OtpKind::Hotp => format!("counter {}", key.counter),
+    };
+    let length = match &key.params.encoding {
+        Encoding::Decimal => format!("{} digits", key.params.digits),
+        encoding => format!("{} chars {}", key.params.digits, encoding),
+    };
+    format!(
+        "{:<24} {:<16} {} {} {} {} {}",
+        entry.label,
+        entry.issuer.as_deref().unwrap_or("-"),
+        key.kind.as_str(),
+        key.params.algorithm,
+        length,
+        moving_factor,
+        entry.tags.join(",")
+    )
+}
+
+/// The audit log entry of a vault command, `None` for read-only listings.
+fn audit_entry(command: &Command) -> Option<(&'static str, String)> {
+    match command {
+        Command::Verify { account, .. } => Some(("verify", account.name())),
+        Command::Resync { account, .. } => Some(("resync", account.name())),
+        Command::Recovery {
+            action: RecoveryAction::Generate { account, .. },
+        } => Some(("recovery", account.name())),
+        Command::Ocra {
+            verify, account, ..
+        } => match verify {
+            Some(_) => Some(("ocra-verify", account.name())),
+            None => Some(("ocra", account.name())),
+        },
+        Command::Qr { account, .. } => Some(("qr", account.name())),
+        Command::Add { label, .. } => Some(("add", label.clone())),
+        Command::Import { dry_run: true, .. } => None,
+        Command::Import { sources, .. } => Some(("import", sources.join(", "))),
+        Command::Export { labels, .. } | Command::ExportMigration { labels, .. } => {
+            match labels.is_empty() {
+                true => Some(("export", "*".to_string())),
+                false => Some(("export", labels.join(", "))),
+            }
+        }
+        Command::Show { label, .. } => Some(("code", label.clone())),
+        Command::Rm { label, .. } => Some(("rm", label.clone())),
+        Command::Rename {
+            label, new_label, ..
+        } => Some(("rename", format!("{} -> {}", label, new_label))),
+        _ => None,
+    }
+}
+
+/// The key file or vault a vault command opens.
+fn store_path(command: &Command) -> Option<&Path> {
+    match command {
+        Command::Verify { account, .. }
+        | Command::Resync { account, .. }
+        | Command::Ocra { account, .. }
+        | Command::Qr { account, .. }
+        | Command::Recovery {
+            action: RecoveryAction::Generate { account, .. } | RecoveryAction::Status { account },
+        } => Some(account.store()),
+        Command::Add { vault, .. }
+        | Command::Import { vault, .. }
+        | Command::Export { vault, .. }
+        | Command::ExportMigration { vault, .. }
+        | Command::Tui { vault }
+        | Command::List { vault }
+        | Command::Show { vault, .. }
+        | Command::Rm { vault, .. }
+        | Command::Rename { vault, .. } => Some(&vault.vault),
+        _ => None,
+    }
+}
+
+/// Backups have a passphrase of their own, so `PASSPHRASE` is never used for them.
+fn backup_passphrase_source(file: Option<PathBuf>) -> passphrase::Source {
+    match file {
+        Some(path) => passphrase::Source::File(path),
+        None => passphrase::Source::Prompt,
+    }
+}
+
+pub fn run(
+    command: Command,
+    source: &passphrase::Source,
+    time: &TimeArgs,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let store = store_path(&command).map(Path::to_path_buf);
+    let passphrase = match &store {
+        Some(store) => passphrase::read_for(store, source, false)?,
+        None => passphrase::read(source, false)?,
+    };
+    match (audit_entry(&command), store) {
+        (Some((operation, label)), Some(store)) => audited(
+            &audit_path(audit_log, &store),
+            &passphrase,
+            operation,
+            &label,
+            || vault_command(command, &passphrase, time),
+        ),
+        _ => vault_command(command, &passphrase, time),
+    }
+}
+
+fn vault_command(command: Command, passphrase: &Passphrase, time: &TimeArgs) -> anyhow::Result<()> {
+    let clock = time.clock()?;
+
+    match command {
+        Command::Verify {
+            code,
+            window,
+            account,
+        } => {
+            let mut account = account.open(passphrase.expose())?;
+            time.warn(&clock, account.key().params.period);
+            let verification = account.update(passphrase.expose(), |key| {
+                key.verify_with(code.trim(), window, &clock)
+            })?;
+            match verification {
+                Verification::Accepted { offset } => {
+                    println!("Code accepted (offset {:+})", offset);
+                }
+                Verification::Recovered { remaining } => {
+                    println!("Recovery code accepted, {} left", remaining);
+                }
+                Verification::Rejected => anyhow::bail!("invalid code."),
+                Verification::Replayed => anyhow::bail!("code was already used."),
+            }
+        }
+        Command::Recovery {
+            action:
+                RecoveryAction::Generate {
+                    recovery,
+                    account: args,
+                },
+        } => {
+            let mut account = args.open(passphrase.expose())?;
+            let codes = account.update(passphrase.expose(), |key| {
+                Ok(key.regenerate_recovery_codes(recovery.recovery_codes))
+            })?;
+            print_recovery_codes(&codes);
+        }
+        Command::Recovery {
+            action: RecoveryAction::Status { account },
+        } => {
+            let account = account.open(passphrase.expose())?;
+            println!("{} recovery codes left", account.key().recovery.len());
+        }
+        Command::Resync {
+            first,
+            second,
+            window,
+            account,
+        } => {
+            let mut account = account.open(passphrase.expose())?;
+            let counter = account.update(passphrase.expose(), |key| {
+                key.resync(&first, &second, window)
+            })?;
+            println!("Counter resynchronized, next code uses counter {}", counter);
+        }
+        Command::Ocra {
+            suite,
+            question,
+            counter,
+            pin_file,
+            pin_fd,
+            session,
+            timestamp,
+            verify,
+            window,
+            account,
+        } => {
+            let account = account.open(passphrase.expose())?;
+            // Never taken on the command line, where other users can read it.
+            let pin = match suite.pin {
+                Some(_) => {
+                    let source = match (pin_file, pin_fd) {
+                        (Some(path), _) => passphrase::Source::File(path),
+                        (None, Some(fd)) => passphrase::Source::Fd(fd),
+                        (None, None) => passphrase::Source::Prompt,
+                    };
+                    Some(passphrase::read_named(&source, false, "PIN")?)
+                }
+                None => None,
+            };
+            let session = hex::decode(session.trim())
+                .map_err(|_| anyhow::anyhow!("--session must be hexadecimal."))?;
+            let inputs = ocra::Inputs {
+                counter,
+                question: &question,
+                pin: pin.as_ref().map_or("", |pin| pin.expose()),
+                session: &session,
+                timestamp: timestamp.unwrap_or_else(|| clock.now()),
+            };
+            let secret = account.key().secret.expose();
+            match verify {
+                Some(response) => {
+                    match ocra::verify(secret, &suite, &inputs, response.trim(), window)? {
+                        Verification::Accepted { offset } => println!("valid (offset {})", offset),
+                        _ => anyhow::bail!("invalid response."),
+                    }
+                }
+                None => println!("{}", ocra::generate(secret, &suite, &inputs)?),
+            }
+        }
+        Command::Qr {
+            out,
+            level,
+            quiet_zone,
+            scale,
+            preview,
+            account,
+        } => {
+            let entry = match account.open(passphrase.expose())? {
+                Account::Vault { vault, index, .. } => vault.entries[index].clone(),
+                Account::File { path, key } => Entry {
+                    label: file_label(&path),
+                    issuer: None,
+                    key,
+                    tags: Vec::new(),
+                    icon: None,
+                },
+            };
+            let uri = otpauth::to_uri(&entry);
+            let matrix = qr::Matrix::new(&uri, level, quiet_zone)?;
+            match out {
+                Some(path) => {
+                    qr::save(&matrix, &path, scale)?;
+                    println!("QR code of '{}' written to {}", entry.label, path.display());
+                }
+                None => qr::preview(&matrix, preview)?,
+            }
+        }
+        Command::Add {
+            label,
+            file,
+            key_format,
+            allow_short_key,
+            issuer,
+            tags,
+            params,
+            vault: args,
+        } => {
+            let _lock = storage::Lock::acquire(&args.vault)?;
+            let mut vault = Vault::load(&args.vault, passphrase.expose())?;
+            vault.add(Entry {
+                label: label.clone(),
+                issuer,
+                key: params.to_key(read_key(&file, key_format, allow_short_key)?)?,
+                tags,
+                icon: None,
+            })?;
+            vault.save(&args.vault, passphrase.expose())?;
+            println!("Added '{}' to {}", label, args.vault.display());
+        }
+        Command::Import {
+            sources,
+            label,
+            tags,
+            conflict,
+            backup_passphrase_file,
+            dry_run,
+            allow_short_key,
+            vault: args,
+        } => {
+            let backup_source = backup_passphrase_source(backup_passphrase_file);
+            let mut entries = import::read_sources(&sources, &backup_source)?;
+            if let Some(label) = label {
+                match entries.as_mut_slice() {
+                    [entry] => entry.label = label,
+                    _ => anyhow::bail!("--label needs a source holding a single account."),
+                }
+            }
+            for entry in &mut entries {
+                check_key_length(&entry.label, entry.key.secret.len(), allow_short_key)?;
+                entry.tags.extend(tags.iter().cloned());
+            }
+
+            let _lock = storage::Lock::acquire(&args.vault)?;
+            let mut vault = Vault::load(&args.vault, passphrase.expose())?;
+            if dry_run {
+                for entry in &entries {
+                    println!("{}", summary(entry));
+                }
+            }
+            let prefix = if dry_run { "Would import" } else { "Imported" };
+            for (label, merged) in backup::merge(&mut vault, entries, conflict) {
+                match merged {
+                    Merged::Added => println!("{} '{}'", prefix, label),
+                    Merged::Skipped => println!("Skipped '{}', already in the vault", label),
+                    Merged::Overwritten if dry_run => println!("Would overwrite '{}'", label),
+                    Merged::Overwritten => println!("Overwrote '{}'", label),
+                    Merged::Renamed(new_label) => {
+                        println!("{} '{}' as '{}'", prefix, label, new_label)
+                    }
+                }
+            }
+            if dry_run {
+                println!("Dry run, {} left unchanged", args.vault.display());
+            } else {
+                vault.save(&args.vault, passphrase.expose())?;
+            }
+        }
+        Command::Export {
+            file,
+            labels,
+            shares,
+            threshold,
+            backup_passphrase_file,
+            vault: args,
+        } => {
+            let vault = Vault::load(&args.vault, passphrase.expose())?;
+            let entries = if labels.is_empty() {
+                vault.entries
+            } else {
+                labels
+                    .iter()
+                    .map(|label| vault.get(label).cloned())
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            if entries.is_empty() {
+                anyhow::bail!("{} holds no accounts.", args.vault.display());
+            }
+
+            let backup_source = backup_passphrase_source(backup_passphrase_file);
+            let backup_passphrase =
+                passphrase::read_named(&backup_source, true, "Backup passphrase")?;
+            let count = entries.len();
+            let sealed = Backup::new(entries).seal(backup_passphrase.expose())?;
+
+            match (shares, threshold) {
+                (Some(shares), Some(threshold)) => {
+                    for share in shamir::split(&sealed, threshold, shares)? {
+                        let mut path = file.clone().into_os_string();
+                        path.push(format!(".{}", share.index));
+                        storage::write_atomic(Path::new(&path), &share.to_bytes())?;
+                        println!(
+                            "Share {}/{} written to {}",
+                            share.index,
+                            shares,
+                            path.display()
+                        );
+                    }
+                    println!(
+                        "{} accounts backed up, any {} of the {} shares rebuild the backup",
+                        count, threshold, shares
+                    );
+                }
+                _ => {
+                    storage::write_atomic(&file, &sealed)?;
+                    println!("{} accounts backed up to {}", count, file.display());
+                }
+            }
+        }
+        Command::ExportMigration {
+            labels,
+            batch_size,
+            uri,
+            vault: args,
+        } => {
+            let vault = Vault::load(&args.vault, passphrase.expose())?;
+            let selected = if labels.is_empty() {
+                vault.entries.iter().collect()
+            } else {
+                labels
+                    .iter()
+                    .map(|label| vault.get(label))
+                    .collect::<ft_otp::Result<Vec<_>>>()?
+            };
+
+            let (exportable, skipped): (Vec<&Entry>, Vec<&Entry>) = selected
+                .into_iter()
+                .partition(|e| migration::is_exportable(e));
+            for entry in skipped {
+                eprintln!(
+                    "Skipping '{}': Google Authenticator only supports 30s periods and 6 or 8 digits",
+                    entry.label
+                );
+            }
+
+            let batch_id = OsRng.next_u32() >> 1;
+            let uris = migration::encode_uris(&exportable, batch_size as usize, batch_id);
+            for (index, migration_uri) in uris.iter().enumerate() {
+                if uri {
+                    println!("{}", migration_uri.as_str());
+                } else {
+                    println!("Batch {}/{}", index + 1, uris.len());
+                    println!("{}", qr::render_unicode(migration_uri.as_str())?);
+                }
+            }
+        }
+        Command::Tui { vault: args } => {
+            let vault = Vault::load(&args.vault, passphrase.expose())?;
+            if vault.entries.is_empty() {
+                anyhow::bail!("{} holds no accounts.", args.vault.display());
+            }
+            let period = vault.entries.iter().map(|e| e.key.params.period).min();
+            let warning = time.check(&clock, period.unwrap_or(30));
+            tui::run_tui(vault.entries, false, Box::new(clock), warning)?;
+        }
+        Command::List { vault: args } => {
+            let vault = Vault::load(&args.vault, passphrase.expose())?;
+            for entry in &vault.entries {
+                println!("{}", summary(entry));
+            }
+        }
+        Command::Show { label, vault: args } => {
+            let mut account = Account::open_vault(&args.vault, &label, passphrase.expose())?;
+            if account.key().kind == OtpKind::Totp {
+                time.warn(&clock, account.key().params.period);
+            }
+            println!("{}", account.next_code_with(passphrase.expose(), &clock)?);
+        }
+        Command::Rm { label, vault: args } => {
+            let _lock = storage::Lock::acquire(&args.vault)?;
+            let mut vault = Vault::load(&args.vault, passphrase.expose())?;
+            vault.remove(&label)?;
+            vault.save(&args.vault, passphrase.expose())?;
+            println!("Removed '{}' from {}", label, args.vault.display());
+        }
+        Command::Rename {
+            label,
+            new_label,
+            vault: args,
+        } => {
+            let _lock = storage::Lock::acquire(&args.vault)?;
+            let mut vault = Vault::load(&args.vault, passphrase.expose())?;
+            vault.rename(&label, &new_label)?;
+            vault.save(&args.vault, passphrase.expose())?;
+            println!("Renamed '{}' to '{}'", label, new_label);
+        }
+        Command::Upgrade { .. }
+        | Command::Passwd { .. }
+        | Command::Encrypt { .. }
+        | Command::Agent { .. }
+        | Command::Time { .. }
+        | Command::Pam { .. }
+        | Command::Serve { .. }
+        | Command::Log { .. } => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,828 @@
+//! `ft_otp agent`: keeps the key store unlocked in memory and hands out codes
+//! over a Unix socket, so scripts skip the passphrase read and the decrypt.
+//!
+//! Every message is a frame: a 4 byte big-endian length followed by that many
+//! bytes of JSON. A client connects, sends one `Request` and reads one
+//! `Response`.
+//!
+//! Besides its own request log, the agent records unlocks and the codes it
+//! hands out in the audit log of its store, like `ft_otp -k` does.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    mem,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use zeroize::{Zeroize, Zeroizing};
+
+use ft_otp::{
+    Account, Clock, KeyFile, Passphrase, Vault,
+    audit::{self, AuditLog},
+    keyfile::OtpKind,
+    storage, totp,
+};
+
+use crate::{AgentAction, TimeArgs, audit_log, passphrase};
+
+/// Overrides the default socket path, like `SSH_AUTH_SOCK`.
+pub const SOCKET_ENV: &str = "FT_OTP_AGENT_SOCK";
+
+const MAX_FRAME: usize = 64 * 1024;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// Next code of an account. The label may be left out when the agent
+    /// holds a single key file.
+    Code {
+        label: Option<String>,
+    },
+    List,
+    Status,
+    Lock,
+    Unlock {
+        passphrase: String,
+    },
+    Stop,
+}
+
+impl Request {
+    fn name(&self) -> &'static str {
+        match self {
+            Request::Code { .. } => "code",
+            Request::List => "list",
+            Request::Status => "status",
+            Request::Lock => "lock",
+            Request::Unlock { .. } => "unlock",
+            Request::Stop => "stop",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Response {
+    Code { code: String },
+    Accounts { labels: Vec<String> },
+    Status { locked: bool, timeout: Option<u64> },
+    Ok,
+    Error { message: String },
+}
+
+pub fn write_frame<T: Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    let body = Zeroizing::new(serde_json::to_vec(message)?);
+    if body.len() > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut body = Zeroizing::new(vec![0u8; len]);
+    reader.read_exact(&mut body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// `$XDG_RUNTIME_DIR/ft_otp/agent.sock`, or a per-user directory in /tmp.
+/// `bind` refuses the directory unless it is private to the agent's user.
+pub fn default_socket() -> PathBuf {
+    if let Some(path) = env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("ft_otp"),
+        None => env::temp_dir().join(format!("ft_otp-{}", unsafe { libc::geteuid() })),
+    };
+    dir.join("agent.sock")
+}
+
+/// Where the agent reads its accounts from.
+pub enum Store {
+    Vault(PathBuf),
+    File(PathBuf),
+}
+
+enum Accounts {
+    Vault(Vault),
+    File(KeyFile),
+}
+
+struct Unlocked {
+    passphrase: Passphrase,
+    accounts: Accounts,
+    audit: Option<AuditLog>,
+}
+
+pub struct Agent {
+    store: Store,
+    clock: Box<dyn Clock>,
+    timeout: Option<Duration>,
+    unlocked: Option<Unlocked>,
+    last_used: Instant,
+    log: Box<dyn Write + Send>,
+    audit_log: Option<PathBuf>,
+}
+
+impl Agent {
+    /// `timeout` locks the agent after that long without a request.
+    pub fn new(
+        store: Store,
+        clock: Box<dyn Clock>,
+        timeout: Option<Duration>,
+        log: Box<dyn Write + Send>,
+    ) -> Agent {
+        Agent {
+            store,
+            clock,
+            timeout,
+            unlocked: None,
+            last_used: Instant::now(),
+            log,
+            audit_log: None,
+        }
+    }
+
+    /// Records unlocks and code requests in the audit log at `path`.
+    pub fn with_audit_log(mut self, path: PathBuf) -> Agent {
+        self.audit_log = Some(path);
+        self
+    }
+
+    /// How the store is named in the audit log.
+    fn store_label(&self) -> String {
+        let path = match &self.store {
+            Store::Vault(path) | Store::File(path) => path,
+        };
+        path.file_stem()
+            .map_or("ft_otp".into(), |stem| stem.to_string_lossy().into_owned())
+    }
+
+    pub fn unlock(&mut self, passphrase: Passphrase) -> anyhow::Result<()> {
+        let label = self.store_label();
+        let mut audit = match &self.audit_log {
+            Some(path) => Some(audit::open_for(
+                path,
+                passphrase.expose(),
+                "agent-unlock",
+                &label,
+            )?),
+            None => None,
+        };
+        let accounts = match &self.store {
+            Store::Vault(path) => Vault::load(path, passphrase.expose()).map(Accounts::Vault),
+            Store::File(path) => KeyFile::load(path, passphrase.expose()).map(Accounts::File),
+        }
+        .map_err(anyhow::Error::from);
+        if let Some(audit) = &mut audit {
+            audit.record("agent-unlock", &label, audit_log::outcome_of(&accounts))?;
+        }
+        self.unlocked = Some(Unlocked {
+            passphrase,
+            accounts: accounts?,
+            audit,
+        });
+        self.last_used = Instant::now();
+        Ok(())
+    }
+
+    /// Drops the decrypted accounts and the passphrase, which wipes them.
+    pub fn lock(&mut self) {
+        self.unlocked = None;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.unlocked.is_none()
+    }
+
+    /// Locks the agent once it has been idle for longer than the timeout.
+    fn expire(&mut self) {
+        if let Some(timeout) = self.timeout
+            && !self.is_locked()
+            && self.last_used.elapsed() >= timeout
+        {
+            self.lock();
+            self.log_line("timeout", "-", "locked");
+        }
+    }
+
+    fn log_line(&mut self, op: &str, detail: &str, outcome: &str) {
+        let _ = writeln!(self.log, "{} {} {} {}", totp::now(), op, detail, outcome);
+    }
+
+    /// TOTP codes come from the accounts read at unlock. HOTP counters are
+    /// moved in the store itself, reloaded under its lock, so accounts added
+    /// and counters moved since the unlock are kept; the copy read then
+    /// replaces the one held in memory.
+    fn next_code(&mut self, label: Option<&str>) -> anyhow::Result<String> {
+        let store = &self.store;
+        let clock = &self.clock;
+        let Some(unlocked) = &mut self.unlocked else {
+            anyhow::bail!("agent is locked.");
+        };
+
+        let mut account = match (&mut unlocked.accounts, store) {
+            (Accounts::Vault(vault), Store::Vault(path)) => {
+                let label = label.ok_or_else(|| anyhow::anyhow!("a label is required."))?;
+                let index = vault.position(label)?;
+                let key = &mut vault.entries[index].key;
+                if key.kind == OtpKind::Totp {
+                    return Ok(key.next_code_with(clock)?);
+                }
+                Account::Vault {
+                    path: path.clone(),
+                    vault: mem::take(vault),
+                    label: label.to_string(),
+                    index,
+                }
+            }
+            (Accounts::File(key), Store::File(path)) => {
+                if key.kind == OtpKind::Totp {
+                    return Ok(key.next_code_with(clock)?);
+                }
+                Account::File {
+                    path: path.clone(),
+                    key: key.clone(),
+                }
+            }
+            _ => unreachable!("accounts always match their store"),
+        };
+
+        let code = account.next_code_with(unlocked.passphrase.expose(), clock);
+        unlocked.accounts = match account {
+            Account::Vault { vault, .. } => Accounts::Vault(vault),
+            Account::File { key, .. } => Accounts::File(key),
+        };
+        Ok(code?)
+    }
+
+    fn labels(&self) -> anyhow::Result<Vec<String>> {
+        match &self.unlocked {
+            None => anyhow::bail!("agent is locked."),
+            Some(Unlocked {
+                accounts: Accounts::Vault(vault),
+                ..
+            }) => Ok(vault.entries.iter().map(|e| e.label.clone()).collect()),
+            Some(Unlocked {
+                accounts: Accounts::File(_),
+                ..
+            }) => match &self.store {
+                Store::File(path) => Ok(vec![path.display().to_string()]),
+                Store::Vault(_) => unreachable!("accounts always match their store"),
+            },
+        }
+    }
+
+    /// Records a code request in the audit log, when the agent is unlocked
+    /// and keeps one. A code is only handed out once it is recorded.
+    fn audit_code(
+        &mut self,
+        label: Option<&str>,
+        code: &anyhow::Result<String>,
+    ) -> anyhow::Result<()> {
+        let label = label.map_or_else(|| self.store_label(), str::to_string);
+        if let Some(Unlocked {
+            audit: Some(audit), ..
+        }) = &mut self.unlocked
+        {
+            audit.record("agent-code", &label, audit_log::outcome_of(code))?;
+        }
+        Ok(())
+    }
+
+    /// Answers one request and logs it. Neither codes nor passphrases are
+    /// written to the log.
+    pub fn handle(&mut self, request: Request) -> Response {
+        self.expire();
+        let op = request.name();
+        let detail = match &request {
+            Request::Code { label } => label.clone().unwrap_or_else(|| "-".to_string()),
+            _ => "-".to_string(),
+        };
+
+        let result = match request {
+            Request::Code { label } => {
+                let code = self.next_code(label.as_deref());
+                self.audit_code(label.as_deref(), &code)
+                    .and(code)
+                    .map(|code| Response::Code { code })
+            }
+            Request::List => self.labels().map(|labels| Response::Accounts { labels }),
+            Request::Status => Ok(Response::Status {
+                locked: self.is_locked(),
+                timeout: self.timeout.map(|t| t.as_secs()),
+            }),
+            Request::Lock => {
+                self.lock();
+                Ok(Response::Ok)
+            }
+            Request::Unlock { passphrase } => self
+                .unlock(Passphrase::new(passphrase))
+                .map(|_| Response::Ok),
+            Request::Stop => {
+                self.lock();
+                Ok(Response::Ok)
+            }
+        };
+
+        self.last_used = Instant::now();
+        let response = result.unwrap_or_else(|e| Response::Error {
+            message: e.to_string(),
+        });
+        let outcome = match &response {
+            Response::Error { message } => format!("error: {}", message),
+            _ => "ok".to_string(),
+        };
+        self.log_line(op, &detail, &outcome);
+        response
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Other platforms rely on the socket and directory permissions alone.
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(_stream: &UnixStream) -> io::Result<u32> {
+    Ok(unsafe { libc::geteuid() })
+}
+
+/// Refuses a socket directory someone else could swap the socket in: it
+/// must be a directory of ours that nobody else can enter, or a sticky
+/// directory of root like /tmp, where others cannot remove our socket.
+fn check_dir(dir: &Path) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    let mode = metadata.mode();
+    let ours = metadata.uid() == unsafe { libc::geteuid() } && mode & 0o077 == 0;
+    let sticky = metadata.uid() == 0 && mode & 0o1000 != 0;
+    if !metadata.is_dir() || !(ours || sticky) {
+        anyhow::bail!(
+            "{} must be a directory owned by uid {} with mode 0700.",
+            dir.display(),
+            unsafe { libc::geteuid() }
+        );
+    }
+    Ok(())
+}
+
+/// Creates the socket readable by its owner only, in a directory nobody else
+/// can enter. A leftover socket from a dead agent is replaced, anything else
+/// at that path is left alone.
+fn bind(socket: &Path) -> anyhow::Result<UnixListener> {
+    if let Some(dir) = socket.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        if !dir.exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+        check_dir(dir)?;
+    }
+    if UnixStream::connect(socket).is_ok() {
+        anyhow::bail!("an agent is already listening on {}.", socket.display());
+    }
+    storage::remove_stale_socket(socket)?;
+
+    // Clients of other users are turned away by their uid, so the moment
+    // before the mode is set leaves nothing open.
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn serve_client(agent: &Mutex<Agent>, mut stream: UnixStream) -> io::Result<bool> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let our_uid = unsafe { libc::geteuid() };
+    let uid = peer_uid(&stream)?;
+    if uid != our_uid {
+        agent
+            .lock()
+            .unwrap()
+            .log_line("connect", &format!("uid={}", uid), "refused");
+        return Ok(false);
+    }
+
+    let request: Request = read_frame(&mut stream)?;
+    let stop = matches!(request, Request::Stop);
+    let response = agent.lock().unwrap().handle(request);
+    write_frame(&mut stream, &response)?;
+    Ok(stop)
+}
+
+/// Serves requests until a `stop` request arrives, then removes the socket.
+pub fn serve(agent: Agent, socket: &Path) -> anyhow::Result<()> {
+    let listener = bind(socket)?;
+    let agent = Arc::new(Mutex::new(agent));
+
+    let timer = Arc::clone(&agent);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            timer.lock().unwrap().expire();
+        }
+    });
+
+    eprintln!("ft_otp agent listening on {}", socket.display());
+    eprintln!("export {}={}", SOCKET_ENV, socket.display());
+    for stream in listener.incoming() {
+        let stop = match stream {
+            Ok(stream) => serve_client(&agent, stream).unwrap_or_else(|e| {
+                eprintln!("client error: {}", e);
+                false
+            }),
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                false
+            }
+        };
+        if stop {
+            break;
+        }
+    }
+
+    fs::remove_file(socket)?;
+    Ok(())
+}
+
+/// Sends one request to the agent listening on `socket`. The agent must run
+/// as the same user, so a socket planted by someone else never receives a
+/// passphrase.
+pub fn request(socket: &Path, request: &Request) -> anyhow::Result<Response> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| anyhow::anyhow!("cannot reach the agent at {}: {}", socket.display(), e))?;
+    let uid = peer_uid(&stream)?;
+    if uid != unsafe { libc::geteuid() } {
+        anyhow::bail!(
+            "the agent at {} runs as uid {}, not as this user.",
+            socket.display(),
+            uid
+        );
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    write_frame(&mut stream, request)?;
+    Ok(read_frame(&mut stream)?)
+}
+
+pub fn command(
+    socket: Option<PathBuf>,
+    action: AgentAction,
+    source: &passphrase::Source,
+    time: &TimeArgs,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let socket = socket.unwrap_or_else(default_socket);
+
+    let mut message = match action {
+        AgentAction::Start {
+            key,
+            timeout,
+            locked,
+            log,
+            vault,
+        } => {
+            let path = key.clone().unwrap_or_else(|| vault.vault.clone());
+            let store = match key {
+                Some(path) => Store::File(path),
+                None => Store::Vault(vault.vault),
+            };
+            let log: Box<dyn std::io::Write + Send> = match log {
+                Some(path) => Box::new(
+                    fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+                ),
+                None => Box::new(std::io::stderr()),
+            };
+            let timeout = (timeout > 0).then(|| Duration::from_secs(timeout));
+            let mut agent = Agent::new(store, Box::new(time.clock()?), timeout, log)
+                .with_audit_log(audit_log::audit_path(audit_log, &path));
+            if !locked {
+                agent.unlock(passphrase::read_for(&path, source, false)?)?;
+            }
+            return serve(agent, &socket);
+        }
+        AgentAction::Code { label } => Request::Code { label },
+        AgentAction::List => Request::List,
+        AgentAction::Status => Request::Status,
+        AgentAction::Lock => Request::Lock,
+        AgentAction::Unlock => Request::Unlock {
+            passphrase: passphrase::read(source, false)?.expose().to_string(),
+        },
+        AgentAction::Stop => Request::Stop,
+    };
+
+    let response = request(&socket, &message);
+    if let Request::Unlock { passphrase } = &mut message {
+        passphrase.zeroize();
+    }
+    match response? {
+        Response::Code { code } => println!("{}", code),
+        Response::Accounts { labels } => {
+            for label in labels {
+                println!("{}", label);
+            }
+        }
+        Response::Status { locked, timeout } => {
+            let timeout = match timeout {
+                Some(seconds) => format!("locks after {}s idle", seconds),
+                None => "no timeout".to_string(),
+            };
+            let state = if locked { "locked" } else { "unlocked" };
+            println!("{} ({})", state, timeout);
+        }
+        Response::Ok => {}
+        Response::Error { message } => anyhow::bail!("agent: {}", message),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ft_otp::{SystemClock, TotpParams};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ft_otp-agent-{}-{}", std::process::id(), name))
+    }
+
+    fn hotp_key() -> KeyFile {
+        let mut key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        key.kind = OtpKind::Hotp;
+        key
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(
+            &mut buffer,
+            &Request::Code {
+                label: Some("github".into()),
+            },
+        )
+        .unwrap();
+        assert_eq!(&buffer[..4], &(buffer.len() as u32 - 4).to_be_bytes());
+
+        let request: Request = read_frame(&mut buffer.as_slice()).unwrap();
+        assert!(matches!(request, Request::Code { label: Some(l) } if l == "github"));
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut frame = (MAX_FRAME as u32 + 1).to_be_bytes().to_vec();
+        frame.extend([0; 16]);
+        assert!(read_frame::<Request>(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn locks_and_unlocks() {
+        let path = temp_path("locks.key");
+        hotp_key().save(&path, "pass").unwrap();
+
+        let mut agent = Agent::new(
+            Store::File(path.clone()),
+            Box::new(SystemClock),
+            None,
+            Box::new(io::sink()),
+        );
+        let code = |agent: &mut Agent| agent.handle(Request::Code { label: None });
+        assert!(matches!(code(&mut agent), Response::Error { .. }));
+
+        let wrong = agent.handle(Request::Unlock {
+            passphrase: "wrong".into(),
+        });
+        assert!(matches!(wrong, Response::Error { .. }));
+        let unlock = agent.handle(Request::Unlock {
+            passphrase: "pass".into(),
+        });
+        assert_eq!(unlock, Response::Ok);
+
+        // HOTP counters advance and are written back to the key file.
+        assert_eq!(
+            code(&mut agent),
+            Response::Code {
+                code: "755224".into()
+            }
+        );
+        assert_eq!(
+            code(&mut agent),
+            Response::Code {
+                code: "287082".into()
+            }
+        );
+        assert_eq!(KeyFile::load(&path, "pass").unwrap().counter, 2);
+
+        assert_eq!(agent.handle(Request::Lock), Response::Ok);
+        assert!(agent.is_locked());
+        assert!(matches!(code(&mut agent), Response::Error { .. }));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hotp_codes_keep_changes_made_since_the_unlock() {
+        let path = temp_path("changed.vault");
+        let entry = |label: &str| ft_otp::Entry {
+            label: label.to_string(),
+            issuer: None,
+            key: hotp_key(),
+            tags: Vec::new(),
+            icon: None,
+        };
+        let mut vault = Vault::default();
+        vault.add(entry("vpn")).unwrap();
+        vault.save(&path, "pass").unwrap();
+
+        let mut agent = Agent::new(
+            Store::Vault(path.clone()),
+            Box::new(SystemClock),
+            None,
+            Box::new(io::sink()),
+        );
+        agent.unlock(Passphrase::new("pass".into())).unwrap();
+
+        // `ft_otp add` and `ft_otp show vpn` while the agent is unlocked.
+        let mut vault = Vault::load(&path, "pass").unwrap();
+        vault.add(entry("github")).unwrap();
+        vault.entries[0].key.counter = 1;
+        vault.save(&path, "pass").unwrap();
+
+        let code = agent.handle(Request::Code {
+            label: Some("vpn".into()),
+        });
+        assert_eq!(
+            code,
+            Response::Code {
+                code: "287082".into()
+            }
+        );
+        let vault = Vault::load(&path, "pass").unwrap();
+        assert!(vault.get("github").is_ok());
+        assert_eq!(vault.get("vpn").unwrap().key.counter, 2);
+        assert_eq!(
+            agent.handle(Request::List),
+            Response::Accounts {
+                labels: vec!["vpn".into(), "github".into()]
+            }
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn audits_unlocks_and_codes() {
+        let path = temp_path("audited.key");
+        let log = audit::log_path(&path);
+        let _ = fs::remove_file(&log);
+        hotp_key().save(&path, "pass").unwrap();
+
+        let mut agent = Agent::new(
+            Store::File(path.clone()),
+            Box::new(SystemClock),
+            None,
+            Box::new(io::sink()),
+        )
+        .with_audit_log(log.clone());
+        agent.unlock(Passphrase::new("pass".into())).unwrap();
+        agent.handle(Request::Code { label: None });
+        agent.handle(Request::Lock);
+        agent.handle(Request::Unlock {
+            passphrase: "wrong".into(),
+        });
+
+        let summary = audit::verify(&log, "pass").unwrap();
+        assert_eq!((summary.entries, summary.unsealed), (2, 1));
+        let label = path.file_stem().unwrap().to_str().unwrap();
+        assert!(
+            summary
+                .last
+                .unwrap()
+                .ends_with(&format!("\tagent-code\t{}\tok", label))
+        );
+        let text = fs::read_to_string(&log).unwrap();
+        assert!(text.contains(&format!("\tagent-unlock\t{}\tdenied\t-\n", label)));
+        assert!(!text.contains("755224"));
+        fs::remove_file(path).unwrap();
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn idle_timeout_locks() {
+        let path = temp_path("timeout.key");
+        hotp_key().save(&path, "pass").unwrap();
+
+        let mut agent = Agent::new(
+            Store::File(path.clone()),
+            Box::new(SystemClock),
+            Some(Duration::ZERO),
+            Box::new(io::sink()),
+        );
+        agent.unlock(Passphrase::new("pass".into())).unwrap();
+        agent.expire();
+        assert!(agent.is_locked());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_socket_directories_others_control() {
+        let dir = temp_path("open-dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let error = bind(&dir.join("agent.sock")).unwrap_err().to_string();
+        assert!(error.contains("mode 0700"), "{}", error);
+
+        let link = temp_path("link-dir");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(bind(&link.join("agent.sock")).is_err());
+        assert!(bind(&dir.join("agent.sock")).is_ok());
+
+        let file = dir.join("notes");
+        fs::write(&file, b"keep").unwrap();
+        assert!(bind(&file).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"keep");
+        fs::remove_file(link).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serves_over_the_socket() {
+        let key_path = temp_path("serve.key");
+        let socket = temp_path("serve.sock");
+        hotp_key().save(&key_path, "pass").unwrap();
+
+        let mut agent = Agent::new(
+            Store::File(key_path.clone()),
+            Box::new(SystemClock),
+            None,
+            Box::new(io::sink()),
+        );
+        agent.unlock(Passphrase::new("pass".into())).unwrap();
+        let server_socket = socket.clone();
+        let server = thread::spawn(move || serve(agent, &server_socket).unwrap());
+        while UnixStream::connect(&socket).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            request(&socket, &Request::Code { label: None }).unwrap(),
+            Response::Code {
+                code: "755224".into()
+            }
+        );
+        assert_eq!(request(&socket, &Request::Stop).unwrap(), Response::Ok);
+        server.join().unwrap();
+        assert!(!socket.exists());
+        fs::remove_file(key_path).unwrap();
+    }
+}
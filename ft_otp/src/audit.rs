@@ -0,0 +1,529 @@
+//! Tamper-evident audit log: one line per operation, chained with
+//! HMAC-SHA256 so an edited, inserted or removed line breaks every MAC after
+//! it.
+//!
+//! Each store keeps its own log next to it, so the log key is always sealed
+//! under the passphrase of the store it records. The first line holds that
+//! random key, encrypted like a key file, followed by the number of entries
+//! and an anchor MAC over the last one: cutting lines off the end no longer
+//! matches the anchor. Every other line is
+//! `timestamp \t operation \t label \t outcome \t mac`, where `mac` covers the
+//! previous line's MAC and the rest of the line.
+//!
+//! A failed unlock has no key to MAC its line with, so it is appended with
+//! `-` in place of the MAC and sealed into the chain at the next successful
+//! open. Until then it can be removed unnoticed, `verify` reports how many
+//! such lines are waiting. Replacing the whole log with an older copy cannot
+//! be told apart from the log of that time.
+
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher,
+    error::{Error, Result},
+    storage, totp,
+};
+
+const HEADER: &str = "ft_otp-audit v2";
+const HEADER_V1: &str = "ft_otp-audit v1";
+const MAC_SIZE: usize = 32;
+const UNSEALED: &str = "-";
+
+/// The log of the key file or vault at `store`: the same path with `.audit`
+/// appended.
+pub fn log_path(store: &Path) -> PathBuf {
+    let mut path = store.as_os_str().to_owned();
+    path.push(".audit");
+    PathBuf::from(path)
+}
+
+/// What an entry records of an operation. Error messages are left out, they
+/// may quote what was being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Failed,
+    /// The passphrase did not open the store.
+    Denied,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "failed",
+            Outcome::Denied => "denied",
+        })
+    }
+}
+
+fn invalid(path: &Path, message: String) -> Error {
+    Error::Audit(format!("{}: {}", path.display(), message))
+}
+
+/// Keeps tabs and line breaks out of a field so every entry stays one line.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push('?'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn entry_body(operation: &str, label: &str, outcome: Outcome) -> String {
+    format!(
+        "{}\t{}\t{}\t{}",
+        totp::now(),
+        escape(operation),
+        escape(label),
+        outcome
+    )
+}
+
+fn mac(key: &[u8], previous: &[u8], body: &str) -> [u8; MAC_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(previous);
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Binds the number of entries to the last MAC, so the header stops matching
+/// once entries are cut off the end.
+fn anchor(key: &[u8], last: &[u8], entries: usize) -> [u8; MAC_SIZE] {
+    mac(key, last, &format!("{} entries", entries))
+}
+
+/// A log as read from disk, before any MAC is checked.
+struct Parsed<'a> {
+    encrypted: &'a str,
+    entries: usize,
+    anchor: Vec<u8>,
+    sealed: Vec<&'a str>,
+    unsealed: Vec<&'a str>,
+}
+
+fn parse<'a>(path: &Path, text: &'a str) -> Result<Parsed<'a>> {
+    let mut lines = text.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid(path, "the log is empty.".to_string()))?;
+    if header.starts_with(HEADER_V1) {
+        return Err(invalid(
+            path,
+            "was written by an older version that cannot detect removed entries, move it aside to start a new log.".to_string(),
+        ));
+    }
+    let fields: Vec<&str> = match header.strip_prefix(HEADER) {
+        Some(rest) => rest.split(' ').skip(1).collect(),
+        None => Vec::new(),
+    };
+    let (encrypted, entries, anchor) = match fields[..] {
+        [encrypted, entries, anchor] => (
+            encrypted,
+            entries.parse::<usize>().ok(),
+            hex::decode(anchor).ok(),
+        ),
+        _ => return Err(invalid(path, "not an ft_otp audit log.".to_string())),
+    };
+    let (Some(entries), Some(anchor)) = (entries, anchor) else {
+        return Err(invalid(path, "the header is malformed.".to_string()));
+    };
+
+    let mut sealed = Vec::new();
+    let mut unsealed = Vec::new();
+    for (i, line) in lines.enumerate() {
+        match line
+            .strip_suffix(UNSEALED)
+            .and_then(|b| b.strip_suffix('\t'))
+        {
+            Some(body) => unsealed.push(body),
+            None if unsealed.is_empty() => sealed.push(line),
+            None => {
+                return Err(invalid(
+                    path,
+                    format!("line {} is sealed after unsealed lines.", i + 2),
+                ));
+            }
+        }
+    }
+    Ok(Parsed {
+        encrypted,
+        entries,
+        anchor,
+        sealed,
+        unsealed,
+    })
+}
+
+/// Decrypts the log key from the header. A wrong passphrase is reported as
+/// `Error::Decrypt`, like for the store itself.
+fn read_key(path: &Path, encrypted: &str, passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let encrypted = hex::decode(encrypted)
+        .map_err(|_| invalid(path, "the header is malformed.".to_string()))?;
+    cipher::decrypt_key(&encrypted, passphrase).map_err(|e| match e {
+        Error::Decrypt => Error::Decrypt,
+        e => invalid(path, e.to_string()),
+    })
+}
+
+/// Checks every MAC of the chain and the anchor of the header, and returns
+/// the last MAC.
+fn check_chain(path: &Path, key: &[u8], parsed: &Parsed) -> Result<[u8; MAC_SIZE]> {
+    let mut previous = mac(key, &[], HEADER);
+    for (i, line) in parsed.sealed.iter().enumerate() {
+        let number = i + 2;
+        let (body, tag) = line
+            .rsplit_once('\t')
+            .ok_or_else(|| invalid(path, format!("line {} is malformed.", number)))?;
+        let tag = hex::decode(tag)
+            .ok()
+            .filter(|tag| tag.len() == MAC_SIZE)
+            .ok_or_else(|| invalid(path, format!("line {} is malformed.", number)))?;
+        let expected = mac(key, &previous, body);
+        if !bool::from(expected.ct_eq(&tag)) {
+            return Err(invalid(
+                path,
+                format!(
+                    "line {} does not match the chain: it or an earlier line was edited, inserted or removed.",
+                    number
+                ),
+            ));
+        }
+        previous = expected;
+    }
+
+    let entries = parsed.sealed.len();
+    if entries < parsed.entries {
+        return Err(invalid(
+            path,
+            format!(
+                "{} entries where the header records {}: entries were removed from the end.",
+                entries, parsed.entries
+            ),
+        ));
+    }
+    let expected = anchor(key, &previous, parsed.entries);
+    if entries != parsed.entries || !bool::from(expected.ct_eq(&parsed.anchor)) {
+        return Err(invalid(
+            path,
+            "the header does not match the last entry: it or the end of the log was edited."
+                .to_string(),
+        ));
+    }
+    Ok(previous)
+}
+
+/// What `verify` found in an intact log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub entries: usize,
+    /// Failed unlocks waiting to be sealed into the chain.
+    pub unsealed: usize,
+    /// The last entry without its MAC, to compare with what is expected.
+    pub last: Option<String>,
+}
+
+/// Checks the whole chain of the log at `path`.
+pub fn verify(path: &Path, passphrase: &str) -> Result<Summary> {
+    let text = fs::read_to_string(path)?;
+    let parsed = parse(path, &text)?;
+    let key = read_key(path, parsed.encrypted, passphrase)?;
+    check_chain(path, &key, &parsed)?;
+    Ok(Summary {
+        entries: parsed.sealed.len(),
+        unsealed: parsed.unsealed.len(),
+        last: parsed
+            .sealed
+            .last()
+            .and_then(|line| line.rsplit_once('\t'))
+            .map(|(body, _)| body.to_string()),
+    })
+}
+
+/// Re-encrypts the log key under a new passphrase, keeping every entry. A
+/// store without a log is left without one.
+pub fn rekey(path: &Path, passphrase: &str, new_passphrase: &str) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut log = AuditLog::open(path, passphrase)?;
+    log.encrypted = hex::encode(cipher::encrypt_key(&log.key, new_passphrase)?);
+    log.write()
+}
+
+/// Appends a line nobody could MAC, sealed at the next `AuditLog::open`.
+/// Nothing is written when the log does not exist yet.
+fn append_unsealed(path: &Path, operation: &str, label: &str, outcome: Outcome) -> Result<()> {
+    let mut file = match OpenOptions::new()
+        .append(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let line = format!("{}\t{}\n", entry_body(operation, label, outcome), UNSEALED);
+    file.write_all(line.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Opens the log before `operation` runs on its store. When the passphrase
+/// does not open the log, the attempt is still appended as `denied` and
+/// `Error::Decrypt` returned.
+pub fn open_for(path: &Path, passphrase: &str, operation: &str, label: &str) -> Result<AuditLog> {
+    match AuditLog::open(path, passphrase) {
+        Err(Error::Decrypt) => {
+            append_unsealed(path, operation, label, Outcome::Denied)?;
+            Err(Error::Decrypt)
+        }
+        result => result,
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    key: Zeroizing<Vec<u8>>,
+    /// The log key sealed under the passphrase, hex encoded.
+    encrypted: String,
+    lines: Vec<String>,
+    last: [u8; MAC_SIZE],
+    /// Whether the log exists on disk. A new log is only written once an
+    /// operation that opened the store is recorded, so its key is never
+    /// sealed under a wrong passphrase.
+    written: bool,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, or starts a new one when missing. The
+    /// existing chain is checked, so entries are never appended to a log
+    /// that was already tampered with, and waiting unsealed lines are sealed.
+    pub fn open(path: &Path, passphrase: &str) -> Result<AuditLog> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut key = Zeroizing::new(vec![0u8; MAC_SIZE]);
+                OsRng.fill_bytes(&mut key);
+                let encrypted = hex::encode(cipher::encrypt_key(&key, passphrase)?);
+                let last = mac(&key, &[], HEADER);
+                return Ok(AuditLog {
+                    path: path.to_path_buf(),
+                    key,
+                    encrypted,
+                    lines: Vec::new(),
+                    last,
+                    written: false,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let parsed = parse(path, &text)?;
+        let key = read_key(path, parsed.encrypted, passphrase)?;
+        let last = check_chain(path, &key, &parsed)?;
+        let mut log = AuditLog {
+            path: path.to_path_buf(),
+            encrypted: parsed.encrypted.to_string(),
+            lines: parsed.sealed.iter().map(|line| line.to_string()).collect(),
+            key,
+            last,
+            written: true,
+        };
+        if !parsed.unsealed.is_empty() {
+            for body in &parsed.unsealed {
+                log.seal(body);
+            }
+            log.write()?;
+        }
+        Ok(log)
+    }
+
+    fn seal(&mut self, body: &str) {
+        let tag = mac(&self.key, &self.last, body);
+        self.lines.push(format!("{}\t{}", body, hex::encode(tag)));
+        self.last = tag;
+    }
+
+    fn write(&mut self) -> Result<()> {
+        let mut text = format!(
+            "{} {} {} {}\n",
+            HEADER,
+            self.encrypted,
+            self.lines.len(),
+            hex::encode(anchor(&self.key, &self.last, self.lines.len()))
+        );
+        for line in &self.lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        storage::write_private(&self.path, text.as_bytes())?;
+        self.written = true;
+        Ok(())
+    }
+
+    /// Appends one entry and moves the anchor to it.
+    pub fn record(&mut self, operation: &str, label: &str, outcome: Outcome) -> Result<()> {
+        if !self.written && outcome == Outcome::Denied {
+            return Ok(());
+        }
+        self.seal(&entry_body(operation, label, outcome));
+        self.write()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ft_otp-audit-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn write_entries(path: &Path) {
+        let mut log = AuditLog::open(path, "pass").unwrap();
+        log.record("generate", "ft_otp.key", Outcome::Ok).unwrap();
+        log.record("code", "github", Outcome::Ok).unwrap();
+        log.record("verify", "bank\tx", Outcome::Failed).unwrap();
+        // Reopening continues the same chain.
+        let mut log = AuditLog::open(path, "pass").unwrap();
+        log.record("rm", "gitlab", Outcome::Ok).unwrap();
+    }
+
+    fn edit(path: &Path, change: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        change(&mut lines);
+        fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn verifies_an_intact_log() {
+        let path = temp_log("intact");
+        write_entries(&path);
+
+        let summary = verify(&path, "pass").unwrap();
+        assert_eq!(summary.entries, 4);
+        assert_eq!(summary.unsealed, 0);
+        assert!(summary.last.unwrap().ends_with("\trm\tgitlab\tok"));
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("\tverify\tbank\\tx\tfailed\t"));
+        assert!(matches!(verify(&path, "other"), Err(Error::Decrypt)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn detects_edited_removed_and_inserted_lines() {
+        let path = temp_log("tampered");
+        let tampering: [fn(&mut Vec<String>); 4] = [
+            |lines| lines[2] = lines[2].replace("github", "gitlab"),
+            |lines| {
+                lines.remove(1);
+            },
+            |lines| lines.swap(2, 3),
+            |lines| {
+                let copy = lines[2].clone();
+                lines.insert(3, copy);
+            },
+        ];
+        for tamper in tampering {
+            fs::remove_file(&path).ok();
+            write_entries(&path);
+            edit(&path, tamper);
+            let error = verify(&path, "pass").unwrap_err().to_string();
+            assert!(error.contains("does not match the chain"), "{}", error);
+            assert!(AuditLog::open(&path, "pass").is_err());
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn detects_entries_cut_off_the_end() {
+        let path = temp_log("truncated");
+        write_entries(&path);
+        edit(&path, |lines| {
+            lines.pop();
+        });
+        let error = verify(&path, "pass").unwrap_err().to_string();
+        assert!(error.contains("removed from the end"), "{}", error);
+
+        // Lowering the count in the header breaks the anchor instead.
+        edit(&path, |lines| {
+            let (rest, anchor) = lines[0].rsplit_once(' ').unwrap();
+            let (rest, _) = rest.rsplit_once(' ').unwrap();
+            lines[0] = format!("{} 3 {}", rest, anchor);
+        });
+        let error = verify(&path, "pass").unwrap_err().to_string();
+        assert!(error.contains("does not match the last entry"), "{}", error);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_unlocks_are_sealed_at_the_next_open() {
+        let path = temp_log("denied");
+        write_entries(&path);
+        assert!(matches!(
+            open_for(&path, "wrong", "code", "github"),
+            Err(Error::Decrypt)
+        ));
+        let summary = verify(&path, "pass").unwrap();
+        assert_eq!((summary.entries, summary.unsealed), (4, 1));
+
+        AuditLog::open(&path, "pass").unwrap();
+        let summary = verify(&path, "pass").unwrap();
+        assert_eq!((summary.entries, summary.unsealed), (5, 0));
+        assert!(summary.last.unwrap().ends_with("\tcode\tgithub\tdenied"));
+
+        // A store without a log gets none from a failed unlock.
+        let missing = temp_log("denied-missing");
+        let mut log = open_for(&missing, "wrong", "code", "github").unwrap();
+        log.record("code", "github", Outcome::Denied).unwrap();
+        assert!(!missing.exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rekeying_keeps_the_chain() {
+        let path = temp_log("rekey");
+        write_entries(&path);
+        rekey(&path, "pass", "new pass").unwrap();
+
+        assert!(verify(&path, "pass").is_err());
+        assert_eq!(verify(&path, "new pass").unwrap().entries, 4);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn logs_follow_their_store() {
+        assert_eq!(
+            log_path(Path::new("keys/ft_otp.key")),
+            Path::new("keys/ft_otp.key.audit")
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! The audit log as the CLI writes it: every command that opens a key file or
+//! vault records what it did, and `ft_otp log verify` checks the chain.
+
+use std::path::{Path, PathBuf};
+
+use ft_otp::{Passphrase, audit};
+
+use crate::{LogAction, passphrase};
+
+/// Account name of a standalone key file, taken from its file name.
+pub fn file_label(path: &Path) -> String {
+    path.file_stem()
+        .map_or("ft_otp".into(), |stem| stem.to_string_lossy().into_owned())
+}
+
+/// The audit log of `store`, unless `--audit-log` names another one.
+pub fn audit_path(audit_log: Option<&Path>, store: &Path) -> PathBuf {
+    audit_log.map_or_else(|| audit::log_path(store), Path::to_path_buf)
+}
+
+/// The outcome code an audit entry records for `result`.
+pub fn outcome_of<T>(result: &anyhow::Result<T>) -> audit::Outcome {
+    match result {
+        Ok(_) => audit::Outcome::Ok,
+        Err(e) if matches!(e.downcast_ref(), Some(ft_otp::Error::Decrypt)) => {
+            audit::Outcome::Denied
+        }
+        Err(_) => audit::Outcome::Failed,
+    }
+}
+
+/// Runs `action` and records its outcome in the audit log at `log`.
+pub fn audited<T>(
+    log: &Path,
+    passphrase: &Passphrase,
+    operation: &str,
+    label: &str,
+    action: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut log = audit::open_for(log, passphrase.expose(), operation, label)?;
+    let result = action();
+    log.record(operation, label, outcome_of(&result))?;
+    result
+}
+
+pub fn command(
+    action: LogAction,
+    source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    match action {
+        LogAction::Verify { store } => {
+            let (audit_log, passphrase) = match (audit_log, store) {
+                (audit_log, Some(store)) => (
+                    audit_path(audit_log, &store),
+                    passphrase::read_for(&store, source, false)?,
+                ),
+                (Some(audit_log), None) => {
+                    (audit_log.to_path_buf(), passphrase::read(source, false)?)
+                }
+                (None, None) => anyhow::bail!("name the key file or vault the log belongs to."),
+            };
+            let summary = audit::verify(&audit_log, passphrase.expose())?;
+            println!(
+                "{}: {} entries, chain intact",
+                audit_log.display(),
+                summary.entries
+            );
+            if summary.unsealed > 0 {
+                println!(
+                    "{} failed unlocks not sealed yet, they are sealed at the next unlock",
+                    summary.unsealed
+                );
+            }
+            if let Some(last) = summary.last {
+                println!("Last entry: {}", last.replace('\t', "  "));
+            }
+        }
+    }
+    Ok(())
+}
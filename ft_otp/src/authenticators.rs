@@ -0,0 +1,883 @@
+//! Exports of other authenticator apps: Aegis (plain and encrypted vaults),
+//! andOTP (plain and `.json.aes`), 2FAS (`.2fas`, optionally encrypted) and
+//! Bitwarden (password manager or Authenticator JSON, optionally password
+//! protected).
+//!
+//! Secret fields are read into `Sensitive` strings and decrypted payloads
+//! into `Zeroizing` buffers, so neither outlives the import.
+
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, Payload},
+    aes::Aes256,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use cbc::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{Error, Result},
+    keyfile::{KeyFile, OtpKind},
+    otpauth,
+    totp::{Algorithm, Encoding, TotpParams},
+    vault::Entry,
+};
+
+const TWOFAS_ITERATIONS: u32 = 10_000;
+const ANDOTP_SALT: usize = 12;
+const ANDOTP_NONCE: usize = 12;
+const GCM_TAG: usize = 16;
+
+// KDF costs read from an export are bounded before anything is derived, so a
+// crafted file cannot ask for terabytes of memory or days of work. The caps
+// sit well above what each app writes.
+const MAX_ANDOTP_ITERATIONS: u32 = 10_000_000;
+/// scrypt needs `128 * r * N` bytes: 4 GiB at the caps.
+const MAX_SCRYPT_N: u64 = 1 << 20;
+const MAX_SCRYPT_R: u32 = 32;
+const MAX_SCRYPT_P: u32 = 16;
+/// Bitwarden's own upper limits.
+const MAX_BITWARDEN_PBKDF2_ITERATIONS: u32 = 2_000_000;
+const MAX_BITWARDEN_ARGON2_ITERATIONS: u32 = 10;
+/// In MiB, as written in the export.
+const MAX_BITWARDEN_ARGON2_MEMORY: u32 = 1024;
+const MAX_BITWARDEN_ARGON2_PARALLELISM: u32 = 16;
+
+fn invalid(format: Format, message: impl fmt::Display) -> Error {
+    Error::InvalidExport(format!("{} export: {}", format, message))
+}
+
+fn wrong_password(format: Format) -> Error {
+    invalid(format, "wrong password or corrupted file.")
+}
+
+fn required(format: Format, password: Option<&str>) -> Result<&str> {
+    password.ok_or_else(|| invalid(format, "the export is encrypted, a password is needed."))
+}
+
+/// A string field holding a secret, wiped when dropped.
+struct Sensitive(Zeroizing<String>);
+
+impl<'de> Deserialize<'de> for Sensitive {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|s| Sensitive(Zeroizing::new(s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Aegis,
+    AndOtp,
+    TwoFas,
+    Bitwarden,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Aegis => "Aegis",
+            Format::AndOtp => "andOTP",
+            Format::TwoFas => "2FAS",
+            Format::Bitwarden => "Bitwarden",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "aegis" => Ok(Format::Aegis),
+            "andotp" => Ok(Format::AndOtp),
+            "2fas" => Ok(Format::TwoFas),
+            "bitwarden" => Ok(Format::Bitwarden),
+            _ => Err(Error::InvalidExport(format!(
+                "unknown export format '{}' (expected aegis, andotp, 2fas or bitwarden)",
+                s
+            ))),
+        }
+    }
+}
+
+/// The top-level keys that tell the JSON exports apart.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Probe {
+    header: Option<serde::de::IgnoredAny>,
+    db: Option<serde::de::IgnoredAny>,
+    services: Option<serde::de::IgnoredAny>,
+    schema_version: Option<serde::de::IgnoredAny>,
+    items: Option<serde::de::IgnoredAny>,
+    encrypted: Option<serde::de::IgnoredAny>,
+}
+
+/// Accounts read from an export, and why the others were left out.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub entries: Vec<Entry>,
+    pub skipped: Vec<String>,
+}
+
+impl Format {
+    /// Recognizes an export from its content, or from its name for the
+    /// andOTP encrypted backups, which are opaque.
+    pub fn detect(path: &Path, data: &[u8]) -> Option<Format> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".json.aes") {
+            return Some(Format::AndOtp);
+        }
+        let first = data.iter().find(|b| !b.is_ascii_whitespace())?;
+        if *first == b'[' {
+            return serde_json::from_slice::<Vec<serde::de::IgnoredAny>>(data)
+                .ok()
+                .map(|_| Format::AndOtp);
+        }
+        let probe: Probe = serde_json::from_slice(data).ok()?;
+        if probe.header.is_some() && probe.db.is_some() {
+            Some(Format::Aegis)
+        } else if probe.services.is_some() && probe.schema_version.is_some() {
+            Some(Format::TwoFas)
+        } else if probe.items.is_some() || probe.encrypted.is_some() {
+            Some(Format::Bitwarden)
+        } else {
+            None
+        }
+    }
+
+    /// Whether reading `data` needs the export's password.
+    pub fn is_encrypted(&self, data: &[u8]) -> Result<bool> {
+        match self {
+            Format::Aegis => {
+                let file: AegisFile = self.parse(data)?;
+                Ok(file.header.slots.is_some())
+            }
+            Format::AndOtp => {
+                Ok(serde_json::from_slice::<Vec<serde::de::IgnoredAny>>(data).is_err())
+            }
+            Format::TwoFas => {
+                let file: TwoFasFile = self.parse(data)?;
+                Ok(file.services_encrypted.is_some_and(|s| !s.is_empty()))
+            }
+            Format::Bitwarden => {
+                let file: BitwardenFile = self.parse(data)?;
+                Ok(file.encrypted)
+            }
+        }
+    }
+
+    /// Reads every account of `data`; `password` is needed when
+    /// `is_encrypted` says so.
+    pub fn read(&self, data: &[u8], password: Option<&str>) -> Result<Imported> {
+        match self {
+            Format::Aegis => read_aegis(data, password),
+            Format::AndOtp => {
+                if !self.is_encrypted(data)? {
+                    return read_andotp(data);
+                }
+                read_andotp(&decrypt_andotp(data, required(*self, password)?)?)
+            }
+            Format::TwoFas => read_twofas(data, password),
+            Format::Bitwarden => read_bitwarden(data, password),
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(|e| invalid(*self, e))
+    }
+}
+
+/// The fields every format maps onto ft_otp's account model.
+struct Fields {
+    kind: String,
+    label: String,
+    issuer: Option<String>,
+    secret: Sensitive,
+    algorithm: Option<String>,
+    digits: Option<u32>,
+    period: Option<u64>,
+    counter: Option<u64>,
+    tags: Vec<String>,
+    icon: Option<String>,
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
+fn to_entry(fields: Fields) -> Result<Entry> {
+    let mut params = TotpParams::default();
+    let kind = match fields.kind.to_ascii_lowercase().as_str() {
+        "totp" => OtpKind::Totp,
+        "hotp" => OtpKind::Hotp,
+        "steam" => {
+            params.encoding = Encoding::Steam;
+            params.digits = 5;
+            OtpKind::Totp
+        }
+        other => {
+            return Err(Error::InvalidExport(format!(
+                "unsupported OTP type '{}'",
+                other
+            )));
+        }
+    };
+    if let Some(algorithm) = fields.algorithm {
+        params.algorithm = algorithm.parse::<Algorithm>()?;
+    }
+    if let Some(digits) = fields.digits.filter(|_| params.encoding.is_decimal()) {
+        params.digits = digits;
+    }
+    if let Some(period) = fields.period {
+        params.period = period;
+    }
+    params.validate()?;
+
+    // The decoding error quotes the secret, which must not reach the output.
+    let secret = otpauth::decode_secret(&fields.secret.0)
+        .map_err(|_| Error::InvalidExport("the secret is not valid base32.".to_string()))?;
+    let mut key = KeyFile::new(secret, params);
+    key.kind = kind;
+    key.counter = fields.counter.unwrap_or(0);
+    Ok(Entry {
+        label: fields.label,
+        issuer: non_empty(fields.issuer),
+        key,
+        tags: fields.tags,
+        icon: fields.icon,
+    })
+}
+
+/// Adds the account to `imported`, or records why it was left out.
+fn push(imported: &mut Imported, fields: Fields) {
+    let label = fields.label.clone();
+    match to_entry(fields) {
+        Ok(entry) => imported.entries.push(entry),
+        Err(e) => imported.skipped.push(format!("'{}': {}", label, e)),
+    }
+}
+
+fn decode_hex(format: Format, value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|_| invalid(format, format!("'{}' is not hexadecimal.", value)))
+}
+
+fn decode_base64(format: Format, value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value.trim())
+        .map_err(|_| invalid(format, "malformed base64 data."))
+}
+
+/// AES-256-GCM with the tag appended to the ciphertext.
+fn open_gcm(key: &[u8], nonce: &[u8], sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    if key.len() != 32 || nonce.len() != 12 {
+        return None;
+    }
+    let cipher = Aes256Gcm::new_from_slice(key).ok()?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload::from(sealed))
+        .ok()
+        .map(Zeroizing::new)
+}
+
+// Aegis: https://github.com/beemdevelopment/Aegis/blob/master/docs/vault.md
+
+#[derive(Deserialize)]
+struct AegisFile {
+    header: AegisHeader,
+}
+
+#[derive(Deserialize)]
+struct AegisHeader {
+    slots: Option<Vec<AegisSlot>>,
+    params: Option<AegisParams>,
+}
+
+#[derive(Deserialize)]
+struct AegisSlot {
+    #[serde(rename = "type")]
+    kind: u8,
+    key: String,
+    key_params: AegisParams,
+    n: Option<u64>,
+    r: Option<u32>,
+    p: Option<u32>,
+    salt: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AegisParams {
+    nonce: String,
+    tag: String,
+}
+
+#[derive(Deserialize)]
+struct AegisPlain {
+    db: AegisDb,
+}
+
+#[derive(Deserialize)]
+struct AegisSealed {
+    db: String,
+}
+
+#[derive(Deserialize)]
+struct AegisDb {
+    #[serde(default)]
+    entries: Vec<AegisEntry>,
+    #[serde(default)]
+    groups: Vec<AegisGroup>,
+}
+
+#[derive(Deserialize)]
+struct AegisEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    issuer: Option<String>,
+    icon: Option<String>,
+    icon_mime: Option<String>,
+    info: AegisInfo,
+    /// Group uuids since version 3 of the database.
+    #[serde(default)]
+    groups: Vec<String>,
+    /// The group name in earlier versions.
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AegisInfo {
+    secret: Sensitive,
+    algo: Option<String>,
+    digits: Option<u32>,
+    period: Option<u64>,
+    counter: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct AegisGroup {
+    uuid: String,
+    name: String,
+}
+
+/// The master key, unwrapped with the first password slot that opens.
+fn aegis_master_key(slots: &[AegisSlot], password: &str) -> Result<Zeroizing<Vec<u8>>> {
+    const PASSWORD_SLOT: u8 = 1;
+    let format = Format::Aegis;
+    for slot in slots.iter().filter(|slot| slot.kind == PASSWORD_SLOT) {
+        let (Some(n), Some(r), Some(p), Some(salt)) = (slot.n, slot.r, slot.p, &slot.salt) else {
+            return Err(invalid(format, "password slot without scrypt parameters."));
+        };
+        if !n.is_power_of_two() || n < 2 {
+            return Err(invalid(format, "scrypt N must be a power of two."));
+        }
+        if n > MAX_SCRYPT_N || r > MAX_SCRYPT_R || p > MAX_SCRYPT_P {
+            return Err(invalid(
+                format,
+                format!(
+                    "scrypt parameters N={} r={} p={} exceed the limit of N={} r={} p={}.",
+                    n, r, p, MAX_SCRYPT_N, MAX_SCRYPT_R, MAX_SCRYPT_P
+                ),
+            ));
+        }
+        let params = scrypt::Params::new(n.trailing_zeros() as u8, r, p, 32)
+            .map_err(|_| invalid(format, "unsupported scrypt parameters."))?;
+        let mut derived = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(
+            password.as_bytes(),
+            &decode_hex(format, salt)?,
+            &params,
+            derived.as_mut(),
+        )
+        .map_err(|_| invalid(format, "unsupported scrypt parameters."))?;
+
+        let mut sealed = decode_hex(format, &slot.key)?;
+        sealed.extend(decode_hex(format, &slot.key_params.tag)?);
+        let nonce = decode_hex(format, &slot.key_params.nonce)?;
+        if let Some(key) = open_gcm(derived.as_ref(), &nonce, &sealed) {
+            return Ok(key);
+        }
+    }
+    Err(wrong_password(format))
+}
+
+fn read_aegis(data: &[u8], password: Option<&str>) -> Result<Imported> {
+    let format = Format::Aegis;
+    let file: AegisFile = format.parse(data)?;
+    let db = match (&file.header.slots, &file.header.params) {
+        (Some(slots), Some(params)) => {
+            let key = aegis_master_key(slots, required(format, password)?)?;
+            let sealed: AegisSealed = format.parse(data)?;
+            let mut ciphertext = decode_base64(format, &sealed.db)?;
+            ciphertext.extend(decode_hex(format, &params.tag)?);
+            let nonce = decode_hex(format, &params.nonce)?;
+            let plaintext =
+                open_gcm(&key, &nonce, &ciphertext).ok_or_else(|| wrong_password(format))?;
+            serde_json::from_slice::<AegisDb>(&plaintext).map_err(|e| invalid(format, e))?
+        }
+        (Some(_), None) => return Err(invalid(format, "encrypted vault without parameters.")),
+        (None, _) => format.parse::<AegisPlain>(data)?.db,
+    };
+
+    let groups: HashMap<&str, &str> = db
+        .groups
+        .iter()
+        .map(|group| (group.uuid.as_str(), group.name.as_str()))
+        .collect();
+    let mut imported = Imported::default();
+    for entry in db.entries {
+        let mut tags: Vec<String> = entry
+            .groups
+            .iter()
+            .filter_map(|uuid| groups.get(uuid.as_str()).map(|name| name.to_string()))
+            .collect();
+        tags.extend(non_empty(entry.group));
+        let icon = match (entry.icon, entry.icon_mime) {
+            (Some(icon), Some(mime)) => Some(format!("data:{};base64,{}", mime, icon)),
+            (Some(icon), None) => Some(format!("data:image/png;base64,{}", icon)),
+            _ => None,
+        };
+        push(
+            &mut imported,
+            Fields {
+                kind: entry.kind,
+                label: entry.name,
+                issuer: entry.issuer,
+                secret: entry.info.secret,
+                algorithm: entry.info.algo,
+                digits: entry.info.digits,
+                period: entry.info.period,
+                counter: entry.info.counter,
+                tags,
+                icon,
+            },
+        );
+    }
+    Ok(imported)
+}
+
+// andOTP: https://github.com/andOTP/andOTP/wiki/Backup-formats
+
+#[derive(Deserialize)]
+struct AndOtpEntry {
+    secret: Sensitive,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    label: String,
+    issuer: Option<String>,
+    algorithm: Option<String>,
+    digits: Option<u32>,
+    period: Option<u64>,
+    counter: Option<u64>,
+    thumbnail: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// `iterations (4 bytes) | salt | nonce | ciphertext | tag`, keyed with
+/// PBKDF2-HMAC-SHA1. Backups from before andOTP 0.6.3 lack the first two
+/// fields and are keyed with a bare SHA-256 of the password.
+fn decrypt_andotp(data: &[u8], password: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let format = Format::AndOtp;
+    let header = 4 + ANDOTP_SALT + ANDOTP_NONCE;
+    if data.len() >= header + GCM_TAG {
+        let iterations = u32::from_be_bytes(data[..4].try_into().unwrap());
+        if (1..=MAX_ANDOTP_ITERATIONS).contains(&iterations) {
+            let salt = &data[4..4 + ANDOTP_SALT];
+            let mut key = Zeroizing::new([0u8; 32]);
+            pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), salt, iterations, key.as_mut());
+            if let Some(plaintext) = open_gcm(
+                key.as_ref(),
+                &data[4 + ANDOTP_SALT..header],
+                &data[header..],
+            ) {
+                return Ok(plaintext);
+            }
+        }
+    }
+    if data.len() >= ANDOTP_NONCE + GCM_TAG {
+        let key = Zeroizing::new(<[u8; 32]>::from(Sha256::digest(password.as_bytes())));
+        if let Some(plaintext) =
+            open_gcm(key.as_ref(), &data[..ANDOTP_NONCE], &data[ANDOTP_NONCE..])
+        {
+            return Ok(plaintext);
+        }
+    }
+    Err(wrong_password(format))
+}
+
+fn read_andotp(data: &[u8]) -> Result<Imported> {
+    let entries: Vec<AndOtpEntry> = Format::AndOtp.parse(data)?;
+    let mut imported = Imported::default();
+    for entry in entries {
+        push(
+            &mut imported,
+            Fields {
+                kind: entry.kind,
+                label: entry.label,
+                issuer: entry.issuer,
+                secret: entry.secret,
+                algorithm: entry.algorithm,
+                digits: entry.digits,
+                period: entry.period,
+                counter: entry.counter,
+                tags: entry.tags,
+                icon: entry.thumbnail.filter(|name| name != "Default"),
+            },
+        );
+    }
+    Ok(imported)
+}
+
+// 2FAS: https://github.com/twofas/2fas-android (backup schema version 4)
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasFile {
+    #[serde(default)]
+    services: Vec<TwoFasService>,
+    #[serde(default)]
+    groups: Vec<TwoFasGroup>,
+    services_encrypted: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasService {
+    name: String,
+    secret: Sensitive,
+    #[serde(default)]
+    otp: TwoFasOtp,
+    group_id: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasOtp {
+    label: Option<String>,
+    account: Option<String>,
+    issuer: Option<String>,
+    digits: Option<u32>,
+    period: Option<u64>,
+    algorithm: Option<String>,
+    counter: Option<u64>,
+    token_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TwoFasGroup {
+    id: String,
+    name: String,
+}
+
+/// `servicesEncrypted` is `ciphertext+tag:salt:nonce` in base64, keyed with
+/// PBKDF2-HMAC-SHA256.
+fn decrypt_twofas(sealed: &str, password: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let format = Format::TwoFas;
+    let parts: Vec<&str> = sealed.split(':').collect();
+    let [ciphertext, salt, nonce] = parts[..] else {
+        return Err(invalid(format, "malformed encrypted services."));
+    };
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        password.as_bytes(),
+        &decode_base64(format, salt)?,
+        TWOFAS_ITERATIONS,
+        key.as_mut(),
+    );
+    open_gcm(
+        key.as_ref(),
+        &decode_base64(format, nonce)?,
+        &decode_base64(format, ciphertext)?,
+    )
+    .ok_or_else(|| wrong_password(format))
+}
+
+fn read_twofas(data: &[u8], password: Option<&str>) -> Result<Imported> {
+    let format = Format::TwoFas;
+    let file: TwoFasFile = format.parse(data)?;
+    let services = match file.services_encrypted.as_deref().filter(|s| !s.is_empty()) {
+        Some(sealed) => {
+            let plaintext = decrypt_twofas(sealed, required(format, password)?)?;
+            format.parse::<Vec<TwoFasService>>(&plaintext)?
+        }
+        None => file.services,
+    };
+
+    let groups: HashMap<&str, &str> = file
+        .groups
+        .iter()
+        .map(|group| (group.id.as_str(), group.name.as_str()))
+        .collect();
+    let mut imported = Imported::default();
+    for service in services {
+        let otp = service.otp;
+        let tags = service
+            .group_id
+            .and_then(|id| groups.get(id.as_str()).map(|name| name.to_string()))
+            .into_iter()
+            .collect();
+        push(
+            &mut imported,
+            Fields {
+                kind: otp.token_type.unwrap_or_else(|| "totp".to_string()),
+                label: non_empty(otp.account)
+                    .or(non_empty(otp.label))
+                    .unwrap_or_else(|| service.name.clone()),
+                issuer: non_empty(otp.issuer).or(Some(service.name)),
+                secret: service.secret,
+                algorithm: otp.algorithm,
+                digits: otp.digits,
+                period: otp.period,
+                counter: otp.counter,
+                tags,
+                icon: None,
+            },
+        );
+    }
+    Ok(imported)
+}
+
+// Bitwarden: the password manager's JSON export and the Authenticator app's
+// export share one layout. Password protected exports wrap it in an
+// `EncString` keyed from the password.
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenFile {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    password_protected: bool,
+    salt: Option<String>,
+    kdf_type: Option<u8>,
+    kdf_iterations: Option<u32>,
+    kdf_memory: Option<u32>,
+    kdf_parallelism: Option<u32>,
+    data: Option<String>,
+    #[serde(default)]
+    folders: Vec<BitwardenFolder>,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenFolder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenItem {
+    name: String,
+    folder_id: Option<String>,
+    login: Option<BitwardenLogin>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    username: Option<String>,
+    totp: Option<Sensitive>,
+}
+
+type Key = Zeroizing<[u8; 32]>;
+
+/// The export key: PBKDF2-HMAC-SHA256 (kdf 0) or Argon2id (kdf 1) of the
+/// password, stretched into encryption and MAC keys with HKDF-Expand.
+fn bitwarden_keys(file: &BitwardenFile, password: &str) -> Result<(Key, Key)> {
+    let format = Format::Bitwarden;
+    let salt = file
+        .salt
+        .as_deref()
+        .ok_or_else(|| invalid(format, "missing salt."))?;
+    let iterations = file
+        .kdf_iterations
+        .ok_or_else(|| invalid(format, "missing KDF iterations."))?;
+    let out_of_bounds = |name: &str, value: u32, max: u32| {
+        invalid(
+            format,
+            format!("{} {} is outside 1 to {}.", name, value, max),
+        )
+    };
+    let mut master = Zeroizing::new([0u8; 32]);
+    match file.kdf_type.unwrap_or(0) {
+        0 => {
+            if !(1..=MAX_BITWARDEN_PBKDF2_ITERATIONS).contains(&iterations) {
+                return Err(out_of_bounds(
+                    "PBKDF2 iterations",
+                    iterations,
+                    MAX_BITWARDEN_PBKDF2_ITERATIONS,
+                ));
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                iterations,
+                master.as_mut(),
+            )
+        }
+        1 => {
+            let memory = file.kdf_memory.unwrap_or(64);
+            let parallelism = file.kdf_parallelism.unwrap_or(4);
+            for (name, value, max) in [
+                (
+                    "Argon2 iterations",
+                    iterations,
+                    MAX_BITWARDEN_ARGON2_ITERATIONS,
+                ),
+                ("Argon2 memory (MiB)", memory, MAX_BITWARDEN_ARGON2_MEMORY),
+                (
+                    "Argon2 parallelism",
+                    parallelism,
+                    MAX_BITWARDEN_ARGON2_PARALLELISM,
+                ),
+            ] {
+                if !(1..=max).contains(&value) {
+                    return Err(out_of_bounds(name, value, max));
+                }
+            }
+            let params = argon2::Params::new(memory * 1024, iterations, parallelism, Some(32))
+                .map_err(|_| invalid(format, "unsupported Argon2 parameters."))?;
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(
+                    password.as_bytes(),
+                    &Sha256::digest(salt.as_bytes()),
+                    master.as_mut(),
+                )
+                .map_err(|_| invalid(format, "unsupported Argon2 parameters."))?;
+        }
+        kdf => return Err(invalid(format, format!("unsupported KDF type {}.", kdf))),
+    }
+
+    let expand = |info: &[u8]| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(master.as_ref())
+            .expect("HMAC takes keys of any length");
+        mac.update(info);
+        mac.update(&[1]);
+        Zeroizing::new(<[u8; 32]>::from(mac.finalize().into_bytes()))
+    };
+    Ok((expand(b"enc"), expand(b"mac")))
+}
+
+/// An `EncString` of type 2: `2.iv|ciphertext|mac`, AES-256-CBC then
+/// HMAC-SHA256 over the IV and ciphertext.
+fn open_enc_string(value: &str, enc: &[u8; 32], mac_key: &[u8; 32]) -> Result<Zeroizing<Vec<u8>>> {
+    let format = Format::Bitwarden;
+    let parts: Vec<&str> = value
+        .strip_prefix("2.")
+        .ok_or_else(|| invalid(format, "unsupported encryption type."))?
+        .split('|')
+        .collect();
+    let [iv, ciphertext, tag] = parts[..] else {
+        return Err(invalid(format, "malformed encrypted data."));
+    };
+    let (iv, ciphertext, tag) = (
+        decode_base64(format, iv)?,
+        decode_base64(format, ciphertext)?,
+        decode_base64(format, tag)?,
+    );
+
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC takes keys of any length");
+    mac.update(&iv);
+    mac.update(&ciphertext);
+    if !bool::from(mac.finalize().into_bytes().ct_eq(&tag)) {
+        return Err(wrong_password(format));
+    }
+
+    let decryptor = cbc::Decryptor::<Aes256>::new_from_slices(enc, &iv)
+        .map_err(|_| invalid(format, "malformed encrypted data."))?;
+    let mut buffer = Zeroizing::new(ciphertext);
+    let len = decryptor
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .map_err(|_| wrong_password(format))?
+        .len();
+    buffer.truncate(len);
+    Ok(buffer)
+}
+
+/// The `totp` field holds an otpauth URI, a `steam://` secret or a bare
+/// base32 secret.
+fn bitwarden_entry(item: BitwardenItem, totp: Sensitive, tags: Vec<String>) -> Result<Entry> {
+    let label = item
+        .login
+        .and_then(|login| non_empty(login.username))
+        .unwrap_or_else(|| item.name.clone());
+    if totp.0.to_ascii_lowercase().starts_with("otpauth://") {
+        let mut entry = otpauth::parse(&totp.0)?;
+        entry.issuer = entry.issuer.or(Some(item.name));
+        entry.tags = tags;
+        return Ok(entry);
+    }
+    let (kind, secret) = match totp.0.strip_prefix("steam://") {
+        Some(secret) => ("steam", Sensitive(Zeroizing::new(secret.to_string()))),
+        None => ("totp", totp),
+    };
+    to_entry(Fields {
+        kind: kind.to_string(),
+        issuer: Some(item.name).filter(|name| *name != label),
+        label,
+        secret,
+        algorithm: None,
+        digits: None,
+        period: None,
+        counter: None,
+        tags,
+        icon: None,
+    })
+}
+
+fn read_bitwarden(data: &[u8], password: Option<&str>) -> Result<Imported> {
+    let format = Format::Bitwarden;
+    let file: BitwardenFile = format.parse(data)?;
+    let file = if file.encrypted {
+        if !file.password_protected {
+            return Err(invalid(
+                format,
+                "encrypted with the account key, export it with a password instead.",
+            ));
+        }
+        let (enc, mac) = bitwarden_keys(&file, required(format, password)?)?;
+        let sealed = file
+            .data
+            .as_deref()
+            .ok_or_else(|| invalid(format, "missing encrypted data."))?;
+        format.parse::<BitwardenFile>(&open_enc_string(sealed, &enc, &mac)?)?
+    } else {
+        file
+    };
+
+    let folders: HashMap<&str, &str> = file
+        .folders
+        .iter()
+        .map(|folder| (folder.id.as_str(), folder.name.as_str()))
+        .collect();
+    let mut imported = Imported::default();
+    for mut item in file.items {
+        // Logins without a TOTP secret are plain passwords, not skipped accounts.
+        let Some(totp) = item.login.as_mut().and_then(|login| login.totp.take()) else {
+            continue;
+        };
+        let tags = item
+            .folder_id
+            .as_deref()
+            .and_then(|id| folders.get(id).map(|name| name.to_string()))
+            .into_iter()
+            .collect();
+        let name = item.name.clone();
+        match bitwarden_entry(item, totp, tags) {
+            Ok(entry) => imported.entries.push(entry),
+            Err(e) => imported.skipped.push(format!("'{}': {}", name, e)),
+        }
+    }
+    Ok(imported)
+}
@@ -0,0 +1,245 @@
+//! Portable backups: every account of a vault, encrypted under a passphrase
+//! of their own with the key file cipher, and merged back on import.
+//!
+//! A backup is `magic | key file envelope`. The envelope authenticates the
+//! whole archive, so a tampered or truncated backup is refused before any
+//! account reaches the store.
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher,
+    error::{Error, Result},
+    totp,
+    vault::{Entry, Vault},
+};
+
+const MAGIC: &[u8; 4] = b"FOBK";
+const FORMAT: &str = "ft_otp-backup";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    format: String,
+    version: u32,
+    /// Unix time of the export.
+    pub created: u64,
+    pub entries: Vec<Entry>,
+}
+
+pub fn is_backup(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+impl Backup {
+    pub fn new(entries: Vec<Entry>) -> Backup {
+        Backup {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            created: totp::now(),
+            entries,
+        }
+    }
+
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(self)?);
+        let encrypted = cipher::encrypt_key(&plaintext, passphrase)?;
+
+        let mut sealed = Vec::with_capacity(MAGIC.len() + encrypted.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend(encrypted);
+        Ok(sealed)
+    }
+
+    /// Decrypts and checks a backup. Fails on a wrong passphrase, any
+    /// modified byte, or accounts that would not load from a vault.
+    pub fn open(data: &[u8], passphrase: &str) -> Result<Backup> {
+        let Some(encrypted) = data.strip_prefix(MAGIC) else {
+            return Err(Error::InvalidBackup("not an ft_otp backup.".to_string()));
+        };
+        // Only the versioned envelope is authenticated with its header.
+        if cipher::is_legacy(encrypted) {
+            return Err(Error::InvalidBackup("not an ft_otp backup.".to_string()));
+        }
+
+        let plaintext = cipher::decrypt_key(encrypted, passphrase)?;
+        let backup: Backup = serde_json::from_slice(&plaintext)?;
+        if backup.format != FORMAT {
+            return Err(Error::InvalidBackup("not an ft_otp backup.".to_string()));
+        }
+        if backup.version != VERSION {
+            return Err(Error::UnsupportedFormat(format!(
+                "backup version {}",
+                backup.version
+            )));
+        }
+
+        let mut labels = HashSet::new();
+        for entry in &backup.entries {
+            entry.key.params.validate()?;
+            if !labels.insert(entry.label.as_str()) {
+                return Err(Error::InvalidBackup(format!(
+                    "the account '{}' appears twice.",
+                    entry.label
+                )));
+            }
+        }
+        Ok(backup)
+    }
+}
+
+/// What to do with an imported account whose label is already in the vault.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the account already in the vault.
+    #[default]
+    Skip,
+    Overwrite,
+    /// Import under the first free `label (N)`.
+    Rename,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Conflict::Skip => "skip",
+            Conflict::Overwrite => "overwrite",
+            Conflict::Rename => "rename",
+        })
+    }
+}
+
+impl FromStr for Conflict {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Conflict::Skip),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "rename" => Ok(Conflict::Rename),
+            _ => Err(Error::InvalidParams(format!(
+                "unknown conflict policy '{}': expected skip, overwrite or rename.",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merged {
+    Added,
+    Skipped,
+    Overwritten,
+    Renamed(String),
+}
+
+/// Adds `entries` to `vault`, settling label clashes with `conflict`.
+/// Returns each imported label with what happened to it.
+pub fn merge(vault: &mut Vault, entries: Vec<Entry>, conflict: Conflict) -> Vec<(String, Merged)> {
+    let mut report = Vec::with_capacity(entries.len());
+    for mut entry in entries {
+        let label = entry.label.clone();
+        let outcome = match (vault.position(&label), conflict) {
+            (Err(_), _) => {
+                vault.entries.push(entry);
+                Merged::Added
+            }
+            (Ok(_), Conflict::Skip) => Merged::Skipped,
+            (Ok(index), Conflict::Overwrite) => {
+                vault.entries[index] = entry;
+                Merged::Overwritten
+            }
+            (Ok(_), Conflict::Rename) => {
+                let new_label = (2..)
+                    .map(|n| format!("{} ({})", label, n))
+                    .find(|candidate| vault.position(candidate).is_err())
+                    .unwrap();
+                entry.label = new_label.clone();
+                vault.entries.push(entry);
+                Merged::Renamed(new_label)
+            }
+        };
+        report.push((label, outcome));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyFile, TotpParams};
+
+    fn entry(label: &str, byte: u8) -> Entry {
+        Entry {
+            label: label.to_string(),
+            issuer: Some("example".to_string()),
+            key: KeyFile::new(vec![byte; 20], TotpParams::default()),
+            tags: vec!["work".to_string()],
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn seals_and_opens() {
+        let sealed = Backup::new(vec![entry("github", 1), entry("gitlab", 2)])
+            .seal("backup pass")
+            .unwrap();
+        assert!(is_backup(&sealed));
+
+        let backup = Backup::open(&sealed, "backup pass").unwrap();
+        assert_eq!(backup.entries.len(), 2);
+        assert_eq!(backup.entries[1].key.secret.expose(), &[2; 20]);
+        assert_eq!(backup.entries[0].tags, ["work"]);
+
+        assert!(matches!(
+            Backup::open(&sealed, "vault pass"),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn refuses_tampered_backups() {
+        let sealed = Backup::new(vec![entry("github", 1)]).seal("pass").unwrap();
+        for position in [MAGIC.len() + 10, sealed.len() / 2, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(Backup::open(&tampered, "pass").is_err());
+        }
+        assert!(Backup::open(&sealed[..sealed.len() - 1], "pass").is_err());
+        assert!(Backup::open(&sealed[MAGIC.len()..], "pass").is_err());
+    }
+
+    #[test]
+    fn merges_with_each_policy() {
+        let existing = || {
+            let mut vault = Vault::default();
+            vault.add(entry("github", 1)).unwrap();
+            vault
+        };
+        let incoming = || vec![entry("github", 9), entry("bank", 9)];
+
+        let mut vault = existing();
+        let report = merge(&mut vault, incoming(), Conflict::Skip);
+        assert_eq!(report[0], ("github".to_string(), Merged::Skipped));
+        assert_eq!(report[1], ("bank".to_string(), Merged::Added));
+        assert_eq!(vault.get("github").unwrap().key.secret.expose(), &[1; 20]);
+
+        let mut vault = existing();
+        merge(&mut vault, incoming(), Conflict::Overwrite);
+        assert_eq!(vault.entries.len(), 2);
+        assert_eq!(vault.get("github").unwrap().key.secret.expose(), &[9; 20]);
+
+        let mut vault = existing();
+        merge(&mut vault, incoming(), Conflict::Rename);
+        let report = merge(&mut vault, vec![entry("github", 8)], Conflict::Rename);
+        assert_eq!(report[0].1, Merged::Renamed("github (3)".to_string()));
+        assert_eq!(
+            vault.get("github (2)").unwrap().key.secret.expose(),
+            &[9; 20]
+        );
+        assert_eq!(vault.entries.len(), 4);
+    }
+}
@@ -1,40 +1,874 @@
+//! The envelope shared by key files, vaults and backups.
+//!
+//! ```text
+//! magic "FOTP" | version | backend id | backend fields | sealed content
+//! ```
+//!
+//! The backend id says how the content is sealed, so a file is opened with
+//! whatever it was written with. Everything before the sealed content is
+//! authenticated along with it.
+
+use std::{
+    env, fmt, fs,
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
 use aes_gcm::{
     Aes256Gcm, Key, Nonce,
-    aead::{Aead, AeadCore, KeyInit, OsRng, Result},
+    aead::{
+        Aead, AeadCore, KeyInit, OsRng, Payload, generic_array::typenum::Unsigned,
+        rand_core::RngCore,
+    },
 };
+use age::secrecy::ExposeSecret;
+use argon2::{Argon2, Params};
+use chacha20poly1305::XChaCha20Poly1305;
 use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{Error, Result},
+    secret::Passphrase,
+};
 
 const NONCE_SIZE: usize = 12;
+const SALT_SIZE: usize = 16;
+
+const MAGIC: &[u8; 4] = b"FOTP";
+const VERSION: u8 = 1;
+const PREFIX_SIZE: usize = MAGIC.len() + 2;
+const KDF_SIZE: usize = 3 * 4 + SALT_SIZE;
+
+/// File holding the age identities (`AGE-SECRET-KEY-1...` lines, as written
+/// by `age-keygen`) that open age-sealed files.
+pub const AGE_IDENTITY_ENV: &str = "FT_OTP_AGE_IDENTITY";
+
+/// Kernel keyring entries are named `ft_otp:<name>`.
+const KEYRING_PREFIX: &str = "ft_otp:";
+const KEYRING_SECRET_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Highest parameters read from a header: four times the defaults. The
+    /// header is not authenticated before the key is derived, so without a
+    /// ceiling a crafted file could ask for gigabytes of memory or hours of
+    /// work.
+    pub const MAX: KdfParams = KdfParams {
+        m_cost: Params::DEFAULT_M_COST * 4,
+        t_cost: Params::DEFAULT_T_COST * 4,
+        p_cost: Params::DEFAULT_P_COST * 4,
+    };
+
+    fn check_bounds(&self) -> Result<()> {
+        let max = KdfParams::MAX;
+        if self.m_cost > max.m_cost || self.t_cost > max.t_cost || self.p_cost > max.p_cost {
+            return Err(Error::InvalidParams(format!(
+                "KDF parameters m={} t={} p={} exceed the limit of m={} t={} p={}.",
+                self.m_cost, self.t_cost, self.p_cost, max.m_cost, max.t_cost, max.p_cost
+            )));
+        }
+        Ok(())
+    }
+}
 
-fn derive_key(passphrase: &str) -> [u8; 32] {
+/// How a file is sealed, as recorded in its header. Files written before
+/// there was a choice hold 1, the Argon2id KDF id they used to record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendId {
+    /// Argon2id passphrase, AES-256-GCM with a 12-byte random nonce.
+    AesGcm = 1,
+    /// Argon2id passphrase, XChaCha20-Poly1305 with a 24-byte random nonce.
+    XChaCha20Poly1305 = 2,
+    /// age, to one or more X25519 recipients.
+    Age = 3,
+    /// XChaCha20-Poly1305 under a random key kept in the kernel keyring.
+    Keyring = 4,
+}
+
+impl BackendId {
+    fn from_byte(byte: u8) -> Result<BackendId> {
+        match byte {
+            1 => Ok(BackendId::AesGcm),
+            2 => Ok(BackendId::XChaCha20Poly1305),
+            3 => Ok(BackendId::Age),
+            4 => Ok(BackendId::Keyring),
+            other => Err(Error::UnsupportedFormat(format!("backend {}", other))),
+        }
+    }
+
+    /// Whether files sealed this way are opened with the passphrase. The
+    /// others are opened with a key held elsewhere.
+    pub fn takes_passphrase(self) -> bool {
+        matches!(self, BackendId::AesGcm | BackendId::XChaCha20Poly1305)
+    }
+}
+
+impl fmt::Display for BackendId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BackendId::AesGcm => "aes-gcm",
+            BackendId::XChaCha20Poly1305 => "xchacha20-poly1305",
+            BackendId::Age => "age",
+            BackendId::Keyring => "keyring",
+        })
+    }
+}
+
+impl FromStr for BackendId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BackendId> {
+        match s {
+            "aes-gcm" => Ok(BackendId::AesGcm),
+            "xchacha20-poly1305" => Ok(BackendId::XChaCha20Poly1305),
+            "age" => Ok(BackendId::Age),
+            "keyring" => Ok(BackendId::Keyring),
+            _ => Err(Error::InvalidParams(format!(
+                "unknown backend '{}' (aes-gcm, xchacha20-poly1305, age or keyring).",
+                s
+            ))),
+        }
+    }
+}
+
+/// A way of sealing the content of a file, holding what it needs to do so.
+pub trait Backend {
+    fn id(&self) -> BackendId;
+
+    /// Appends this backend's fields to `header`, then seals `content` so
+    /// that the whole header is authenticated. Returns what follows the header.
+    fn seal(&self, header: &mut Vec<u8>, content: &[u8]) -> Result<Vec<u8>>;
+
+    /// Opens `sealed`, the rest of a file whose header (fields included) is
+    /// `header`.
+    fn open(&self, header: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+fn derive_key_legacy(passphrase: &str) -> Zeroizing<[u8; 32]> {
     let mut hasher = Sha256::new();
     hasher.update(passphrase.as_bytes());
-    hasher.finalize().into()
+    Zeroizing::new(hasher.finalize().into())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| Error::InvalidParams(format!("invalid KDF parameters: {}", e)))?;
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut derived_key = Zeroizing::new([0u8; 32]);
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, derived_key.as_mut())
+        .map_err(|e| Error::InvalidParams(format!("key derivation failed: {}", e)))?;
+    Ok(derived_key)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+/// Seals `content` with AEAD `A` under `key`, appending the random nonce to
+/// `header` first.
+fn seal_aead<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    header: &mut Vec<u8>,
+    content: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = A::new_from_slice(key).map_err(|_| Error::Encrypt)?;
+    let nonce = A::generate_nonce(&mut OsRng);
+    header.extend_from_slice(&nonce);
+    cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: content,
+                aad: header,
+            },
+        )
+        .map_err(|_| Error::Encrypt)
+}
+
+/// Opens what `seal_aead` sealed: the nonce ends the header.
+fn open_aead<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    header: &[u8],
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let nonce_size = A::NonceSize::USIZE;
+    if header.len() < nonce_size {
+        return Err(Error::Decrypt);
+    }
+    let cipher = A::new_from_slice(key).map_err(|_| Error::Decrypt)?;
+    let nonce = aes_gcm::aead::Nonce::<A>::from_slice(&header[header.len() - nonce_size..]);
+    let plaintext = cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: sealed,
+                aad: header,
+            },
+        )
+        .map_err(|_| Error::Decrypt)?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Argon2id from a passphrase into AEAD `A`. The KDF parameters and salt
+/// are in the header, followed by the nonce.
+struct PassphraseAead<'a, A> {
+    id: BackendId,
+    passphrase: &'a str,
+    kdf: KdfParams,
+    aead: std::marker::PhantomData<A>,
+}
+
+impl<A: Aead + AeadCore + KeyInit> Backend for PassphraseAead<'_, A> {
+    fn id(&self) -> BackendId {
+        self.id
+    }
+
+    fn seal(&self, header: &mut Vec<u8>, content: &[u8]) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        header.extend_from_slice(&self.kdf.m_cost.to_le_bytes());
+        header.extend_from_slice(&self.kdf.t_cost.to_le_bytes());
+        header.extend_from_slice(&self.kdf.p_cost.to_le_bytes());
+        header.extend_from_slice(&salt);
+
+        let derived_key = derive_key(self.passphrase, &salt, &self.kdf)?;
+        seal_aead::<A>(derived_key.as_ref(), header, content)
+    }
+
+    fn open(&self, header: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        // Callers may hand any bytes to a public backend, not only headers
+        // `backend_of` has measured.
+        if header.len() < PREFIX_SIZE + KDF_SIZE + A::NonceSize::USIZE {
+            return Err(Error::Decrypt);
+        }
+        let salt = &header[PREFIX_SIZE + KDF_SIZE - SALT_SIZE..PREFIX_SIZE + KDF_SIZE];
+        let derived_key =
+            derive_key(self.passphrase, salt, &self.kdf).map_err(|_| Error::Decrypt)?;
+        open_aead::<A>(derived_key.as_ref(), header, sealed)
+    }
+}
+
+/// The original backend, and the default.
+pub fn aes_gcm<'a>(passphrase: &'a str, kdf: KdfParams) -> impl Backend + 'a {
+    PassphraseAead::<Aes256Gcm> {
+        id: BackendId::AesGcm,
+        passphrase,
+        kdf,
+        aead: std::marker::PhantomData,
+    }
+}
+
+/// Like `aes_gcm`, with nonces large enough to be picked at random without
+/// a second thought.
+pub fn xchacha20_poly1305<'a>(passphrase: &'a str, kdf: KdfParams) -> impl Backend + 'a {
+    PassphraseAead::<XChaCha20Poly1305> {
+        id: BackendId::XChaCha20Poly1305,
+        passphrase,
+        kdf,
+        aead: std::marker::PhantomData,
+    }
+}
+
+/// age to X25519 recipients, so a team key can open the file as well as its
+/// owner. The recipients are listed in the header so the file can be saved
+/// again to the same ones, and the sealed content starts with a hash of the
+/// header, which age itself does not authenticate.
+pub struct Age {
+    pub recipients: Vec<age::x25519::Recipient>,
+    /// Tried in turn when opening.
+    pub identities: Vec<age::x25519::Identity>,
+}
+
+impl Age {
+    /// The identities in the file named by `FT_OTP_AGE_IDENTITY`: one per
+    /// line, with `#` comments as `age-keygen` writes them.
+    pub fn identities_from_env() -> Result<Vec<age::x25519::Identity>> {
+        let path = env::var_os(AGE_IDENTITY_ENV).ok_or_else(|| {
+            Error::Backend(format!(
+                "age-encrypted file: set {} to your identity file.",
+                AGE_IDENTITY_ENV
+            ))
+        })?;
+        let text = Zeroizing::new(
+            fs::read_to_string(&path)
+                .map_err(|e| Error::Backend(format!("{}: {}", Path::new(&path).display(), e)))?,
+        );
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse()
+                    .map_err(|e| Error::Backend(format!("{}: {}", Path::new(&path).display(), e)))
+            })
+            .collect()
+    }
+
+    fn read_recipients(fields: &[u8]) -> Result<(Vec<age::x25519::Recipient>, usize)> {
+        let count = *fields.first().ok_or(Error::Decrypt)? as usize;
+        let mut at = 1;
+        let mut recipients = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = *fields.get(at).ok_or(Error::Decrypt)? as usize;
+            let text = fields.get(at + 1..at + 1 + len).ok_or(Error::Decrypt)?;
+            let recipient = std::str::from_utf8(text)
+                .ok()
+                .and_then(|text| text.parse().ok())
+                .ok_or(Error::Decrypt)?;
+            recipients.push(recipient);
+            at += 1 + len;
+        }
+        Ok((recipients, at))
+    }
+}
+
+impl Backend for Age {
+    fn id(&self) -> BackendId {
+        BackendId::Age
+    }
+
+    fn seal(&self, header: &mut Vec<u8>, content: &[u8]) -> Result<Vec<u8>> {
+        if self.recipients.is_empty() || self.recipients.len() > u8::MAX as usize {
+            return Err(Error::InvalidParams(
+                "age needs between 1 and 255 recipients.".to_string(),
+            ));
+        }
+        header.push(self.recipients.len() as u8);
+        for recipient in &self.recipients {
+            let text = recipient.to_string();
+            header.push(text.len() as u8);
+            header.extend_from_slice(text.as_bytes());
+        }
+
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient);
+        let encryptor = age::Encryptor::with_recipients(recipients).map_err(|_| Error::Encrypt)?;
+        let mut sealed = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(&mut sealed)
+            .map_err(|_| Error::Encrypt)?;
+        writer
+            .write_all(&Sha256::digest(&*header))
+            .and_then(|_| writer.write_all(content))
+            .and_then(|_| writer.finish())
+            .map_err(|_| Error::Encrypt)?;
+        Ok(sealed)
+    }
+
+    fn open(&self, header: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let decryptor = age::Decryptor::new(sealed).map_err(|_| Error::Decrypt)?;
+        let identities = self
+            .identities
+            .iter()
+            .map(|identity| identity as &dyn age::Identity);
+        let mut reader = decryptor.decrypt(identities).map_err(|e| match e {
+            age::DecryptError::NoMatchingKeys => Error::Backend(format!(
+                "none of the identities in {} is a recipient of this file.",
+                AGE_IDENTITY_ENV
+            )),
+            _ => Error::Decrypt,
+        })?;
+        let mut plaintext = Zeroizing::new(Vec::new());
+        reader
+            .read_to_end(&mut plaintext)
+            .map_err(|_| Error::Decrypt)?;
+
+        let digest = Sha256::digest(header);
+        if !plaintext.starts_with(&digest) {
+            return Err(Error::Decrypt);
+        }
+        Ok(Zeroizing::new(plaintext[digest.len()..].to_vec()))
+    }
+}
+
+/// XChaCha20-Poly1305 under a random key stored in the user's kernel
+/// keyring as `ft_otp:<name>`, created on first use. No passphrase is
+/// asked for, but the keyring is emptied on reboot: keep a backup.
+pub struct Keyring {
+    pub name: String,
+}
+
+impl Keyring {
+    fn description(&self) -> String {
+        format!("{}{}", KEYRING_PREFIX, self.name)
+    }
+
+    /// The stored key, or a new one if `create` and there is none yet.
+    fn secret(&self, create: bool) -> Result<Zeroizing<Vec<u8>>> {
+        let secret = keyring::read(&self.description(), create)?;
+        if secret.len() != KEYRING_SECRET_SIZE {
+            return Err(Error::Backend(format!(
+                "kernel keyring entry '{}' is not an ft_otp key.",
+                self.description()
+            )));
+        }
+        Ok(secret)
+    }
+
+    fn read_name(fields: &[u8]) -> Result<(Keyring, usize)> {
+        let len = fields
+            .get(..2)
+            .map(|len| u16::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or(Error::Decrypt)?;
+        let name = fields
+            .get(2..2 + len)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(Error::Decrypt)?;
+        let keyring = Keyring {
+            name: name.to_string(),
+        };
+        Ok((keyring, 2 + len))
+    }
+}
+
+impl Backend for Keyring {
+    fn id(&self) -> BackendId {
+        BackendId::Keyring
+    }
+
+    fn seal(&self, header: &mut Vec<u8>, content: &[u8]) -> Result<Vec<u8>> {
+        let name = self.name.as_bytes();
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidParams("invalid keyring name.".to_string()));
+        }
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(name);
+        let secret = self.secret(true)?;
+        seal_aead::<XChaCha20Poly1305>(&secret, header, content)
+    }
+
+    fn open(&self, header: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let secret = self.secret(false)?;
+        open_aead::<XChaCha20Poly1305>(&secret, header, sealed)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod keyring {
+    use aes_gcm::aead::{OsRng, rand_core::RngCore};
+    use linux_keyutils::{KeyError, KeyRing, KeyRingIdentifier};
+    use zeroize::Zeroizing;
+
+    use super::KEYRING_SECRET_SIZE;
+    use crate::error::{Error, Result};
+
+    fn error(description: &str, e: KeyError) -> Error {
+        Error::Backend(format!("kernel keyring entry '{}': {}", description, e))
+    }
+
+    pub fn read(description: &str, create: bool) -> Result<Zeroizing<Vec<u8>>> {
+        let keyring = KeyRing::from_special_id(KeyRingIdentifier::User, true)
+            .map_err(|e| error(description, e))?;
+        match keyring.search(description) {
+            Ok(key) => Ok(Zeroizing::new(
+                key.read_to_vec().map_err(|e| error(description, e))?,
+            )),
+            Err(KeyError::KeyDoesNotExist) if create => {
+                let mut secret = Zeroizing::new(vec![0u8; KEYRING_SECRET_SIZE]);
+                OsRng.fill_bytes(&mut secret);
+                keyring
+                    .add_key(description, &*secret)
+                    .map_err(|e| error(description, e))?;
+                Ok(secret)
+            }
+            Err(KeyError::KeyDoesNotExist) => Err(Error::Backend(format!(
+                "no entry '{}' in the kernel keyring (it is emptied on reboot, restore the file from a backup).",
+                description
+            ))),
+            Err(e) => Err(error(description, e)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod keyring {
+    use zeroize::Zeroizing;
+
+    use crate::error::{Error, Result};
+
+    pub fn read(_description: &str, _create: bool) -> Result<Zeroizing<Vec<u8>>> {
+        Err(Error::Backend(
+            "the kernel keyring is only available on Linux.".to_string(),
+        ))
+    }
+}
+
+/// Files written before the versioned header only hold `nonce || ciphertext`.
+pub fn is_legacy(encrypted: &[u8]) -> bool {
+    !encrypted.starts_with(MAGIC)
 }
 
 pub fn encrypt_key(content: &[u8], passphrase: &str) -> Result<Vec<u8>> {
-    let derived_key = derive_key(passphrase);
-    let key: &Key<Aes256Gcm> = (&derived_key).into();
-    let cipher = Aes256Gcm::new(key);
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, content)?;
+    encrypt_key_with(content, passphrase, &KdfParams::default())
+}
+
+pub fn encrypt_key_with(content: &[u8], passphrase: &str, kdf: &KdfParams) -> Result<Vec<u8>> {
+    encrypt_with(content, &aes_gcm(passphrase, *kdf))
+}
+
+pub fn encrypt_with(content: &[u8], backend: &dyn Backend) -> Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(PREFIX_SIZE + KDF_SIZE + 24);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.push(backend.id() as u8);
+    let sealed = backend.seal(&mut header, content)?;
 
-    let mut result = nonce.to_vec();
-    result.extend(ciphertext);
+    let mut result = header;
+    result.extend(sealed);
     Ok(result)
 }
 
-pub fn decrypt_key(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+/// The backend `encrypted` was sealed with.
+pub fn backend_id(encrypted: &[u8]) -> Result<BackendId> {
+    if is_legacy(encrypted) {
+        return Ok(BackendId::AesGcm);
+    }
+    if encrypted.len() < PREFIX_SIZE {
+        return Err(Error::Decrypt);
+    }
+    let version = encrypted[4];
+    if version != VERSION {
+        return Err(Error::UnsupportedFormat(format!("version {}", version)));
+    }
+    BackendId::from_byte(encrypted[5])
+}
+
+/// The backend `encrypted` was sealed with, set up the same way (KDF
+/// parameters, age recipients, keyring entry), and where its header ends.
+fn backend_of<'a>(encrypted: &[u8], passphrase: &'a str) -> Result<(Box<dyn Backend + 'a>, usize)> {
+    let id = backend_id(encrypted)?;
+    let fields = &encrypted[PREFIX_SIZE..];
+    match id {
+        BackendId::AesGcm | BackendId::XChaCha20Poly1305 => {
+            if fields.len() < KDF_SIZE {
+                return Err(Error::Decrypt);
+            }
+            let kdf = KdfParams {
+                m_cost: read_u32(&fields[0..4]),
+                t_cost: read_u32(&fields[4..8]),
+                p_cost: read_u32(&fields[8..12]),
+            };
+            kdf.check_bounds()?;
+            Ok(match id {
+                BackendId::AesGcm => (
+                    Box::new(aes_gcm(passphrase, kdf)),
+                    PREFIX_SIZE + KDF_SIZE + NONCE_SIZE,
+                ),
+                _ => (
+                    Box::new(xchacha20_poly1305(passphrase, kdf)),
+                    PREFIX_SIZE + KDF_SIZE + <XChaCha20Poly1305 as AeadCore>::NonceSize::USIZE,
+                ),
+            })
+        }
+        BackendId::Age => {
+            let (recipients, len) = Age::read_recipients(fields)?;
+            let backend = Age {
+                recipients,
+                identities: Age::identities_from_env()?,
+            };
+            Ok((Box::new(backend), PREFIX_SIZE + len))
+        }
+        BackendId::Keyring => {
+            let (backend, len) = Keyring::read_name(fields)?;
+            let nonce_size = <XChaCha20Poly1305 as AeadCore>::NonceSize::USIZE;
+            Ok((Box::new(backend), PREFIX_SIZE + len + nonce_size))
+        }
+    }
+}
+
+/// Seals `content` the way `previous` was, under `passphrase` when that
+/// backend takes one. Legacy files move to the default backend.
+pub fn encrypt_like(previous: &[u8], content: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if is_legacy(previous) {
+        return encrypt_key(content, passphrase);
+    }
+    let (backend, _) = backend_of(previous, passphrase)?;
+    encrypt_with(content, &*backend)
+}
+
+/// Seals `content` for `path` the way the file there is sealed, or with the
+/// default backend if there is none yet.
+pub fn encrypt_for(path: &Path, content: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(previous) => encrypt_like(&previous, content, passphrase),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => encrypt_key(content, passphrase),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What stands in for the passphrase of a file that is opened without one:
+/// derived from the age identity or keyring key that opens it, so the audit
+/// log key is still protected by a secret. With age it depends on the
+/// identity, so each member of a team keeps their own audit log. `None` for
+/// passphrase backends.
+pub fn unattended_passphrase(encrypted: &[u8]) -> Result<Option<Passphrase>> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"ft_otp unattended passphrase\0");
+    match backend_id(encrypted)? {
+        BackendId::AesGcm | BackendId::XChaCha20Poly1305 => return Ok(None),
+        BackendId::Age => {
+            let (recipients, _) = Age::read_recipients(&encrypted[PREFIX_SIZE..])?;
+            let identity = Age::identities_from_env()?
+                .into_iter()
+                .find(|identity| recipients.contains(&identity.to_public()))
+                .ok_or_else(|| {
+                    Error::Backend(format!(
+                        "none of the identities in {} is a recipient of this file.",
+                        AGE_IDENTITY_ENV
+                    ))
+                })?;
+            hasher.update(identity.to_string().expose_secret().as_bytes());
+        }
+        BackendId::Keyring => {
+            let (keyring, _) = Keyring::read_name(&encrypted[PREFIX_SIZE..])?;
+            hasher.update(&*keyring.secret(false)?);
+        }
+    }
+    Ok(Some(Passphrase::new(hex::encode(hasher.finalize()))))
+}
+
+fn decrypt_legacy(encrypted: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
     if encrypted.len() < NONCE_SIZE {
-        return Err(aes_gcm::aead::Error);
+        return Err(Error::Decrypt);
     }
 
-    let derived_key = derive_key(passphrase);
-    let key: &Key<Aes256Gcm> = (&derived_key).into();
+    let derived_key = derive_key_legacy(passphrase);
+    let key: &Key<Aes256Gcm> = (&*derived_key).into();
     let cipher = Aes256Gcm::new(key);
     let nonce = Nonce::from_slice(&encrypted[..NONCE_SIZE]);
     let ciphertext = &encrypted[NONCE_SIZE..];
-    let plaintext = cipher.decrypt(nonce, ciphertext)?;
+    let plaintext = cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|_| Error::Decrypt)?;
+
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Opens `encrypted` with the backend named in its header. The passphrase is
+/// only used by the backends that take one. The plaintext is wiped when the
+/// returned buffer is dropped.
+pub fn decrypt_key(encrypted: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if is_legacy(encrypted) {
+        return decrypt_legacy(encrypted, passphrase);
+    }
+    let (backend, header_len) = backend_of(encrypted, passphrase)?;
+    if encrypted.len() < header_len {
+        return Err(Error::Decrypt);
+    }
+    let (header, sealed) = encrypted.split_at(header_len);
+    backend.open(header, sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn round_trip() {
+        let encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        assert!(!is_legacy(&encrypted));
+        assert_eq!(*decrypt_key(&encrypted, "passphrase").unwrap(), b"secret");
+        assert!(decrypt_key(&encrypted, "wrong").is_err());
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let mut encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        encrypted[10] ^= 1;
+        assert!(decrypt_key(&encrypted, "passphrase").is_err());
+    }
+
+    #[test]
+    fn backends_refuse_truncated_headers() {
+        let encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        let aes = aes_gcm("passphrase", TEST_KDF);
+        let xchacha = xchacha20_poly1305("passphrase", TEST_KDF);
+        for len in [0, PREFIX_SIZE, PREFIX_SIZE + KDF_SIZE] {
+            let header = &encrypted[..len];
+            assert!(matches!(aes.open(header, &[]), Err(Error::Decrypt)));
+            assert!(matches!(xchacha.open(header, &[]), Err(Error::Decrypt)));
+        }
+    }
+
+    #[test]
+    fn refuses_kdf_parameters_above_the_limit() {
+        let encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        for (offset, max) in [
+            (6, KdfParams::MAX.m_cost),
+            (10, KdfParams::MAX.t_cost),
+            (14, KdfParams::MAX.p_cost),
+        ] {
+            let mut crafted = encrypted.clone();
+            crafted[offset..offset + 4].copy_from_slice(&(max + 1).to_le_bytes());
+            let error = decrypt_key(&crafted, "passphrase").unwrap_err();
+            assert!(error.to_string().contains("exceed the limit"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn reads_legacy_layout() {
+        let derived_key = derive_key_legacy("passphrase");
+        let cipher = Aes256Gcm::new((&*derived_key).into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut legacy = nonce.to_vec();
+        legacy.extend(cipher.encrypt(&nonce, b"secret".as_ref()).unwrap());
+
+        assert!(is_legacy(&legacy));
+        assert_eq!(*decrypt_key(&legacy, "passphrase").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn aes_gcm_layout_is_unchanged() {
+        let encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        assert_eq!(&encrypted[..6], b"FOTP\x01\x01");
+        let header_len = PREFIX_SIZE + KDF_SIZE + NONCE_SIZE;
+        assert_eq!(encrypted.len(), header_len + b"secret".len() + 16);
+        assert_eq!(read_u32(&encrypted[6..10]), TEST_KDF.m_cost);
+    }
+
+    #[test]
+    fn xchacha20_poly1305_round_trip() {
+        let backend = xchacha20_poly1305("passphrase", TEST_KDF);
+        let encrypted = encrypt_with(b"secret", &backend).unwrap();
+        assert_eq!(
+            backend_id(&encrypted).unwrap(),
+            BackendId::XChaCha20Poly1305
+        );
+        assert_eq!(*decrypt_key(&encrypted, "passphrase").unwrap(), b"secret");
+        assert!(decrypt_key(&encrypted, "wrong").is_err());
+        assert!(unattended_passphrase(&encrypted).unwrap().is_none());
+
+        let mut tampered = encrypted.clone();
+        tampered[PREFIX_SIZE + KDF_SIZE] ^= 1;
+        assert!(decrypt_key(&tampered, "passphrase").is_err());
+    }
 
-    Ok(plaintext)
+    #[test]
+    fn saving_again_keeps_the_backend() {
+        let encrypted = encrypt_with(b"old", &xchacha20_poly1305("passphrase", TEST_KDF)).unwrap();
+        let again = encrypt_like(&encrypted, b"new", "passphrase").unwrap();
+        assert_eq!(backend_id(&again).unwrap(), BackendId::XChaCha20Poly1305);
+        assert_eq!(&again[6..18], &encrypted[6..18]);
+        assert_eq!(*decrypt_key(&again, "passphrase").unwrap(), b"new");
+    }
+
+    #[test]
+    fn age_opens_with_any_recipient() {
+        let alice = age::x25519::Identity::generate();
+        let team = age::x25519::Identity::generate();
+        let backend = Age {
+            recipients: vec![alice.to_public(), team.to_public()],
+            identities: Vec::new(),
+        };
+        let encrypted = encrypt_with(b"secret", &backend).unwrap();
+        assert_eq!(backend_id(&encrypted).unwrap(), BackendId::Age);
+
+        let (recipients, header_len) = Age::read_recipients(&encrypted[PREFIX_SIZE..]).unwrap();
+        assert_eq!(recipients, backend.recipients);
+        let (header, sealed) = encrypted.split_at(PREFIX_SIZE + header_len);
+        for identity in [alice, team] {
+            let opener = Age {
+                recipients: Vec::new(),
+                identities: vec![identity],
+            };
+            assert_eq!(*opener.open(header, sealed).unwrap(), b"secret");
+        }
+
+        let stranger = Age {
+            recipients: Vec::new(),
+            identities: vec![age::x25519::Identity::generate()],
+        };
+        assert!(matches!(
+            stranger.open(header, sealed),
+            Err(Error::Backend(_))
+        ));
+    }
+
+    #[test]
+    fn age_header_is_authenticated() {
+        let alice = age::x25519::Identity::generate();
+        let backend = Age {
+            recipients: vec![alice.to_public()],
+            identities: Vec::new(),
+        };
+        let encrypted = encrypt_with(b"secret", &backend).unwrap();
+        let (_, header_len) = Age::read_recipients(&encrypted[PREFIX_SIZE..]).unwrap();
+        let (header, sealed) = encrypted.split_at(PREFIX_SIZE + header_len);
+
+        // Listing another recipient would have the next save go to them too.
+        let mallory = age::x25519::Identity::generate().to_public().to_string();
+        let mut forged = header[..PREFIX_SIZE].to_vec();
+        forged.push(1);
+        forged.push(mallory.len() as u8);
+        forged.extend_from_slice(mallory.as_bytes());
+        let opener = Age {
+            recipients: Vec::new(),
+            identities: vec![alice],
+        };
+        assert!(opener.open(header, sealed).is_ok());
+        assert!(matches!(opener.open(&forged, sealed), Err(Error::Decrypt)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keyring_needs_no_passphrase() {
+        use linux_keyutils::{KeyRing, KeyRingIdentifier};
+
+        let name = format!("test-{}-{}", std::process::id(), OsRng.next_u64());
+        let encrypted = encrypt_with(b"secret", &Keyring { name: name.clone() }).unwrap();
+        assert_eq!(backend_id(&encrypted).unwrap(), BackendId::Keyring);
+        assert_eq!(*decrypt_key(&encrypted, "").unwrap(), b"secret");
+
+        let stand_in = unattended_passphrase(&encrypted).unwrap().unwrap();
+        assert_eq!(stand_in.expose().len(), 64);
+        let again = encrypt_like(&encrypted, b"new", stand_in.expose()).unwrap();
+        assert_eq!(backend_id(&again).unwrap(), BackendId::Keyring);
+        assert_eq!(*decrypt_key(&again, "").unwrap(), b"new");
+
+        let keyring = KeyRing::from_special_id(KeyRingIdentifier::User, false).unwrap();
+        let key = keyring
+            .search(&format!("{}{}", KEYRING_PREFIX, name))
+            .unwrap();
+        key.invalidate().unwrap();
+        assert!(matches!(decrypt_key(&again, ""), Err(Error::Backend(_))));
+    }
+
+    #[test]
+    fn unknown_backends_are_refused() {
+        let mut encrypted = encrypt_key_with(b"secret", "passphrase", &TEST_KDF).unwrap();
+        encrypted[5] = 9;
+        assert!(matches!(
+            decrypt_key(&encrypted, "passphrase"),
+            Err(Error::UnsupportedFormat(_))
+        ));
+        assert_eq!("age".parse::<BackendId>().unwrap(), BackendId::Age);
+        assert!("rot13".parse::<BackendId>().is_err());
+    }
 }
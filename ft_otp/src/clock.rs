@@ -0,0 +1,127 @@
+//! Sources of the current Unix time, so time-based codes can be computed
+//! against something other than the system clock.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub trait Clock: Send + Sync {
+    /// Seconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct FixedClock(AtomicU64);
+
+impl FixedClock {
+    pub fn new(timestamp: u64) -> FixedClock {
+        FixedClock(AtomicU64::new(timestamp))
+    }
+
+    pub fn set(&self, timestamp: u64) {
+        self.0.store(timestamp, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+                Some(now.saturating_add(seconds))
+            });
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Another clock shifted by a number of seconds, to correct a known skew.
+#[derive(Debug, Default)]
+pub struct OffsetClock<C = SystemClock> {
+    clock: C,
+    offset: i64,
+}
+
+impl<C: Clock> OffsetClock<C> {
+    pub fn new(clock: C, offset: i64) -> OffsetClock<C> {
+        OffsetClock { clock, offset }
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+}
+
+impl<C: Clock> Clock for OffsetClock<C> {
+    fn now(&self) -> u64 {
+        self.clock.now().saturating_add_signed(self.offset)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock_moves_on_demand() {
+        let clock = FixedClock::new(59);
+        assert_eq!(clock.now(), 59);
+        clock.advance(1);
+        assert_eq!(clock.now(), 60);
+        clock.set(u64::MAX - 1);
+        clock.advance(10);
+        assert_eq!(clock.now(), u64::MAX);
+    }
+
+    #[test]
+    fn offset_clock_saturates() {
+        assert_eq!(OffsetClock::new(FixedClock::new(100), -30).now(), 70);
+        assert_eq!(OffsetClock::new(FixedClock::new(100), 30).now(), 130);
+        assert_eq!(OffsetClock::new(FixedClock::new(10), -30).now(), 0);
+        assert_eq!(
+            OffsetClock::new(FixedClock::new(u64::MAX), 1).now(),
+            u64::MAX
+        );
+
+        let system = SystemClock.now();
+        let ahead = OffsetClock::new(SystemClock, 3600).now();
+        assert!((3600..=3601).contains(&(ahead - system)));
+    }
+}
@@ -0,0 +1,69 @@
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Error decrypting: wrong passphrase or corrupted file.")]
+    Decrypt,
+
+    #[error("Error encrypting.")]
+    Encrypt,
+
+    #[error("{0}")]
+    Backend(String),
+
+    #[error("unsupported key file: {0}.")]
+    UnsupportedFormat(String),
+
+    #[error("malformed key file: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    InvalidParams(String),
+
+    #[error("invalid HMAC key.")]
+    InvalidKey,
+
+    #[error("{0}")]
+    InvalidSecret(String),
+
+    #[error("{0}")]
+    InvalidUri(String),
+
+    #[error("{0}")]
+    InvalidMigration(String),
+
+    #[error("{0}")]
+    InvalidExport(String),
+
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+
+    #[error("invalid backup share: {0}")]
+    InvalidShare(String),
+
+    #[error("{0}")]
+    TimeSource(String),
+
+    #[error("audit log {0}")]
+    Audit(String),
+
+    #[error("{0}")]
+    Login(String),
+
+    #[error("no entry named '{0}' in the vault.")]
+    NotFound(String),
+
+    #[error("an entry named '{0}' already exists.")]
+    AlreadyExists(String),
+
+    #[error("only counter-based (HOTP) keys can be resynchronized.")]
+    NotHotp,
+
+    #[error("codes not found within {0} counters, key left unchanged.")]
+    ResyncFailed(u64),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
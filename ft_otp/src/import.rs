@@ -0,0 +1,130 @@
+use std::{fs, path::Path};
+
+use ft_otp::{
+    authenticators::Format,
+    backup::{self, Backup},
+    migration, otpauth,
+    shamir::{self, Share},
+    vault::Entry,
+};
+use zeroize::Zeroizing;
+
+use crate::{passphrase, qr};
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["png", "jpg", "jpeg"].contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn is_uri(source: &str) -> bool {
+    let source = source.to_ascii_lowercase();
+    source.starts_with("otpauth://") || source.starts_with(migration::SCHEME)
+}
+
+/// A migration URI holds a whole batch of accounts, a plain one a single account.
+fn parse_payload(payload: &str) -> anyhow::Result<Vec<Entry>> {
+    if payload.to_ascii_lowercase().starts_with(migration::SCHEME) {
+        Ok(migration::decode_uri(payload)?)
+    } else {
+        Ok(vec![otpauth::parse(payload)?])
+    }
+}
+
+/// Reads accounts from an otpauth or otpauth-migration URI, a QR code
+/// screenshot or a text file holding one URI per line.
+pub fn read_source(source: &str) -> anyhow::Result<Vec<Entry>> {
+    if is_uri(source) {
+        return parse_payload(source);
+    }
+
+    let path = Path::new(source);
+    let payloads: Vec<Zeroizing<String>> = if is_image(path) {
+        qr::decode_image(path)?
+            .into_iter()
+            .map(Zeroizing::new)
+            .collect()
+    } else {
+        Zeroizing::new(fs::read_to_string(path)?)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| Zeroizing::new(line.to_string()))
+            .collect()
+    };
+
+    let mut entries = Vec::new();
+    for (i, payload) in payloads.iter().enumerate() {
+        let parsed = parse_payload(payload)
+            .map_err(|e| anyhow::anyhow!("{} (entry {}): {}", source, i + 1, e))?;
+        entries.extend(parsed);
+    }
+    Ok(entries)
+}
+
+/// Reads an Aegis, andOTP, 2FAS or Bitwarden export, asking for its password
+/// when it is encrypted. Accounts the format cannot map are reported and left out.
+fn read_export(
+    source: &str,
+    format: Format,
+    data: &[u8],
+    password: &passphrase::Source,
+) -> anyhow::Result<Vec<Entry>> {
+    let with_source = |e: ft_otp::Error| anyhow::anyhow!("{}: {}", source, e);
+    let imported = if format.is_encrypted(data).map_err(with_source)? {
+        let prompt = format!("{} export password", format);
+        let password = passphrase::read_named(password, false, &prompt)?;
+        format.read(data, Some(password.expose()))
+    } else {
+        format.read(data, None)
+    }
+    .map_err(with_source)?;
+
+    for skipped in imported.skipped {
+        eprintln!("{}: skipping {}", source, skipped);
+    }
+    Ok(imported.entries)
+}
+
+/// Reads accounts from every source. A backup, or the shares of one, and
+/// encrypted app exports are decrypted here, before the caller opens its store.
+pub fn read_sources(
+    sources: &[String],
+    backup_passphrase: &passphrase::Source,
+) -> anyhow::Result<Vec<Entry>> {
+    let open = |path: &str, data: &[u8]| -> anyhow::Result<Vec<Entry>> {
+        let passphrase = passphrase::read_named(backup_passphrase, false, "Backup passphrase")?;
+        let backup = Backup::open(data, passphrase.expose())
+            .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        Ok(backup.entries)
+    };
+
+    let mut entries = Vec::new();
+    let mut shares = Vec::new();
+    for source in sources {
+        let path = Path::new(source);
+        if is_uri(source) || !path.is_file() {
+            entries.extend(read_source(source)?);
+            continue;
+        }
+
+        let data =
+            Zeroizing::new(fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", source, e))?);
+        if backup::is_backup(&data) {
+            entries.extend(open(source, &data)?);
+        } else if shamir::is_share(&data) {
+            shares
+                .push(Share::from_bytes(&data).map_err(|e| anyhow::anyhow!("{}: {}", source, e))?);
+        } else if let Some(format) = Format::detect(path, &data) {
+            entries.extend(read_export(source, format, &data, backup_passphrase)?);
+        } else {
+            entries.extend(read_source(source)?);
+        }
+    }
+
+    if !shares.is_empty() {
+        let data = shamir::combine(&shares)?;
+        entries.extend(open("backup shares", &data)?);
+    }
+    Ok(entries)
+}
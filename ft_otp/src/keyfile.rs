@@ -0,0 +1,331 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher,
+    clock::{Clock, SystemClock},
+    error::{Error, Result},
+    recovery::{self, RecoveryCode},
+    secret::Secret,
+    storage,
+    totp::{self, TotpParams},
+};
+
+/// How far ahead of the stored counter `resync` looks for the user's codes.
+pub const RESYNC_WINDOW: u64 = 100;
+
+/// Default number of steps (TOTP) or counters (HOTP) `verify` accepts around
+/// the expected one.
+pub const VERIFY_WINDOW: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The code matched `offset` steps (or counters) away from the expected one.
+    Accepted {
+        offset: i64,
+    },
+    Rejected,
+    /// The code matched a time step that was already accepted once.
+    Replayed,
+    /// A recovery code matched and was used up.
+    Recovered {
+        remaining: usize,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtpKind {
+    #[default]
+    Totp,
+    Hotp,
+}
+
+impl OtpKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OtpKind::Totp => "totp",
+            OtpKind::Hotp => "hotp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyFile {
+    #[serde(default)]
+    pub kind: OtpKind,
+    pub secret: Secret,
+    #[serde(flatten)]
+    pub params: TotpParams,
+    #[serde(default)]
+    pub counter: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_step: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery: Vec<RecoveryCode>,
+}
+
+impl KeyFile {
+    pub fn new(secret: impl Into<Secret>, params: TotpParams) -> KeyFile {
+        KeyFile {
+            kind: OtpKind::Totp,
+            secret: secret.into(),
+            params,
+            counter: 0,
+            last_step: None,
+            recovery: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Zeroizing<Vec<u8>>> {
+        Ok(Zeroizing::new(serde_json::to_vec(self)?))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<KeyFile> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| Error::UnsupportedFormat("key file is not UTF-8".to_string()))?
+            .trim();
+
+        // Key files written before TOTP parameters existed only hold the hex key.
+        if let Ok(secret) = hex::decode(text) {
+            return Ok(KeyFile::new(secret, TotpParams::default()));
+        }
+
+        let key_file: KeyFile = serde_json::from_str(text)?;
+        key_file.params.validate()?;
+        Ok(key_file)
+    }
+
+    pub fn load(path: &Path, passphrase: &str) -> Result<KeyFile> {
+        let data = fs::read(path)?;
+        let decrypted_key = cipher::decrypt_key(&data, passphrase)?;
+        KeyFile::from_bytes(&decrypted_key)
+    }
+
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<()> {
+        let encrypted_key = cipher::encrypt_for(path, &self.to_bytes()?, passphrase)?;
+        storage::write_atomic(path, &encrypted_key)?;
+        Ok(())
+    }
+
+    fn hotp(&self, counter: u64) -> Result<String> {
+        self.params.code(self.secret.expose(), counter)
+    }
+
+    /// Returns the code for the current moving factor. HOTP keys advance their
+    /// counter, so the caller must persist the key before showing the code,
+    /// holding the store's `storage::Lock` from load to save as
+    /// `Account::next_code` does.
+    pub fn next_code(&mut self) -> Result<String> {
+        self.next_code_with(&SystemClock)
+    }
+
+    /// Like `next_code`, reading the time of TOTP keys from `clock`.
+    pub fn next_code_with(&mut self, clock: &dyn Clock) -> Result<String> {
+        let code = match self.kind {
+            OtpKind::Totp => totp::totp_with(self.secret.expose(), &self.params, clock)?,
+            OtpKind::Hotp => {
+                let code = self.hotp(self.counter)?;
+                self.counter += 1;
+                code
+            }
+        };
+        Ok(code)
+    }
+
+    /// Compares `code` against every candidate without stopping at the first
+    /// match, so the time taken does not reveal which one matched.
+    fn find_match(&self, code: &str, candidates: impl Iterator<Item = u64>) -> Result<Option<u64>> {
+        let mut matched = None;
+        for counter in candidates {
+            let expected = self.hotp(counter)?;
+            if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) && matched.is_none() {
+                matched = Some(counter);
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Replaces the recovery codes with `count` new ones, returned for the user
+    /// to write down. The caller must persist the key.
+    pub fn regenerate_recovery_codes(&mut self, count: usize) -> Vec<Zeroizing<String>> {
+        let (codes, stored) = recovery::generate(count);
+        self.recovery = stored;
+        codes
+    }
+
+    /// Checks `code` against the key at `timestamp`, or against the recovery
+    /// codes. Accepted codes update the key (last accepted step, HOTP counter
+    /// or recovery codes left), so the caller must persist it, from a load
+    /// made under the store's `storage::Lock`, or a code could be accepted
+    /// twice.
+    pub fn verify_at(&mut self, code: &str, window: u64, timestamp: u64) -> Result<Verification> {
+        if let Some(index) = recovery::find(&self.recovery, code) {
+            self.recovery.remove(index);
+            return Ok(Verification::Recovered {
+                remaining: self.recovery.len(),
+            });
+        }
+
+        let verification = match self.kind {
+            OtpKind::Totp => {
+                let current = self.params.counter_at(timestamp);
+                let steps = current.saturating_sub(window)..=current.saturating_add(window);
+                let Some(step) = self.find_match(code, steps)? else {
+                    return Ok(Verification::Rejected);
+                };
+                if self.last_step.is_some_and(|last| step <= last) {
+                    return Ok(Verification::Replayed);
+                }
+                self.last_step = Some(step);
+                Verification::Accepted {
+                    offset: step as i64 - current as i64,
+                }
+            }
+            OtpKind::Hotp => {
+                let counters = self.counter..=self.counter.saturating_add(window);
+                let Some(counter) = self.find_match(code, counters)? else {
+                    return Ok(Verification::Rejected);
+                };
+                let offset = (counter - self.counter) as i64;
+                self.counter = counter + 1;
+                Verification::Accepted { offset }
+            }
+        };
+        Ok(verification)
+    }
+
+    pub fn verify_with(
+        &mut self,
+        code: &str,
+        window: u64,
+        clock: &dyn Clock,
+    ) -> Result<Verification> {
+        self.verify_at(code, window, clock.now())
+    }
+
+    pub fn verify(&mut self, code: &str, window: u64) -> Result<Verification> {
+        self.verify_with(code, window, &SystemClock)
+    }
+
+    /// Finds two consecutive codes within `window` counters ahead of the stored
+    /// one and moves the counter past them (RFC 4226 section 7.4).
+    pub fn resync(&mut self, first: &str, second: &str, window: u64) -> Result<u64> {
+        if self.kind != OtpKind::Hotp {
+            return Err(Error::NotHotp);
+        }
+
+        for counter in self.counter..self.counter.saturating_add(window) {
+            if self.hotp(counter)? == first && self.hotp(counter + 1)? == second {
+                self.counter = counter + 2;
+                return Ok(self.counter);
+            }
+        }
+        Err(Error::ResyncFailed(window))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotp_key(counter: u64) -> KeyFile {
+        let mut key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        key.kind = OtpKind::Hotp;
+        key.counter = counter;
+        key
+    }
+
+    #[test]
+    fn hotp_codes_advance_the_counter() {
+        let mut key = hotp_key(0);
+        assert_eq!(key.next_code().unwrap(), "755224");
+        assert_eq!(key.next_code().unwrap(), "287082");
+        assert_eq!(key.counter, 2);
+    }
+
+    #[test]
+    fn resync_with_two_consecutive_codes() {
+        let mut key = hotp_key(1);
+        assert_eq!(key.resync("162583", "399871", RESYNC_WINDOW).unwrap(), 9);
+        assert_eq!(key.next_code().unwrap(), "520489");
+
+        let mut key = hotp_key(1);
+        assert!(key.resync("162583", "520489", RESYNC_WINDOW).is_err());
+        assert!(key.resync("162583", "399871", 3).is_err());
+        assert_eq!(key.counter, 1);
+    }
+
+    #[test]
+    fn totp_verify_window_and_replay() {
+        let mut key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        // RFC 6238 SHA1 codes for T=1111111109 (step 37037036) and the step after.
+        let previous = "081804";
+        let current = "050471";
+
+        assert_eq!(
+            key.verify_at(current, 1, 1111111109).unwrap(),
+            Verification::Accepted { offset: 1 }
+        );
+        assert_eq!(
+            key.verify_at(current, 1, 1111111109).unwrap(),
+            Verification::Replayed
+        );
+        assert_eq!(
+            key.verify_at(previous, 1, 1111111109).unwrap(),
+            Verification::Replayed
+        );
+        assert_eq!(
+            key.verify_at(current, 1, 1111111200).unwrap(),
+            Verification::Rejected
+        );
+        assert_eq!(
+            key.verify_at("05047", 1, 1111111109).unwrap(),
+            Verification::Rejected
+        );
+    }
+
+    #[test]
+    fn hotp_verify_moves_the_counter() {
+        let mut key = hotp_key(3);
+        assert_eq!(
+            key.verify_at("254676", 2, 0).unwrap(),
+            Verification::Accepted { offset: 2 }
+        );
+        assert_eq!(key.counter, 6);
+        assert_eq!(
+            key.verify_at("254676", 2, 0).unwrap(),
+            Verification::Rejected
+        );
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let mut key = hotp_key(0);
+        let codes = key.regenerate_recovery_codes(3);
+        assert_eq!(
+            key.verify_at(&codes[1], 1, 0).unwrap(),
+            Verification::Recovered { remaining: 2 }
+        );
+        assert_eq!(
+            key.verify_at(&codes[1], 1, 0).unwrap(),
+            Verification::Rejected
+        );
+        assert_eq!(key.counter, 0);
+
+        let saved = KeyFile::from_bytes(&key.to_bytes().unwrap()).unwrap();
+        assert_eq!(saved.recovery.len(), 2);
+        let codes = key.regenerate_recovery_codes(2);
+        assert!(recovery::find(&saved.recovery, &codes[0]).is_none());
+    }
+
+    #[test]
+    fn reads_legacy_hex_payload() {
+        let key = KeyFile::from_bytes(b"3132333435363738393031323334353637383930\n").unwrap();
+        assert_eq!(key.secret.expose(), b"12345678901234567890");
+        assert_eq!(key.kind, OtpKind::Totp);
+    }
+}
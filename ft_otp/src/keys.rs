@@ -0,0 +1,287 @@
+//! Key file commands: `-g` and `-k`, and `upgrade`, `passwd` and `encrypt`,
+//! which work on key files and vaults alike.
+
+use std::{fs, path::Path};
+use zeroize::Zeroizing;
+
+use ft_otp::{
+    Secret,
+    account::Account,
+    audit, cipher,
+    keyfile::{KeyFile, OtpKind},
+    secret::{self, KeyFormat},
+    storage,
+    vault::Entry,
+};
+
+use crate::{
+    Cli,
+    audit_log::{audit_path, audited, file_label},
+    passphrase, tui,
+};
+
+/// Applies `secret::check_length` to a key about to be stored as `name`.
+pub fn check_key_length(name: &str, len: usize, allow_short: bool) -> anyhow::Result<()> {
+    match secret::check_length(len, allow_short) {
+        Ok(Some(warning)) => eprintln!("warning: {}: {}", name, warning),
+        Ok(None) => {}
+        Err(e) => anyhow::bail!("{}: {} Pass --allow-short-key to store it anyway.", name, e),
+    }
+    Ok(())
+}
+
+pub fn read_key(file_path: &str, format: KeyFormat, allow_short: bool) -> anyhow::Result<Secret> {
+    let data =
+        Zeroizing::new(fs::read(file_path).map_err(|e| anyhow::anyhow!("{}: {}", file_path, e))?);
+    let secret =
+        secret::parse_key(&data, format).map_err(|e| anyhow::anyhow!("{}: {}", file_path, e))?;
+    check_key_length(file_path, secret.len(), allow_short)?;
+    Ok(secret)
+}
+
+/// `-g`: encrypts the key in `file_path` into `ft_otp.key`.
+pub fn generate(
+    cli: &Cli,
+    file_path: &str,
+    source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut key_file = cli.params.to_key(read_key(
+        file_path,
+        cli.key_format.unwrap_or_default(),
+        cli.allow_short_key,
+    )?)?;
+    let codes = key_file.regenerate_recovery_codes(cli.recovery.recovery_codes);
+    if cli.tui && key_file.kind == OtpKind::Hotp {
+        anyhow::bail!("the TUI only supports time-based keys.");
+    }
+
+    let filename = "ft_otp.key";
+    let passphrase = passphrase::read_for(Path::new(filename), source, true)?;
+    audited(
+        &audit_path(audit_log, Path::new(filename)),
+        &passphrase,
+        "generate",
+        &file_label(Path::new(filename)),
+        || Ok(key_file.save(Path::new(filename), passphrase.expose())?),
+    )?;
+    println!("Key was successfully saved in {}", filename);
+    print_recovery_codes(&codes);
+
+    if cli.tui {
+        let entry = Entry {
+            label: "ft_otp".to_string(),
+            issuer: Some("ft_otp".to_string()),
+            key: key_file,
+            tags: Vec::new(),
+            icon: None,
+        };
+        let clock = cli.time.clock()?;
+        let warning = cli.time.check(&clock, entry.key.params.period);
+        tui::run_tui(vec![entry], true, Box::new(clock), warning)?;
+    }
+    Ok(())
+}
+
+/// `-k`: prints the next code of the key file at `file_path`.
+pub fn code(
+    cli: &Cli,
+    file_path: &Path,
+    source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let passphrase = passphrase::read_for(file_path, source, false)?;
+    let log = audit_path(audit_log, file_path);
+    audited(&log, &passphrase, "code", &file_label(file_path), || {
+        let mut account = Account::open_file(file_path, passphrase.expose())?;
+        let clock = cli.time.clock()?;
+
+        if cli.tui {
+            if account.key().kind == OtpKind::Hotp {
+                anyhow::bail!("the TUI only supports time-based keys.");
+            }
+            let entry = Entry {
+                label: file_label(file_path),
+                issuer: None,
+                key: account.key().clone(),
+                tags: Vec::new(),
+                icon: None,
+            };
+            let warning = cli.time.check(&clock, entry.key.params.period);
+            return Ok(tui::run_tui(vec![entry], false, Box::new(clock), warning)?);
+        }
+
+        if account.key().kind == OtpKind::Totp {
+            cli.time.warn(&clock, account.key().params.period);
+        }
+        println!("{}", account.next_code_with(passphrase.expose(), &clock)?);
+        Ok(())
+    })
+}
+
+pub fn upgrade(
+    file_path: &str,
+    source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let _lock = storage::Lock::acquire(Path::new(file_path))?;
+    let data = fs::read(file_path)?;
+    if !cipher::is_legacy(&data) {
+        println!("{} is already up to date", file_path);
+        return Ok(());
+    }
+
+    let passphrase = passphrase::read(source, false)?;
+    audited(
+        &audit_path(audit_log, Path::new(file_path)),
+        &passphrase,
+        "upgrade",
+        &file_label(Path::new(file_path)),
+        || {
+            let key_file = KeyFile::load(Path::new(file_path), passphrase.expose())?;
+            key_file.save(Path::new(file_path), passphrase.expose())?;
+            Ok(())
+        },
+    )?;
+    println!("{} was successfully upgraded", file_path);
+    Ok(())
+}
+
+/// Key files and vaults share the same envelope, so either can be re-encrypted
+/// without looking at what is inside. The key of the store's audit log
+/// follows the passphrase.
+pub fn passwd(
+    file_path: &Path,
+    source: &passphrase::Source,
+    new_source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let _lock = storage::Lock::acquire(file_path)?;
+    let data = fs::read(file_path)?;
+    let backend = cipher::backend_id(&data)?;
+    if !backend.takes_passphrase() {
+        anyhow::bail!(
+            "{} is encrypted with {}, which has no passphrase (see `ft_otp encrypt`).",
+            file_path.display(),
+            backend
+        );
+    }
+    let passphrase = passphrase::read(source, false)?;
+    let audit_log = audit_path(audit_log, file_path);
+    let plaintext = audited(
+        &audit_log,
+        &passphrase,
+        "passwd",
+        &file_label(file_path),
+        || Ok(cipher::decrypt_key(&data, passphrase.expose())?),
+    )?;
+
+    eprintln!("New passphrase for {}", file_path.display());
+    let new_passphrase = passphrase::read(new_source, true)?;
+    let encrypted = cipher::encrypt_like(&data, &plaintext, new_passphrase.expose())?;
+    storage::write_atomic(file_path, &encrypted)?;
+    audit::rekey(&audit_log, passphrase.expose(), new_passphrase.expose())?;
+    println!("{} was successfully re-encrypted", file_path.display());
+    Ok(())
+}
+
+/// Moves a key file or vault to another backend. The new file is opened
+/// before it replaces the old one, so a wrong age recipient or a keyring
+/// that cannot be written to never locks the account out.
+pub fn encrypt(
+    file_path: &Path,
+    backend: cipher::BackendId,
+    mut recipients: Vec<age::x25519::Recipient>,
+    keyring_name: Option<String>,
+    source: &passphrase::Source,
+    audit_log: Option<&Path>,
+) -> anyhow::Result<()> {
+    let _lock = storage::Lock::acquire(file_path)?;
+    let data = fs::read(file_path)?;
+    let passphrase = passphrase::read_for(file_path, source, false)?;
+    let audit_log = audit_path(audit_log, file_path);
+    let plaintext = audited(
+        &audit_log,
+        &passphrase,
+        "encrypt",
+        &file_label(file_path),
+        || Ok(cipher::decrypt_key(&data, passphrase.expose())?),
+    )?;
+
+    let kdf = cipher::KdfParams::default();
+    let new_passphrase = match backend.takes_passphrase() {
+        true if !cipher::backend_id(&data)?.takes_passphrase() => {
+            eprintln!("New passphrase for {}", file_path.display());
+            Some(passphrase::read(source, true)?)
+        }
+        _ => None,
+    };
+    let sealing = new_passphrase.as_ref().unwrap_or(&passphrase).expose();
+    let encrypted = match backend {
+        cipher::BackendId::AesGcm => {
+            cipher::encrypt_with(&plaintext, &cipher::aes_gcm(sealing, kdf))?
+        }
+        cipher::BackendId::XChaCha20Poly1305 => {
+            cipher::encrypt_with(&plaintext, &cipher::xchacha20_poly1305(sealing, kdf))?
+        }
+        cipher::BackendId::Age => {
+            if std::env::var_os(cipher::AGE_IDENTITY_ENV).is_some() {
+                for identity in cipher::Age::identities_from_env()? {
+                    let recipient = identity.to_public();
+                    if !recipients.contains(&recipient) {
+                        recipients.push(recipient);
+                    }
+                }
+            }
+            if recipients.is_empty() {
+                anyhow::bail!(
+                    "age needs --recipient or an identity file in ${}.",
+                    cipher::AGE_IDENTITY_ENV
+                );
+            }
+            let age = cipher::Age {
+                recipients,
+                identities: Vec::new(),
+            };
+            cipher::encrypt_with(&plaintext, &age)?
+        }
+        cipher::BackendId::Keyring => {
+            let name = match keyring_name {
+                Some(name) => name,
+                None => fs::canonicalize(file_path)?.to_string_lossy().into_owned(),
+            };
+            cipher::encrypt_with(&plaintext, &cipher::Keyring { name })?
+        }
+    };
+    if let Err(e) = cipher::decrypt_key(&encrypted, sealing) {
+        anyhow::bail!(
+            "the re-encrypted file could not be opened, {} left unchanged: {}",
+            file_path.display(),
+            e
+        );
+    }
+    storage::write_atomic(file_path, &encrypted)?;
+
+    // The audit log key follows the passphrase, or what stands in for it.
+    let unattended = cipher::unattended_passphrase(&encrypted)?;
+    let new_passphrase = unattended.as_ref().or(new_passphrase.as_ref());
+    if let Some(new_passphrase) = new_passphrase
+        && new_passphrase.expose() != passphrase.expose()
+    {
+        audit::rekey(&audit_log, passphrase.expose(), new_passphrase.expose())?;
+    }
+    println!("{} is now encrypted with {}", file_path.display(), backend);
+    if backend == cipher::BackendId::Keyring {
+        eprintln!(
+            "warning: the kernel keyring is emptied on reboot, keep a backup of this account."
+        );
+    }
+    Ok(())
+}
+
+pub fn print_recovery_codes(codes: &[Zeroizing<String>]) {
+    println!("Recovery codes, each works once in place of a code:");
+    for code in codes {
+        println!("  {}", code.as_str());
+    }
+}
@@ -0,0 +1,32 @@
+//! One-time password library behind the `ft_otp` command: HOTP/TOTP
+//! generation, encrypted key files, vaults and backups, and otpauth / Google
+//! Authenticator migration URIs.
+
+pub mod account;
+pub mod audit;
+pub mod authenticators;
+pub mod backup;
+pub mod cipher;
+pub mod clock;
+pub mod error;
+pub mod keyfile;
+pub mod login;
+pub mod memory;
+pub mod migration;
+pub mod ocra;
+pub mod otpauth;
+pub mod recovery;
+pub mod secret;
+pub mod shamir;
+pub mod skew;
+pub mod storage;
+pub mod totp;
+pub mod vault;
+
+pub use account::Account;
+pub use clock::{Clock, FixedClock, OffsetClock, SystemClock};
+pub use error::{Error, Result};
+pub use keyfile::{KeyFile, OtpKind, Verification};
+pub use secret::{Passphrase, Secret};
+pub use totp::{Algorithm, Hotp, Totp, TotpParams};
+pub use vault::{Entry, Vault};
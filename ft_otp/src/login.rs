@@ -0,0 +1,534 @@
+//! Per-user key files checked at login by the PAM module (`pam/`), and
+//! written by `ft_otp pam enroll` or through `ft_otp serve`.
+//!
+//! Every key file is encrypted under one system passphrase, read from a file
+//! only root can read, since nobody is there to type it at login.
+//!
+//! The module runs as root on paths users may control (`%h`), so key files
+//! and every directory above them must be owned by root, this process or the
+//! user, and not be writable by anyone else. Locks live in a directory of
+//! their own, `/run/ft_otp` when running as root.
+
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fs,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher,
+    clock::Clock,
+    error::{Error, Result},
+    keyfile::{KeyFile, VERIFY_WINDOW, Verification},
+    secret::Passphrase,
+    storage,
+};
+
+/// `%u` is replaced by the user name, `%h` by their home directory.
+pub const DEFAULT_KEY_PATH: &str = "/etc/ft_otp/users/%u.key";
+pub const DEFAULT_PASSPHRASE_FILE: &str = "/etc/ft_otp/pam.passphrase";
+
+fn invalid(message: String) -> Error {
+    Error::Login(message)
+}
+
+/// Module arguments, as written after the module path in `/etc/pam.d`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub key_path: String,
+    pub passphrase_file: PathBuf,
+    pub window: u64,
+    /// Let users without a key file through, for a gradual rollout.
+    pub nullok: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            key_path: DEFAULT_KEY_PATH.to_string(),
+            passphrase_file: PathBuf::from(DEFAULT_PASSPHRASE_FILE),
+            window: VERIFY_WINDOW,
+            nullok: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parses `file=TEMPLATE`, `passphrase_file=PATH`, `window=N` and `nullok`.
+    pub fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Result<Config> {
+        let mut config = Config::default();
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("file", template)) => config.key_path = template.to_string(),
+                Some(("passphrase_file", path)) => config.passphrase_file = PathBuf::from(path),
+                Some(("window", window)) => {
+                    config.window = window.parse().map_err(|_| {
+                        invalid(format!("window must be a number, got '{}'.", window))
+                    })?
+                }
+                None if arg == "nullok" => config.nullok = true,
+                _ => return Err(invalid(format!("unknown module argument '{}'.", arg))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Looks `user` up in the password database, handing the entry to `read`
+/// while its strings are still alive.
+fn with_passwd<T>(user: &str, read: impl FnOnce(&libc::passwd) -> Option<T>) -> Option<T> {
+    let name = CString::new(user).ok()?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let status = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    read(&entry)
+}
+
+/// Home directory of `user` from the password database.
+pub fn home_dir(user: &str) -> Option<PathBuf> {
+    with_passwd(user, |entry| {
+        if entry.pw_dir.is_null() {
+            return None;
+        }
+        let dir = unsafe { CStr::from_ptr(entry.pw_dir) };
+        Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+    })
+}
+
+/// Owners a key file and its directories may have: root, this process, and
+/// `user`, who may own their own key file (under `%h`, say). A user missing
+/// from the password database adds no one.
+fn trusted_owners(user: &str) -> [u32; 3] {
+    let euid = unsafe { libc::geteuid() };
+    let uid = with_passwd(user, |entry| Some(entry.pw_uid)).unwrap_or(0);
+    [0, euid, uid]
+}
+
+/// Refuses `path` unless it is owned by one of `owners` and not writable by
+/// group or others. Root's sticky directories such as `/tmp` are fine: nobody
+/// else can rename or remove what is in them.
+fn check_owner(path: &Path, metadata: &fs::Metadata, owners: &[u32]) -> Result<()> {
+    let mode = metadata.mode();
+    let sticky_root_dir = metadata.is_dir() && metadata.uid() == 0 && mode & 0o1000 != 0;
+    if !owners.contains(&metadata.uid()) {
+        return Err(invalid(format!(
+            "{} is owned by uid {}, refusing it.",
+            path.display(),
+            metadata.uid()
+        )));
+    }
+    if mode & 0o022 != 0 && !sticky_root_dir {
+        return Err(invalid(format!(
+            "{} is writable by others (mode {:o}), refusing it.",
+            path.display(),
+            mode & 0o7777
+        )));
+    }
+    Ok(())
+}
+
+/// Checks every directory above `path`, up to `/`.
+fn check_directories(path: &Path, owners: &[u32]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = fs::canonicalize(dir)?;
+    for ancestor in dir.ancestors() {
+        check_owner(ancestor, &fs::metadata(ancestor)?, owners)?;
+    }
+    Ok(())
+}
+
+/// Checks the key file at `path` and its directories. Returns `false` when
+/// there is no key file.
+fn check_key_file(path: &Path, owners: &[u32]) -> Result<bool> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_file() {
+        return Err(invalid(format!(
+            "{} is not a regular file, refusing it.",
+            path.display()
+        )));
+    }
+    check_owner(path, &metadata, owners)?;
+    check_directories(path, owners)?;
+    Ok(true)
+}
+
+/// Whether there is anything at `path`, for `nullok`. Only a path that is
+/// certainly absent counts as no key: a dangling link, or a directory that
+/// cannot be searched, is an error so the login fails closed.
+pub fn key_file_exists(path: &Path) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Expands `%u`, `%h` and `%%` in a key path template. `home` is only asked
+/// for when the template uses it.
+pub fn key_path(
+    template: &str,
+    user: &str,
+    home: impl FnOnce() -> Option<PathBuf>,
+) -> Result<PathBuf> {
+    if user.is_empty() || user.contains('/') || user.starts_with('.') {
+        return Err(invalid(format!("refusing user name '{}'.", user)));
+    }
+    let mut home = Some(home);
+    let mut path = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => path.push_str(user),
+            Some('h') => {
+                let dir = home
+                    .take()
+                    .and_then(|home| home())
+                    .ok_or_else(|| invalid(format!("no home directory for '{}'.", user)))?;
+                path.push_str(&dir.to_string_lossy());
+            }
+            Some('%') => path.push('%'),
+            _ => return Err(invalid(format!("bad key path template '{}'.", template))),
+        }
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Reads the first line of the system passphrase file, which must not be
+/// readable by anyone but its owner.
+pub fn read_passphrase(path: &Path) -> Result<Passphrase> {
+    let metadata = fs::metadata(path)?;
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != 0 && metadata.uid() != euid {
+        return Err(invalid(format!(
+            "{} is owned by uid {}, refusing it.",
+            path.display(),
+            metadata.uid()
+        )));
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(invalid(format!(
+            "{} must only be readable by its owner (mode {:o}).",
+            path.display(),
+            mode & 0o777
+        )));
+    }
+    let data = Zeroizing::new(fs::read(path)?);
+    let line = data.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let passphrase = std::str::from_utf8(line)
+        .map_err(|_| invalid(format!("{} is not UTF-8.", path.display())))?;
+    if passphrase.is_empty() {
+        return Err(invalid(format!("{} is empty.", path.display())));
+    }
+    Ok(Passphrase::new(passphrase.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    /// A recovery code was used up, `remaining` are left.
+    Recovered {
+        remaining: usize,
+    },
+    Rejected,
+    Replayed,
+    /// The user has no key file.
+    NotEnrolled,
+}
+
+/// Writes `key` over `path` with mode 0600, through a fresh temporary file.
+fn save(path: &Path, passphrase: &str, key: &KeyFile) -> Result<()> {
+    let encrypted = cipher::encrypt_for(path, &key.to_bytes()?, passphrase)?;
+    storage::write_private(path, &encrypted)?;
+    Ok(())
+}
+
+/// Checks `code` against the key of `user` at `path`. An accepted code is
+/// written back (last step or HOTP counter) before returning, which is what
+/// refuses it the second time.
+pub fn verify(
+    path: &Path,
+    user: &str,
+    passphrase: &str,
+    code: &str,
+    window: u64,
+    clock: &dyn Clock,
+) -> Result<Outcome> {
+    if !check_key_file(path, &trusted_owners(user))? {
+        return Ok(Outcome::NotEnrolled);
+    }
+
+    let _lock = storage::Lock::acquire(path)?;
+    let mut key = KeyFile::load(path, passphrase)?;
+    let outcome = match key.verify_with(code.trim(), window, clock)? {
+        Verification::Accepted { .. } => Outcome::Accepted,
+        Verification::Recovered { remaining } => Outcome::Recovered { remaining },
+        Verification::Rejected => return Ok(Outcome::Rejected),
+        Verification::Replayed => return Ok(Outcome::Replayed),
+    };
+    save(path, passphrase, &key)?;
+    Ok(outcome)
+}
+
+/// Writes the key of `user`, refusing to replace one unless `force` is set.
+pub fn enroll(path: &Path, user: &str, passphrase: &str, key: &KeyFile, force: bool) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let _lock = storage::Lock::acquire(path)?;
+    let owners = trusted_owners(user);
+    if check_key_file(path, &owners)? && !force {
+        return Err(invalid(format!(
+            "{} already holds a key, use --force to replace it.",
+            path.display()
+        )));
+    }
+    check_directories(path, &owners)?;
+    save(path, passphrase, key)
+}
+
+/// Replaces the recovery codes of `user` with `count` new ones. Returns
+/// `None` when the user has no key.
+pub fn regenerate_recovery_codes(
+    path: &Path,
+    user: &str,
+    passphrase: &str,
+    count: usize,
+) -> Result<Option<Vec<Zeroizing<String>>>> {
+    if !check_key_file(path, &trusted_owners(user))? {
+        return Ok(None);
+    }
+    let _lock = storage::Lock::acquire(path)?;
+    let mut key = KeyFile::load(path, passphrase)?;
+    let codes = key.regenerate_recovery_codes(count);
+    save(path, passphrase, &key)?;
+    Ok(Some(codes))
+}
+
+/// Deletes the key of `user`. Returns `false` when there was none.
+pub fn revoke(path: &Path, user: &str) -> Result<bool> {
+    // Unlinking never writes through the file, so only where it is matters.
+    match fs::symlink_metadata(path) {
+        Ok(_) => check_directories(path, &trusted_owners(user))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    let _lock = storage::Lock::acquire(path)?;
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::FixedClock,
+        totp::{self, TotpParams},
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ft_otp-login-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_module_arguments() {
+        let config = Config::parse(["file=%h/.ft_otp.key", "window=3", "nullok"]).unwrap();
+        assert_eq!(config.key_path, "%h/.ft_otp.key");
+        assert_eq!(config.window, 3);
+        assert!(config.nullok);
+        assert_eq!(config.passphrase_file, Path::new(DEFAULT_PASSPHRASE_FILE));
+        assert_eq!(Config::parse([]).unwrap(), Config::default());
+        assert!(Config::parse(["window=soon"]).is_err());
+        assert!(Config::parse(["debug"]).is_err());
+    }
+
+    #[test]
+    fn looks_up_home_directories() {
+        assert_eq!(home_dir("root"), Some(PathBuf::from("/root")));
+        assert_eq!(home_dir("no such user"), None);
+    }
+
+    #[test]
+    fn expands_key_paths() {
+        let home = || Some(PathBuf::from("/home/alice"));
+        assert_eq!(
+            key_path("%h/.keys/%u.key", "alice", home).unwrap(),
+            Path::new("/home/alice/.keys/alice.key")
+        );
+        assert_eq!(
+            key_path("/etc/%u-100%%.key", "bob", || None).unwrap(),
+            Path::new("/etc/bob-100%.key")
+        );
+        assert!(key_path("%h/key", "bob", || None).is_err());
+        assert!(key_path("/etc/%x", "bob", || None).is_err());
+        assert!(key_path("/etc/%u.key", "../root", || None).is_err());
+        assert!(key_path("/etc/%u.key", "", || None).is_err());
+    }
+
+    #[test]
+    fn passphrase_file_must_be_private() {
+        let dir = temp_dir("passphrase");
+        let path = dir.join("pam.passphrase");
+        fs::write(&path, "system secret\nignored\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read_passphrase(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(read_passphrase(&path).unwrap().expose(), "system secret");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verifies_with_drift_and_refuses_replays() {
+        let dir = temp_dir("verify");
+        let path = dir.join("users/alice.key");
+        assert_eq!(
+            verify(&path, "alice", "pass", "123456", 1, &FixedClock::new(0)).unwrap(),
+            Outcome::NotEnrolled
+        );
+
+        let key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        enroll(&path, "alice", "pass", &key, false).unwrap();
+        assert!(enroll(&path, "alice", "pass", &key, false).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+
+        let clock = FixedClock::new(1_000_000);
+        let previous = totp::totp_at(key.secret.expose(), &key.params, 1_000_000 - 30).unwrap();
+        assert_eq!(
+            verify(&path, "alice", "pass", &previous, 1, &clock).unwrap(),
+            Outcome::Accepted
+        );
+        assert_eq!(
+            verify(&path, "alice", "pass", &previous, 1, &clock).unwrap(),
+            Outcome::Replayed
+        );
+        let stale = totp::totp_at(key.secret.expose(), &key.params, 1_000_000 - 90).unwrap();
+        assert_eq!(
+            verify(&path, "alice", "pass", &stale, 1, &clock).unwrap(),
+            Outcome::Rejected
+        );
+        assert!(verify(&path, "alice", "wrong", &previous, 1, &clock).is_err());
+
+        let codes = regenerate_recovery_codes(&path, "alice", "pass", 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            verify(&path, "alice", "pass", &codes[0], 1, &clock).unwrap(),
+            Outcome::Recovered { remaining: 1 }
+        );
+        assert_eq!(
+            verify(&path, "alice", "pass", &codes[0], 1, &clock).unwrap(),
+            Outcome::Rejected
+        );
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert!(
+            regenerate_recovery_codes(&dir.join("bob.key"), "bob", "pass", 2)
+                .unwrap()
+                .is_none()
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(verify(&path, "alice", "pass", &previous, 1, &clock).is_err());
+
+        assert!(revoke(&path, "alice").unwrap());
+        assert!(!revoke(&path, "alice").unwrap());
+        assert!(!revoke(&dir.join("nowhere/bob.key"), "bob").unwrap());
+        assert_eq!(
+            verify(&path, "alice", "pass", &previous, 1, &clock).unwrap(),
+            Outcome::NotEnrolled
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_others_control() {
+        let dir = temp_dir("owners");
+        let key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        let clock = FixedClock::new(1_000_000);
+        let code = totp::totp_at(key.secret.expose(), &key.params, 1_000_000).unwrap();
+
+        // A key path pointing elsewhere is neither followed nor written through.
+        let target = dir.join("shadow");
+        fs::write(&target, "root:x:0:\n").unwrap();
+        let link = dir.join("mallory.key");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(verify(&link, "mallory", "pass", &code, 1, &clock).is_err());
+        assert!(enroll(&link, "mallory", "pass", &key, true).is_err());
+        assert!(regenerate_recovery_codes(&link, "mallory", "pass", 2).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "root:x:0:\n");
+
+        // Nothing but the key is written next to it.
+        let path = dir.join("users/alice.key");
+        enroll(&path, "alice", "pass", &key, false).unwrap();
+        assert_eq!(
+            verify(&path, "alice", "pass", &code, 1, &clock).unwrap(),
+            Outcome::Accepted
+        );
+        let names: Vec<_> = fs::read_dir(dir.join("users")).unwrap().collect();
+        assert_eq!(names.len(), 1);
+
+        let users = dir.join("users");
+        fs::set_permissions(&users, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(verify(&path, "alice", "pass", &code, 1, &clock).is_err());
+        assert!(enroll(&dir.join("users/bob.key"), "bob", "pass", &key, false).is_err());
+        fs::set_permissions(&users, fs::Permissions::from_mode(0o755)).unwrap();
+
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chown(&path, Some(12345), None).unwrap();
+            assert!(verify(&path, "alice", "pass", &code, 1, &clock).is_err());
+            std::os::unix::fs::chown(&path, Some(0), None).unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_absent_key_files_count_as_missing() {
+        let dir = temp_dir("exists");
+        assert!(!key_file_exists(&dir.join("alice.key")).unwrap());
+
+        let link = dir.join("bob.key");
+        std::os::unix::fs::symlink(dir.join("nowhere"), &link).unwrap();
+        assert!(key_file_exists(&link).unwrap());
+
+        let file = dir.join("carol");
+        fs::write(&file, "").unwrap();
+        assert!(key_file_exists(&file.join("carol.key")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,89 +1,1084 @@
-mod cipher;
-mod totp;
+mod accounts;
+mod agent;
+mod audit_log;
+mod import;
+mod keys;
+mod pam;
+mod passphrase;
+mod qr;
+mod serve;
+mod time;
 mod tui;
 
-use clap::Parser;
-use std::{env, fs};
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+use ft_otp::{
+    Clock, OffsetClock, Secret, SystemClock,
+    account::Account,
+    backup::Conflict,
+    cipher,
+    keyfile::{KeyFile, OtpKind, RESYNC_WINDOW, VERIFY_WINDOW},
+    login, memory,
+    ocra::Suite,
+    recovery,
+    secret::KeyFormat,
+    skew::{self, Reference},
+    totp::{Algorithm, Encoding, MAX_DIGITS, MIN_CHARS, TotpParams},
+    vault,
+};
 
 #[derive(Parser)]
-#[command(name = "ft_otp")]
+#[command(name = "ft_otp", args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short = 'g',
         value_name = "FILE",
         conflicts_with = "key",
-        help = "Generate an encrypted key file from a 64 hexadecimal key"
+        help = "Generate an encrypted key file from a hexadecimal, base32 or binary key"
     )]
     generate: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        requires = "generate",
+        help = "Encoding of the -g key: auto (default), hex, base32 or raw"
+    )]
+    key_format: Option<KeyFormat>,
+
     #[arg(
         short = 'k',
         value_name = "FILE",
-        conflicts_with = "generate",
-        help = "Use an encrypted key file to generate a TOTP code"
+        conflicts_with_all = ["generate", "ParamsArgs", "RecoveryArgs"],
+        help = "Use an encrypted key file to generate a TOTP (or HOTP) code"
     )]
-    key: Option<String>,
+    key: Option<PathBuf>,
 
     #[arg(
         short = 't',
-        requires = "generate",
         default_value_t = false,
-        help = "Display a TUI with QR code, current OTP, and countdown"
+        help = "Display a TUI with the QR code, current OTP and countdown (with -g or -k)"
     )]
     tui: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "generate",
+        help = "Store keys shorter than the 128 bits RFC 4226 requires, such as 80-bit base32 secrets"
+    )]
+    allow_short_key: bool,
+
+    #[command(flatten)]
+    params: ParamsArgs,
+
+    #[command(flatten)]
+    recovery: RecoveryArgs,
+
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+
+    #[command(flatten)]
+    time: TimeArgs,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Tamper-evident log of key operations, verifications and vault edits [default: the key file or vault path followed by .audit]"
+    )]
+    audit_log: Option<PathBuf>,
 }
 
-fn main() -> anyhow::Result<()> {
-    dotenv::dotenv().ok();
-    let cli = Cli::parse();
+#[derive(Args)]
+#[group(multiple = false)]
+struct PassphraseArgs {
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Read the passphrase from the first line of FILE"
+    )]
+    passphrase_file: Option<PathBuf>,
 
-    if cli.generate.is_none() && cli.key.is_none() {
-        anyhow::bail!("Error: You must at least specify either -g or -k");
+    #[arg(
+        long,
+        global = true,
+        value_name = "FD",
+        help = "Read the passphrase from an open file descriptor"
+    )]
+    passphrase_fd: Option<i32>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "COMMAND",
+        help = "Read the passphrase from the output of a shell command"
+    )]
+    passphrase_cmd: Option<String>,
+}
+
+impl PassphraseArgs {
+    fn source(&self) -> passphrase::Source {
+        if let Some(path) = &self.passphrase_file {
+            passphrase::Source::File(path.clone())
+        } else if let Some(fd) = self.passphrase_fd {
+            passphrase::Source::Fd(fd)
+        } else if let Some(cmd) = &self.passphrase_cmd {
+            passphrase::Source::Cmd(cmd.clone())
+        } else {
+            passphrase::Source::Default
+        }
+    }
+}
+
+#[derive(Args)]
+struct TimeArgs {
+    #[arg(
+        long,
+        global = true,
+        value_name = "SERVER",
+        num_args = 0..=1,
+        default_missing_value = skew::DEFAULT_SERVER,
+        conflicts_with = "reference_time",
+        help = "Check the local clock against an NTP server [default server: pool.ntp.org]"
+    )]
+    ntp: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "TIMESTAMP",
+        help = "Check the local clock against this Unix time, read off another device"
+    )]
+    reference_time: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        default_value = skew::DEFAULT_OFFSET_FILE,
+        help = "Clock offset applied to time-based codes, written by `time --save`"
+    )]
+    offset_file: PathBuf,
+}
+
+impl TimeArgs {
+    fn reference(&self) -> Option<Reference> {
+        match (&self.ntp, self.reference_time) {
+            (Some(server), _) => Some(Reference::Sntp(server.clone())),
+            (None, Some(time)) => Some(Reference::Time(time)),
+            (None, None) => None,
+        }
+    }
+
+    /// The system clock corrected by the stored offset, if any.
+    fn clock(&self) -> anyhow::Result<OffsetClock> {
+        let offset = skew::load_offset(&self.offset_file)?.unwrap_or(0);
+        Ok(OffsetClock::new(SystemClock, offset))
     }
 
-    if let Some(file_path) = cli.generate {
-        let data = fs::read(&file_path)?;
-        let raw_key = String::from_utf8(data.clone())?;
-        let key = raw_key.trim();
+    /// A warning when a reference was given and `clock` is off by enough to
+    /// break codes of `period` seconds. A failed check only warns as well.
+    fn check(&self, clock: &dyn Clock, period: u64) -> Option<String> {
+        let reference = self.reference()?;
+        match skew::measure(&reference, clock) {
+            Ok(offset) if skew::is_significant(offset, period) => Some(format!(
+                "warning: the clock is {}, codes may be rejected (see `ft_otp time --save`)",
+                skew::describe(offset)
+            )),
+            Ok(_) => None,
+            Err(e) => Some(format!("warning: cannot check the clock: {}", e)),
+        }
+    }
 
-        if key.len() < 64 || hex::decode(&key).is_err() {
-            anyhow::bail!("key must be 64 hexadecimal characters.");
+    fn warn(&self, clock: &dyn Clock, period: u64) {
+        if let Some(warning) = self.check(clock, period) {
+            eprintln!("{}", warning);
         }
+    }
+}
 
-        let passphrase = env::var("PASSPHRASE")?;
-        let encrypted_key = cipher::encrypt_key(&data, passphrase.as_str())
-            .map_err(|_| anyhow::anyhow!("Error encrypting key."))?;
+#[derive(Args)]
+struct ParamsArgs {
+    #[arg(
+        long,
+        value_name = "ALGORITHM",
+        help = "HMAC algorithm stored with the key: SHA1 (default), SHA256 or SHA512"
+    )]
+    algorithm: Option<Algorithm>,
 
-        let filename = "ft_otp.key";
-        fs::write(filename, &encrypted_key)?;
-        println!("Key was successfully saved in {}", filename);
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(MIN_CHARS as i64..=MAX_DIGITS as i64),
+        help = "Number of digits (or characters) of the generated codes [default: 6, 5 for steam]"
+    )]
+    digits: Option<u32>,
 
-        let hex_key = hex::decode(&key).unwrap();
-        let base32_key = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &hex_key);
-        let otp_uri = format!("otpauth://totp/ft_otp?secret={}&issuer=ft_otp", base32_key);
+    #[arg(
+        long,
+        value_name = "ENCODING",
+        help = "Code encoding: decimal (default), steam or alphabet:CHARS"
+    )]
+    encoding: Option<Encoding>,
 
-        if cli.tui {
-            let code = qrcode::QrCode::new(otp_uri.as_bytes())?;
-            let qr_string = code.render::<qrcode::render::unicode::Dense1x2>().build();
-            tui::run_tui(&hex_key, &qr_string)?;
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Time step between two codes [default: 30]"
+    )]
+    period: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        help = "Unix time at which the first time step starts [default: 0]"
+    )]
+    t0: Option<u64>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["period", "t0"],
+        help = "Counter-based (HOTP) key instead of a time-based one"
+    )]
+    hotp: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "hotp",
+        help = "Initial HOTP counter [default: 0]"
+    )]
+    counter: Option<u64>,
+}
+
+impl ParamsArgs {
+    fn to_params(&self) -> anyhow::Result<TotpParams> {
+        let defaults = TotpParams::default();
+        let encoding = self.encoding.clone().unwrap_or_default();
+        let params = TotpParams {
+            algorithm: self.algorithm.unwrap_or(defaults.algorithm),
+            digits: self.digits.unwrap_or(encoding.default_digits()),
+            period: self.period.unwrap_or(defaults.period),
+            t0: self.t0.unwrap_or(defaults.t0),
+            encoding,
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    fn to_key(&self, secret: Secret) -> anyhow::Result<KeyFile> {
+        let mut key = KeyFile::new(secret, self.to_params()?);
+        if self.hotp {
+            key.kind = OtpKind::Hotp;
+            key.counter = self.counter.unwrap_or(0);
         }
+        Ok(key)
     }
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = serve::DEFAULT_SOCKET,
+        help = "Unix socket to listen on, writable by its owner and group"
+    )]
+    socket: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "token_file",
+        help = "Listen on a loopback address and port instead (needs --token-file)"
+    )]
+    listen: Option<std::net::SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Require `Authorization: Bearer TOKEN`, TOKEN being the first line of FILE (mode 0600)"
+    )]
+    token_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = login::DEFAULT_KEY_PATH,
+        help = "Key file path, as given to the PAM module with file="
+    )]
+    file: String,
 
-    if let Some(file_path) = cli.key {
-        let data = fs::read(file_path)?;
-        let passphrase = env::var("PASSPHRASE")?;
-        let decrypted_key = cipher::decrypt_key(&data, passphrase.as_str())
-            .map_err(|_| anyhow::anyhow!("Error decrypting key."))?;
-        let raw_key = String::from_utf8(decrypted_key)?;
-        let key = raw_key.trim();
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = login::DEFAULT_PASSPHRASE_FILE,
+        help = "System passphrase file, as given to the PAM module with passphrase_file="
+    )]
+    system_passphrase_file: PathBuf,
 
-        if key.len() < 64 || hex::decode(&key).is_err() {
-            anyhow::bail!("key must be 64 hexadecimal characters.");
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = VERIFY_WINDOW,
+        help = "Accepted drift: ±N time steps"
+    )]
+    window: u64,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Verification attempts allowed per user and minute"
+    )]
+    attempts_per_minute: u32,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Failed verifications in a row before a user is locked out"
+    )]
+    max_failures: u32,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 300,
+        help = "How long a lockout lasts"
+    )]
+    lockout: u64,
+
+    #[command(flatten)]
+    recovery: RecoveryArgs,
+
+    #[command(flatten)]
+    params: ParamsArgs,
+}
+
+#[derive(Args)]
+struct AccountArgs {
+    #[arg(
+        short = 'k',
+        value_name = "FILE",
+        conflicts_with = "label",
+        required_unless_present = "label",
+        help = "Encrypted key file holding the account"
+    )]
+    key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "LABEL",
+        help = "Vault account to use instead of a key file"
+    )]
+    label: Option<String>,
+
+    #[command(flatten)]
+    vault: VaultArgs,
+}
+
+impl AccountArgs {
+    fn open(&self, passphrase: &str) -> anyhow::Result<Account> {
+        match (&self.key, &self.label) {
+            (Some(path), _) => Ok(Account::open_file(path, passphrase)?),
+            (None, Some(label)) => Ok(Account::open_vault(&self.vault.vault, label, passphrase)?),
+            (None, None) => anyhow::bail!("either -k or --label is required."),
         }
+    }
+
+    /// The key file or vault the account is read from.
+    fn store(&self) -> &Path {
+        self.key.as_deref().unwrap_or(&self.vault.vault)
+    }
+
+    /// How the account is named in the audit log.
+    fn name(&self) -> String {
+        match (&self.key, &self.label) {
+            (Some(path), _) => audit_log::file_label(path),
+            (None, Some(label)) => label.clone(),
+            (None, None) => String::new(),
+        }
+    }
+}
 
-        let otp = totp::totp(&hex::decode(&key)?);
-        println!("{:06}", otp);
+#[derive(Args)]
+struct VaultArgs {
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = vault::DEFAULT_VAULT,
+        help = "Encrypted vault holding the accounts"
+    )]
+    vault: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Re-encrypt a legacy key file with the current format")]
+    Upgrade {
+        #[arg(value_name = "FILE")]
+        file: String,
+    },
+
+    #[command(about = "Re-encrypt a key file or vault under a new passphrase")]
+    Passwd {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read the new passphrase from the first line of FILE instead of prompting"
+        )]
+        new_passphrase_file: Option<PathBuf>,
+    },
+
+    #[command(about = "Re-encrypt a key file or vault with another backend")]
+    Encrypt {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(
+            long,
+            value_name = "BACKEND",
+            help = "aes-gcm, xchacha20-poly1305, age (to --recipient) or keyring (no passphrase)"
+        )]
+        backend: cipher::BackendId,
+
+        #[arg(
+            long = "recipient",
+            value_name = "AGE1...",
+            help = "age recipient, repeatable; the identities in $FT_OTP_AGE_IDENTITY are added"
+        )]
+        recipients: Vec<age::x25519::Recipient>,
+
+        #[arg(
+            long,
+            value_name = "NAME",
+            help = "Kernel keyring entry ft_otp:NAME holding the key [default: the file's full path]"
+        )]
+        keyring_name: Option<String>,
+    },
+
+    #[command(about = "Hold the unlocked key store in memory and serve codes over a socket")]
+    Agent {
+        #[arg(
+            long,
+            global = true,
+            value_name = "PATH",
+            help = "Agent socket [default: $FT_OTP_AGENT_SOCK or $XDG_RUNTIME_DIR/ft_otp/agent.sock]"
+        )]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: AgentAction,
+    },
+
+    #[command(about = "Measure the clock offset against NTP or --reference-time")]
+    Time {
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "clear",
+            help = "Store the measured offset and apply it to time-based codes"
+        )]
+        save: bool,
+
+        #[arg(long, default_value_t = false, help = "Forget the stored offset")]
+        clear: bool,
+
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 30,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Time step the offset is judged against"
+        )]
+        period: u64,
+    },
+
+    #[command(about = "Manage the per-user keys checked by the PAM module")]
+    Pam {
+        #[command(subcommand)]
+        action: PamAction,
+    },
+
+    #[command(about = "Enroll, verify and revoke per-user keys over HTTP/JSON")]
+    Serve {
+        #[command(flatten)]
+        args: ServeArgs,
+    },
+
+    #[command(about = "Inspect the audit log")]
+    Log {
+        #[command(subcommand)]
+        action: LogAction,
+    },
+
+    #[command(about = "Check a code against an account, refusing codes already used")]
+    Verify {
+        #[arg(value_name = "CODE")]
+        code: String,
+
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = VERIFY_WINDOW,
+            help = "Accepted drift: ±N time steps for TOTP, N counters ahead for HOTP"
+        )]
+        window: u64,
+
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+
+    #[command(about = "Generate single-use recovery codes or count those left")]
+    Recovery {
+        #[command(subcommand)]
+        action: RecoveryAction,
+    },
+
+    #[command(about = "Resynchronize an HOTP counter from two consecutive codes")]
+    Resync {
+        #[arg(value_name = "CODE")]
+        first: String,
+
+        #[arg(value_name = "NEXT_CODE")]
+        second: String,
+
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = RESYNC_WINDOW,
+            help = "Number of counters to search ahead of the stored one"
+        )]
+        window: u64,
+
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+
+    #[command(about = "Compute or check an OCRA (RFC 6287) challenge response")]
+    Ocra {
+        #[arg(
+            value_name = "SUITE",
+            help = "OCRA suite, e.g. OCRA-1:HOTP-SHA256-8:QN08-PSHA1"
+        )]
+        suite: Suite,
+
+        #[arg(long, value_name = "CHALLENGE")]
+        question: String,
+
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Counter, for C suites"
+        )]
+        counter: u64,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with = "pin_fd",
+            help = "Read the PIN of P suites from the first line of FILE instead of prompting"
+        )]
+        pin_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "FD",
+            help = "Read the PIN of P suites from an open file descriptor"
+        )]
+        pin_fd: Option<i32>,
+
+        #[arg(
+            long,
+            value_name = "HEX",
+            default_value = "",
+            help = "Session information in hexadecimal, for S suites"
+        )]
+        session: String,
+
+        #[arg(
+            long,
+            value_name = "TIMESTAMP",
+            help = "Unix time, for T suites [default: now]"
+        )]
+        timestamp: Option<u64>,
+
+        #[arg(
+            long,
+            value_name = "RESPONSE",
+            help = "Check RESPONSE instead of printing one"
+        )]
+        verify: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = VERIFY_WINDOW,
+            help = "Accepted drift with --verify: N counters ahead or ±N time steps"
+        )]
+        window: u64,
+
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+
+    #[command(about = "Show the QR code of an account, or save it as a PNG or SVG image")]
+    Qr {
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write the QR code to FILE, a .png or .svg, instead of the terminal"
+        )]
+        out: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "LEVEL",
+            default_value_t = qr::Level::default(),
+            help = "Error-correction level: L, M, Q or H"
+        )]
+        level: qr::Level,
+
+        #[arg(
+            long,
+            value_name = "MODULES",
+            default_value_t = qr::DEFAULT_QUIET_ZONE,
+            value_parser = clap::value_parser!(u32).range(0..=32),
+            help = "Width of the blank border around the code"
+        )]
+        quiet_zone: u32,
+
+        #[arg(
+            long,
+            value_name = "PIXELS",
+            default_value_t = qr::DEFAULT_SCALE,
+            value_parser = clap::value_parser!(u32).range(1..=64),
+            help = "Pixels per module in image files"
+        )]
+        scale: u32,
+
+        #[arg(
+            long,
+            value_name = "MODE",
+            default_value_t = qr::Preview::Auto,
+            conflicts_with = "out",
+            help = "Terminal preview: auto, kitty, sixel or unicode"
+        )]
+        preview: qr::Preview,
+
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+
+    #[command(about = "Add an account to the vault from a hexadecimal, base32 or binary key file")]
+    Add {
+        #[arg(value_name = "LABEL")]
+        label: String,
+
+        #[arg(value_name = "FILE")]
+        file: String,
+
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            default_value_t = KeyFormat::Auto,
+            help = "Encoding of the key file: auto, hex, base32 or raw"
+        )]
+        key_format: KeyFormat,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Store keys shorter than the 128 bits RFC 4226 requires, such as 80-bit base32 secrets"
+        )]
+        allow_short_key: bool,
+
+        #[arg(long, help = "Service issuing the account")]
+        issuer: Option<String>,
+
+        #[arg(
+            long = "tag",
+            value_name = "TAG",
+            help = "Tag the account (repeatable)"
+        )]
+        tags: Vec<String>,
+
+        #[command(flatten)]
+        params: ParamsArgs,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(
+        about = "Import accounts from otpauth or otpauth-migration URIs, QR code images, files of URIs, backups, backup shares or Aegis, andOTP, 2FAS and Bitwarden exports"
+    )]
+    Import {
+        #[arg(value_name = "SOURCE", required = true)]
+        sources: Vec<String>,
+
+        #[arg(long, help = "Label of the imported account, when there is only one")]
+        label: Option<String>,
+
+        #[arg(
+            long = "tag",
+            value_name = "TAG",
+            help = "Tag the imported accounts (repeatable)"
+        )]
+        tags: Vec<String>,
+
+        #[arg(
+            long,
+            value_name = "POLICY",
+            default_value_t = Conflict::Skip,
+            help = "What to do with accounts already in the vault: skip, overwrite or rename"
+        )]
+        conflict: Conflict,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read the backup or export password from the first line of FILE instead of prompting"
+        )]
+        backup_passphrase_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Show what would be imported without changing the vault"
+        )]
+        dry_run: bool,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Store keys shorter than the 128 bits RFC 4226 requires, such as 80-bit base32 secrets"
+        )]
+        allow_short_key: bool,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Write an encrypted backup of the vault accounts")]
+    Export {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(value_name = "LABEL", help = "Accounts to export [default: all]")]
+        labels: Vec<String>,
+
+        #[arg(
+            long,
+            value_name = "N",
+            requires = "threshold",
+            value_parser = clap::value_parser!(u8).range(2..),
+            help = "Split the backup into N shares written to FILE.1 to FILE.N"
+        )]
+        shares: Option<u8>,
+
+        #[arg(
+            long,
+            value_name = "K",
+            requires = "shares",
+            value_parser = clap::value_parser!(u8).range(2..),
+            help = "Number of shares needed to rebuild the backup"
+        )]
+        threshold: Option<u8>,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read the backup passphrase from the first line of FILE instead of prompting"
+        )]
+        backup_passphrase_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Export accounts as Google Authenticator migration QR codes")]
+    ExportMigration {
+        #[arg(value_name = "LABEL", help = "Accounts to export [default: all]")]
+        labels: Vec<String>,
+
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 10,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Accounts per QR code"
+        )]
+        batch_size: u64,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print the URIs instead of QR codes"
+        )]
+        uri: bool,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Browse the vault accounts with their live codes")]
+    Tui {
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "List the accounts stored in the vault")]
+    List {
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Print the current code of an account")]
+    Show {
+        #[arg(value_name = "LABEL")]
+        label: String,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Remove an account from the vault")]
+    Rm {
+        #[arg(value_name = "LABEL")]
+        label: String,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Rename an account of the vault")]
+    Rename {
+        #[arg(value_name = "LABEL")]
+        label: String,
+
+        #[arg(value_name = "NEW_LABEL")]
+        new_label: String,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum AgentAction {
+    #[command(about = "Start the agent in the foreground")]
+    Start {
+        #[arg(
+            short = 'k',
+            value_name = "FILE",
+            help = "Serve a single key file instead of the vault"
+        )]
+        key: Option<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 900,
+            help = "Lock after this long without a request, 0 to never lock"
+        )]
+        timeout: u64,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Start locked, without reading a passphrase"
+        )]
+        locked: bool,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Append the request log to FILE instead of stderr"
+        )]
+        log: Option<PathBuf>,
+
+        #[command(flatten)]
+        vault: VaultArgs,
+    },
+
+    #[command(about = "Print the next code of an account held by the agent")]
+    Code {
+        #[arg(
+            value_name = "LABEL",
+            help = "Vault account, not needed when the agent serves a key file"
+        )]
+        label: Option<String>,
+    },
+
+    #[command(about = "List the accounts held by the agent")]
+    List,
+
+    #[command(about = "Show whether the agent is locked")]
+    Status,
+
+    #[command(about = "Lock the agent, wiping the decrypted accounts")]
+    Lock,
+
+    #[command(about = "Unlock the agent with the store passphrase")]
+    Unlock,
+
+    #[command(about = "Stop the agent")]
+    Stop,
+}
+
+#[derive(Subcommand)]
+enum PamAction {
+    #[command(about = "Create the login key of USER and show its QR code and recovery codes")]
+    Enroll {
+        #[command(flatten)]
+        login: LoginKeyArgs,
+
+        #[arg(long, default_value_t = false, help = "Replace an existing key")]
+        force: bool,
+
+        #[command(flatten)]
+        recovery: RecoveryArgs,
+
+        #[command(flatten)]
+        params: ParamsArgs,
+    },
+
+    #[command(about = "Replace the recovery codes of USER")]
+    Recovery {
+        #[command(flatten)]
+        login: LoginKeyArgs,
+
+        #[command(flatten)]
+        recovery: RecoveryArgs,
+    },
+}
+
+#[derive(Args)]
+struct LoginKeyArgs {
+    #[arg(value_name = "USER")]
+    user: String,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = login::DEFAULT_KEY_PATH,
+        help = "Key file path, as given to the module with file="
+    )]
+    file: String,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = login::DEFAULT_PASSPHRASE_FILE,
+        help = "System passphrase file, as given to the module with passphrase_file="
+    )]
+    system_passphrase_file: PathBuf,
+}
+
+#[derive(Args)]
+struct RecoveryArgs {
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = recovery::RECOVERY_CODES,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=100),
+        help = "Number of single-use recovery codes"
+    )]
+    recovery_codes: usize,
+}
+
+#[derive(Subcommand)]
+enum RecoveryAction {
+    #[command(about = "Replace the recovery codes of an account and print the new ones")]
+    Generate {
+        #[command(flatten)]
+        recovery: RecoveryArgs,
+
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+
+    #[command(about = "Show how many recovery codes an account has left")]
+    Status {
+        #[command(flatten)]
+        account: AccountArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum LogAction {
+    #[command(about = "Check that no line of the audit log was edited, inserted or removed")]
+    Verify {
+        #[arg(value_name = "FILE", help = "Key file or vault the log belongs to")]
+        store: Option<PathBuf>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    memory::disable_core_dumps();
+    dotenv::dotenv().ok();
+    let mut cli = Cli::parse();
+    let source = cli.passphrase.source();
+    let audit_log = cli.audit_log.as_deref();
+
+    if let Some(command) = cli.command.take() {
+        return match command {
+            Command::Upgrade { file } => keys::upgrade(&file, &source, audit_log),
+            Command::Passwd {
+                file,
+                new_passphrase_file,
+            } => {
+                let new_source = match new_passphrase_file {
+                    Some(path) => passphrase::Source::File(path),
+                    None => passphrase::Source::Prompt,
+                };
+                keys::passwd(&file, &source, &new_source, audit_log)
+            }
+            Command::Encrypt {
+                file,
+                backend,
+                recipients,
+                keyring_name,
+            } => keys::encrypt(&file, backend, recipients, keyring_name, &source, audit_log),
+            Command::Agent { socket, action } => {
+                agent::command(socket, action, &source, &cli.time, audit_log)
+            }
+            Command::Time {
+                save,
+                clear,
+                period,
+            } => time::command(&cli.time, save, clear, period),
+            Command::Pam { action } => pam::command(action),
+            Command::Serve { args } => serve::command(args, &cli.time),
+            Command::Log { action } => audit_log::command(action, &source, audit_log),
+            command => accounts::run(command, &source, &cli.time, audit_log),
+        };
     }
 
+    if cli.generate.is_none() && cli.key.is_none() {
+        anyhow::bail!("Error: You must at least specify either -g or -k");
+    }
+    if let Some(file_path) = &cli.generate {
+        keys::generate(&cli, file_path, &source, audit_log)?;
+    }
+    if let Some(file_path) = &cli.key {
+        keys::code(&cli, file_path, &source, audit_log)?;
+    }
     Ok(())
 }
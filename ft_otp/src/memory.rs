@@ -0,0 +1,116 @@
+//! Keeps secret buffers out of swap and core dumps. Every call is best effort:
+//! an unprivileged process may hit `RLIMIT_MEMLOCK`, in which case the data is
+//! still wiped on drop, just not pinned in RAM.
+//!
+//! Locks do not stack in the kernel: one `munlock` releases a page however
+//! many buffers on it were locked. Small buffers often share a page, so each
+//! locked page is counted and only released once its last buffer is.
+
+#[cfg(unix)]
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::{Mutex, PoisonError},
+};
+
+/// Number of locked buffers on each page, by page number.
+#[cfg(unix)]
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Page numbers spanned by a non-empty `data`.
+#[cfg(unix)]
+fn pages(data: &[u8]) -> Range<usize> {
+    let size = page_size();
+    let start = data.as_ptr() as usize;
+    start / size..(start + data.len() - 1) / size + 1
+}
+
+/// Pins the pages backing `data` so they are never written to swap.
+pub fn lock(data: &[u8]) {
+    #[cfg(unix)]
+    if !data.is_empty() {
+        let mut locked = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+        for page in pages(data) {
+            *locked.entry(page).or_insert(0) += 1;
+        }
+        unsafe {
+            libc::mlock(data.as_ptr().cast(), data.len());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = data;
+}
+
+/// Releases the pages pinned by `lock` that no other locked buffer still
+/// uses. Call it after the data has been wiped.
+pub fn unlock(data: &[u8]) {
+    #[cfg(unix)]
+    if !data.is_empty() {
+        let size = page_size();
+        let mut locked = LOCKED_PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+        for page in pages(data) {
+            match locked.get_mut(&page) {
+                Some(count) if *count > 1 => *count -= 1,
+                _ => {
+                    locked.remove(&page);
+                    unsafe {
+                        libc::munlock((page * size) as *const libc::c_void, size);
+                    }
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = data;
+}
+
+/// Stops the kernel from writing a core file, which would hold every key and
+/// passphrase the process had in memory when it crashed.
+pub fn disable_core_dumps() {
+    #[cfg(unix)]
+    unsafe {
+        let limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::alloc::{self, Layout};
+
+    use super::*;
+
+    fn lock_count(data: &[u8]) -> Option<usize> {
+        let page = pages(data).start;
+        LOCKED_PAGES.lock().unwrap().get(&page).copied()
+    }
+
+    #[test]
+    fn shared_pages_stay_locked_until_the_last_buffer() {
+        // A page of our own, so no other test locks buffers on it.
+        let layout = Layout::from_size_align(page_size(), page_size()).unwrap();
+        let block = unsafe { alloc::alloc_zeroed(layout) };
+        let page = unsafe { std::slice::from_raw_parts(block, layout.size()) };
+        let (first, second) = (&page[..16], &page[16..32]);
+
+        lock(first);
+        lock(second);
+        assert_eq!(lock_count(first), Some(2));
+        unlock(first);
+        assert_eq!(lock_count(second), Some(1));
+        unlock(second);
+        assert_eq!(lock_count(second), None);
+
+        unsafe { alloc::dealloc(block, layout) };
+    }
+}
@@ -0,0 +1,607 @@
+//! OCRA challenge-response codes (RFC 6287).
+//!
+//! A suite such as `OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1` names the HMAC, the
+//! code length and which inputs go into the message:
+//!
+//! ```text
+//! suite || 0x00 || C (8) || Q (128) || P (hash) || S (nnn) || T (8)
+//! ```
+//!
+//! where only the inputs listed in the suite are present.
+
+use std::{fmt, str::FromStr};
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    error::{Error, Result},
+    keyfile::Verification,
+    totp::{self, Algorithm},
+};
+
+const QUESTION_SIZE: usize = 128;
+const DEFAULT_SESSION_SIZE: usize = 64;
+
+fn invalid(message: String) -> Error {
+    Error::InvalidParams(message)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuestionFormat {
+    Alphanumeric,
+    Numeric,
+    Hex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suite {
+    text: String,
+    pub algorithm: Algorithm,
+    /// Code length, 0 for the untruncated HMAC in hex.
+    pub digits: u32,
+    pub counter: bool,
+    pub question_format: QuestionFormat,
+    pub question_length: usize,
+    /// Hash applied to the PIN, when the suite takes one.
+    pub pin: Option<Algorithm>,
+    /// Length in bytes of the session information, when the suite takes one.
+    pub session: Option<usize>,
+    /// Time step in seconds, when the suite takes a timestamp.
+    pub time_step: Option<u64>,
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm> {
+    match name {
+        "SHA1" => Ok(Algorithm::Sha1),
+        "SHA256" => Ok(Algorithm::Sha256),
+        "SHA512" => Ok(Algorithm::Sha512),
+        _ => Err(invalid(format!("unsupported OCRA hash '{}'.", name))),
+    }
+}
+
+fn parse_time_step(step: &str) -> Result<u64> {
+    let (value, unit) = step.split_at(step.len().saturating_sub(1));
+    let value: u64 = value
+        .parse()
+        .map_err(|_| invalid(format!("invalid OCRA time step 'T{}'.", step)))?;
+    let (range, seconds) = match unit {
+        "S" => (1..=59, 1),
+        "M" => (1..=59, 60),
+        "H" => (1..=48, 3600),
+        _ => return Err(invalid(format!("invalid OCRA time step 'T{}'.", step))),
+    };
+    if !range.contains(&value) {
+        return Err(invalid(format!(
+            "OCRA time step 'T{}' is out of range.",
+            step
+        )));
+    }
+    Ok(value * seconds)
+}
+
+impl FromStr for Suite {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let parts: Vec<&str> = text.split(':').collect();
+        let [version, function, data_input] = parts[..] else {
+            return Err(invalid(format!(
+                "OCRA suite '{}' must have the form OCRA-1:HOTP-SHAx-t:DataInput.",
+                text
+            )));
+        };
+        if version != "OCRA-1" {
+            return Err(invalid(format!("unsupported OCRA version '{}'.", version)));
+        }
+
+        let function: Vec<&str> = function.split('-').collect();
+        let ["HOTP", algorithm, digits] = function[..] else {
+            return Err(invalid(format!(
+                "OCRA crypto function must be HOTP-SHAx-t, got '{}'.",
+                function.join("-")
+            )));
+        };
+        let algorithm = parse_algorithm(algorithm)?;
+        let digits: u32 = digits
+            .parse()
+            .ok()
+            .filter(|d| *d == 0 || (4..=10).contains(d))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "OCRA code length must be 0 or 4 to 10, got '{}'.",
+                    digits
+                ))
+            })?;
+
+        let mut suite = Suite {
+            text: text.to_string(),
+            algorithm,
+            digits,
+            counter: false,
+            question_format: QuestionFormat::Numeric,
+            question_length: 0,
+            pin: None,
+            session: None,
+            time_step: None,
+        };
+
+        let mut fields = data_input.split('-').peekable();
+        if fields.peek() == Some(&"C") {
+            suite.counter = true;
+            fields.next();
+        }
+
+        let question = fields
+            .next()
+            .and_then(|q| q.strip_prefix('Q'))
+            .ok_or_else(|| {
+                invalid("OCRA data input must include a challenge (Qxx).".to_string())
+            })?;
+        let (format, length) = question.split_at(1.min(question.len()));
+        suite.question_format = match format {
+            "A" => QuestionFormat::Alphanumeric,
+            "N" => QuestionFormat::Numeric,
+            "H" => QuestionFormat::Hex,
+            _ => {
+                return Err(invalid(format!(
+                    "invalid OCRA challenge format 'Q{}'.",
+                    question
+                )));
+            }
+        };
+        suite.question_length = length
+            .parse()
+            .ok()
+            .filter(|l| (4..=64).contains(l))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "OCRA challenge length must be 04 to 64, got 'Q{}'.",
+                    question
+                ))
+            })?;
+
+        for field in fields {
+            if let Some(hash) = field.strip_prefix('P') {
+                suite.pin = Some(parse_algorithm(hash)?);
+            } else if let Some(size) = field.strip_prefix('S') {
+                let parsed = match size {
+                    "" => Some(DEFAULT_SESSION_SIZE),
+                    size => size.parse().ok().filter(|s| (1..=512).contains(s)),
+                };
+                let size = parsed
+                    .ok_or_else(|| invalid(format!("invalid OCRA session size 'S{}'.", size)))?;
+                suite.session = Some(size);
+            } else if let Some(step) = field.strip_prefix('T') {
+                suite.time_step = Some(parse_time_step(step)?);
+            } else {
+                return Err(invalid(format!("unknown OCRA data input '{}'.", field)));
+            }
+        }
+        Ok(suite)
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Values fed into an OCRA computation. Only those the suite asks for are used.
+#[derive(Debug, Clone, Default)]
+pub struct Inputs<'a> {
+    pub counter: u64,
+    pub question: &'a str,
+    pub pin: &'a str,
+    pub session: &'a [u8],
+    /// Unix time in seconds, divided by the suite's time step.
+    pub timestamp: u64,
+}
+
+/// Decimal digits to hex nibbles, as the reference implementation does with
+/// `BigInteger(q, 10).toString(16)`.
+fn decimal_to_hex(digits: &str) -> String {
+    let mut number: Vec<u8> = digits.bytes().map(|b| b - b'0').collect();
+    let mut nibbles = Vec::new();
+    while number.iter().any(|d| *d != 0) {
+        let mut remainder = 0u32;
+        for digit in number.iter_mut() {
+            let value = remainder * 10 + *digit as u32;
+            *digit = (value / 16) as u8;
+            remainder = value % 16;
+        }
+        nibbles.push(char::from_digit(remainder, 16).unwrap());
+    }
+    if nibbles.is_empty() {
+        nibbles.push('0');
+    }
+    nibbles.iter().rev().collect()
+}
+
+/// Packs a hex string into `size` bytes, left aligned and padded with zeros.
+fn left_aligned_hex(hex_digits: &str, size: usize) -> Result<Vec<u8>> {
+    if hex_digits.len() > size * 2 {
+        return Err(invalid("OCRA challenge is too long.".to_string()));
+    }
+    let mut padded = hex_digits.to_string();
+    padded.extend(std::iter::repeat_n('0', size * 2 - hex_digits.len()));
+    hex::decode(padded).map_err(|_| invalid("OCRA challenge is not valid hex.".to_string()))
+}
+
+fn encode_question(suite: &Suite, question: &str) -> Result<Vec<u8>> {
+    // The length in the suite is not enforced: mutual challenge-response
+    // concatenates both sides' challenges (RFC 6287 section 7.3).
+    match suite.question_format {
+        QuestionFormat::Numeric => {
+            if question.is_empty() || !question.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid(format!(
+                    "OCRA challenge '{}' is not numeric.",
+                    question
+                )));
+            }
+            left_aligned_hex(&decimal_to_hex(question), QUESTION_SIZE)
+        }
+        QuestionFormat::Hex => {
+            if !question.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid(format!(
+                    "OCRA challenge '{}' is not hex.",
+                    question
+                )));
+            }
+            left_aligned_hex(question, QUESTION_SIZE)
+        }
+        QuestionFormat::Alphanumeric => {
+            if question.len() > QUESTION_SIZE {
+                return Err(invalid("OCRA challenge is too long.".to_string()));
+            }
+            let mut bytes = question.as_bytes().to_vec();
+            bytes.resize(QUESTION_SIZE, 0);
+            Ok(bytes)
+        }
+    }
+}
+
+fn hash_pin(algorithm: Algorithm, pin: &str) -> Vec<u8> {
+    match algorithm {
+        Algorithm::Sha1 => Sha1::digest(pin.as_bytes()).to_vec(),
+        Algorithm::Sha256 => Sha256::digest(pin.as_bytes()).to_vec(),
+        Algorithm::Sha512 => Sha512::digest(pin.as_bytes()).to_vec(),
+    }
+}
+
+/// Builds the HMAC input for `inputs` under `suite`.
+pub fn message(suite: &Suite, inputs: &Inputs) -> Result<Vec<u8>> {
+    let mut message = suite.text.as_bytes().to_vec();
+    message.push(0);
+    if suite.counter {
+        message.extend_from_slice(&inputs.counter.to_be_bytes());
+    }
+    message.extend(encode_question(suite, inputs.question)?);
+    if let Some(algorithm) = suite.pin {
+        message.extend(hash_pin(algorithm, inputs.pin));
+    }
+    if let Some(size) = suite.session {
+        if inputs.session.len() > size {
+            return Err(invalid(format!(
+                "OCRA session information is longer than {} bytes.",
+                size
+            )));
+        }
+        // Right aligned, like the reference implementation pads its hex string.
+        message.extend(std::iter::repeat_n(0, size - inputs.session.len()));
+        message.extend_from_slice(inputs.session);
+    }
+    if let Some(step) = suite.time_step {
+        message.extend_from_slice(&(inputs.timestamp / step).to_be_bytes());
+    }
+    Ok(message)
+}
+
+pub fn generate(key: &[u8], suite: &Suite, inputs: &Inputs) -> Result<String> {
+    let hmac_result = totp::hmac(suite.algorithm, key, &message(suite, inputs)?)?;
+    if suite.digits == 0 {
+        return Ok(hex::encode(hmac_result));
+    }
+    let code = totp::dynamic_truncate(&hmac_result) as u64 % 10_u64.pow(suite.digits);
+    Ok(format!("{:0width$}", code, width = suite.digits as usize))
+}
+
+/// Checks `response`, looking up to `window` counters ahead for counter-based
+/// suites and `window` time steps either way for time-based ones. Every
+/// candidate is computed so the timing does not reveal which one matched.
+pub fn verify(
+    key: &[u8],
+    suite: &Suite,
+    inputs: &Inputs,
+    response: &str,
+    window: u64,
+) -> Result<Verification> {
+    let offsets: Vec<i64> = if suite.counter {
+        (0..=window as i64).collect()
+    } else if suite.time_step.is_some() {
+        (-(window as i64)..=window as i64).collect()
+    } else {
+        vec![0]
+    };
+
+    let mut matched = None;
+    for offset in offsets {
+        let mut candidate = inputs.clone();
+        if suite.counter {
+            candidate.counter = inputs.counter.saturating_add(offset as u64);
+        } else if let Some(step) = suite.time_step {
+            let shift = offset.unsigned_abs() * step;
+            candidate.timestamp = if offset < 0 {
+                inputs.timestamp.saturating_sub(shift)
+            } else {
+                inputs.timestamp.saturating_add(shift)
+            };
+        }
+        let expected = generate(key, suite, &candidate)?;
+        if bool::from(expected.as_bytes().ct_eq(response.as_bytes())) && matched.is_none() {
+            matched = Some(offset);
+        }
+    }
+
+    Ok(match matched {
+        Some(offset) => Verification::Accepted { offset },
+        None => Verification::Rejected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY20: &[u8] = b"12345678901234567890";
+    const KEY32: &[u8] = b"12345678901234567890123456789012";
+    const KEY64: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    // RFC 6287 Appendix C uses T = 0x132d0b6 one-minute steps.
+    const TIMESTAMP: u64 = 0x132d0b6 * 60;
+
+    fn code(key: &[u8], suite: &str, inputs: Inputs) -> String {
+        generate(key, &suite.parse().unwrap(), &inputs).unwrap()
+    }
+
+    fn numeric(digit: u32) -> String {
+        char::from_digit(digit, 10).unwrap().to_string().repeat(8)
+    }
+
+    #[test]
+    fn parses_suites() {
+        let suite: Suite = "OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1".parse().unwrap();
+        assert_eq!(suite.algorithm, Algorithm::Sha256);
+        assert_eq!(suite.digits, 8);
+        assert!(suite.counter);
+        assert_eq!(suite.question_format, QuestionFormat::Numeric);
+        assert_eq!(suite.question_length, 8);
+        assert_eq!(suite.pin, Some(Algorithm::Sha1));
+        assert_eq!(suite.session, None);
+        assert_eq!(suite.time_step, None);
+
+        let suite: Suite = "OCRA-1:HOTP-SHA512-0:QH40-S128-T30S".parse().unwrap();
+        assert_eq!(suite.digits, 0);
+        assert_eq!(suite.question_format, QuestionFormat::Hex);
+        assert_eq!(suite.session, Some(128));
+        assert_eq!(suite.time_step, Some(30));
+
+        for bad in [
+            "OCRA-2:HOTP-SHA1-6:QN08",
+            "OCRA-1:HOTP-MD5-6:QN08",
+            "OCRA-1:HOTP-SHA1-3:QN08",
+            "OCRA-1:HOTP-SHA1-6:C",
+            "OCRA-1:HOTP-SHA1-6:QX08",
+            "OCRA-1:HOTP-SHA1-6:QN99",
+            "OCRA-1:HOTP-SHA1-6:QN08-T60M",
+            "OCRA-1:HOTP-SHA1-6:QN08-X",
+            "OCRA-1:HOTP-SHA1-6",
+        ] {
+            assert!(bad.parse::<Suite>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn rfc6287_one_way_sha1() {
+        let expected = [
+            "237653", "243178", "653583", "740991", "608993", "388898", "816933", "224598",
+            "750600", "294470",
+        ];
+        for (i, expected) in expected.iter().enumerate() {
+            let question = numeric(i as u32);
+            let inputs = Inputs {
+                question: &question,
+                ..Inputs::default()
+            };
+            assert_eq!(code(KEY20, "OCRA-1:HOTP-SHA1-6:QN08", inputs), *expected);
+        }
+    }
+
+    #[test]
+    fn rfc6287_counter_and_pin() {
+        let expected = [
+            "65347737", "86775851", "78192410", "71565254", "10104329", "65983500", "70069104",
+            "91771096", "75011558", "08522129",
+        ];
+        for (counter, expected) in expected.iter().enumerate() {
+            let inputs = Inputs {
+                counter: counter as u64,
+                question: "12345678",
+                pin: "1234",
+                ..Inputs::default()
+            };
+            assert_eq!(
+                code(KEY32, "OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1", inputs),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6287_pin_only() {
+        let expected = ["83238735", "01501458", "17957585", "86776967", "86807031"];
+        for (i, expected) in expected.iter().enumerate() {
+            let question = numeric(i as u32);
+            let inputs = Inputs {
+                question: &question,
+                pin: "1234",
+                ..Inputs::default()
+            };
+            assert_eq!(
+                code(KEY32, "OCRA-1:HOTP-SHA256-8:QN08-PSHA1", inputs),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6287_counter_sha512() {
+        let expected = [
+            "07016083", "63947962", "70123924", "25341727", "33203315", "34205738", "44343969",
+            "51946085", "20403879", "31409299",
+        ];
+        for (i, expected) in expected.iter().enumerate() {
+            let question = numeric(i as u32);
+            let inputs = Inputs {
+                counter: i as u64,
+                question: &question,
+                ..Inputs::default()
+            };
+            assert_eq!(
+                code(KEY64, "OCRA-1:HOTP-SHA512-8:C-QN08", inputs),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6287_timestamp() {
+        let expected = ["95209754", "55907591", "22048402", "24218844", "36209546"];
+        for (i, expected) in expected.iter().enumerate() {
+            let question = numeric(i as u32);
+            let inputs = Inputs {
+                question: &question,
+                timestamp: TIMESTAMP,
+                ..Inputs::default()
+            };
+            assert_eq!(
+                code(KEY64, "OCRA-1:HOTP-SHA512-8:QN08-T1M", inputs),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6287_mutual_challenge_response() {
+        let server = [
+            ("CLI22220SRV11110", "28247970"),
+            ("CLI22221SRV11111", "01984843"),
+            ("CLI22222SRV11112", "65387857"),
+            ("CLI22223SRV11113", "03351211"),
+            ("CLI22224SRV11114", "83412541"),
+        ];
+        let client = [
+            ("SRV11110CLI22220", "15510767"),
+            ("SRV11111CLI22221", "90175646"),
+            ("SRV11112CLI22222", "33777207"),
+            ("SRV11113CLI22223", "95285278"),
+            ("SRV11114CLI22224", "28934924"),
+        ];
+        for (question, expected) in server.iter().chain(client.iter()) {
+            let inputs = Inputs {
+                question,
+                ..Inputs::default()
+            };
+            assert_eq!(code(KEY32, "OCRA-1:HOTP-SHA256-8:QA08", inputs), *expected);
+        }
+    }
+
+    #[test]
+    fn rfc6287_plain_signature() {
+        let expected = [
+            ("SIG10000", "53095496"),
+            ("SIG11000", "04110475"),
+            ("SIG12000", "31331128"),
+            ("SIG13000", "76028668"),
+            ("SIG14000", "46554205"),
+        ];
+        for (question, expected) in expected {
+            let inputs = Inputs {
+                question,
+                ..Inputs::default()
+            };
+            assert_eq!(code(KEY32, "OCRA-1:HOTP-SHA256-8:QA08", inputs), expected);
+        }
+
+        let expected = [
+            ("SIG1000000", "77537423"),
+            ("SIG1100000", "31970405"),
+            ("SIG1200000", "10235557"),
+            ("SIG1300000", "95213541"),
+            ("SIG1400000", "65360607"),
+        ];
+        for (question, expected) in expected {
+            let inputs = Inputs {
+                question,
+                timestamp: TIMESTAMP,
+                ..Inputs::default()
+            };
+            assert_eq!(
+                code(KEY64, "OCRA-1:HOTP-SHA512-8:QA10-T1M", inputs),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn verifies_within_the_window() {
+        let suite: Suite = "OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1".parse().unwrap();
+        let inputs = Inputs {
+            counter: 3,
+            question: "12345678",
+            pin: "1234",
+            ..Inputs::default()
+        };
+        // Counter 5 from the RFC table, two ahead of the expected one.
+        assert_eq!(
+            verify(KEY32, &suite, &inputs, "65983500", 2).unwrap(),
+            Verification::Accepted { offset: 2 }
+        );
+        assert_eq!(
+            verify(KEY32, &suite, &inputs, "65983500", 1).unwrap(),
+            Verification::Rejected
+        );
+
+        let suite: Suite = "OCRA-1:HOTP-SHA512-8:QN08-T1M".parse().unwrap();
+        let inputs = Inputs {
+            question: "00000000",
+            timestamp: TIMESTAMP + 60,
+            ..Inputs::default()
+        };
+        assert_eq!(
+            verify(KEY64, &suite, &inputs, "95209754", 1).unwrap(),
+            Verification::Accepted { offset: -1 }
+        );
+    }
+
+    #[test]
+    fn session_and_full_hmac() {
+        let suite: Suite = "OCRA-1:HOTP-SHA1-0:QH08-S004".parse().unwrap();
+        let inputs = Inputs {
+            question: "DEADBEEF",
+            session: &[1, 2],
+            ..Inputs::default()
+        };
+        let message = message(&suite, &inputs).unwrap();
+        assert_eq!(&message[message.len() - 4..], [0, 0, 1, 2]);
+        assert_eq!(generate(KEY20, &suite, &inputs).unwrap().len(), 40);
+
+        let too_long = Inputs {
+            session: &[0; 5],
+            ..inputs
+        };
+        assert!(generate(KEY20, &suite, &too_long).is_err());
+    }
+}
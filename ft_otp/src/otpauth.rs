@@ -0,0 +1,279 @@
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{Error, Result},
+    keyfile::{KeyFile, OtpKind},
+    totp::{Encoding, TotpParams},
+    vault::Entry,
+};
+
+const SCHEME: &str = "otpauth://";
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+fn decode(value: &str) -> Result<String> {
+    let decoded = percent_decode_str(value).decode_utf8().map_err(|_| {
+        Error::InvalidUri(format!("'{}' is not valid percent-encoded UTF-8.", value))
+    })?;
+    Ok(decoded.into_owned())
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+}
+
+pub fn decode_secret(secret: &str) -> Result<Vec<u8>> {
+    let normalized: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    match base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &normalized) {
+        Some(bytes) if !bytes.is_empty() => Ok(bytes),
+        _ => Err(Error::InvalidUri(format!(
+            "secret '{}' is not valid base32.",
+            secret
+        ))),
+    }
+}
+
+/// The URI carries the secret in clear, so it is wiped when dropped.
+pub fn to_uri(entry: &Entry) -> Zeroizing<String> {
+    let key = &entry.key;
+    let label = match &entry.issuer {
+        Some(issuer) => format!("{}:{}", encode(issuer), encode(&entry.label)),
+        None => encode(&entry.label),
+    };
+
+    let mut params = String::new();
+    if let Some(issuer) = &entry.issuer {
+        params.push_str(&format!("&issuer={}", encode(issuer)));
+    }
+    params.push_str(&format!(
+        "&algorithm={}&digits={}",
+        key.params.algorithm, key.params.digits
+    ));
+    match key.kind {
+        OtpKind::Totp => params.push_str(&format!("&period={}", key.params.period)),
+        OtpKind::Hotp => params.push_str(&format!("&counter={}", key.counter)),
+    }
+    if !key.params.encoding.is_decimal() {
+        params.push_str(&format!(
+            "&encoder={}",
+            encode(&key.params.encoding.to_string())
+        ));
+    }
+
+    // Sized up front so growing the string never leaves a stray copy of the
+    // secret in a freed buffer.
+    let secret = Zeroizing::new(encode_secret(key.secret.expose()));
+    let kind = key.kind.as_str();
+    let mut uri = Zeroizing::new(String::with_capacity(
+        SCHEME.len() + kind.len() + label.len() + secret.len() + params.len() + 9,
+    ));
+    for part in [SCHEME, kind, "/", &label, "?secret=", &secret, &params] {
+        uri.push_str(part);
+    }
+    uri
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidUri(format!("{} must be a number, got '{}'.", name, value)))
+}
+
+pub fn parse(uri: &str) -> Result<Entry> {
+    let uri = uri.trim();
+    let rest = match uri.get(..SCHEME.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(SCHEME) => &uri[SCHEME.len()..],
+        _ => {
+            return Err(Error::InvalidUri(format!(
+                "URI must start with '{}'.",
+                SCHEME
+            )));
+        }
+    };
+
+    let (kind, rest) = rest
+        .split_once('/')
+        .ok_or_else(|| Error::InvalidUri("URI is missing the '/label' part.".to_string()))?;
+    // Some apps (Aegis, ...) write Steam accounts as their own "steam" type,
+    // others as totp with `encoder=steam`.
+    let mut encoding = Encoding::Decimal;
+    let kind = match kind.to_ascii_lowercase().as_str() {
+        "totp" => OtpKind::Totp,
+        "hotp" => OtpKind::Hotp,
+        "steam" => {
+            encoding = Encoding::Steam;
+            OtpKind::Totp
+        }
+        _ => {
+            return Err(Error::InvalidUri(format!(
+                "unsupported OTP type '{}' (expected totp or hotp).",
+                kind
+            )));
+        }
+    };
+
+    let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let label = decode(label)?;
+    let (label_issuer, account) = match label.split_once(':') {
+        Some((issuer, account)) => (Some(issuer.trim().to_string()), account.trim()),
+        None => (None, label.trim()),
+    };
+    if account.is_empty() {
+        return Err(Error::InvalidUri(
+            "URI label must name an account.".to_string(),
+        ));
+    }
+
+    let mut secret = None;
+    let mut issuer = None;
+    let mut params = TotpParams::default();
+    let mut digits = None;
+    let mut counter = None;
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = decode(&value.replace('+', " "))?;
+        match name.to_ascii_lowercase().as_str() {
+            "secret" => secret = Some(decode_secret(&value)?),
+            "issuer" => issuer = Some(value),
+            "algorithm" => params.algorithm = value.parse()?,
+            "digits" => digits = Some(parse_number("digits", &value)?),
+            "encoder" => encoding = value.parse()?,
+            "period" => params.period = parse_number("period", &value)?,
+            "counter" => counter = Some(parse_number("counter", &value)?),
+            _ => {}
+        }
+    }
+
+    let secret = secret
+        .ok_or_else(|| Error::InvalidUri("URI is missing the secret parameter.".to_string()))?;
+    params.digits = digits.unwrap_or(encoding.default_digits());
+    params.encoding = encoding;
+    params.validate()?;
+    let counter = match (kind, counter) {
+        (OtpKind::Hotp, None) => {
+            return Err(Error::InvalidUri(
+                "hotp URI is missing the counter parameter.".to_string(),
+            ));
+        }
+        (_, counter) => counter.unwrap_or(0),
+    };
+
+    let mut key = KeyFile::new(secret, params);
+    key.kind = kind;
+    key.counter = counter;
+
+    Ok(Entry {
+        label: account.to_string(),
+        issuer: issuer.filter(|issuer| !issuer.is_empty()).or(label_issuer),
+        key,
+        tags: Vec::new(),
+        icon: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::totp::Algorithm;
+
+    #[test]
+    fn parses_full_totp_uri() {
+        let entry = parse(
+            "otpauth://totp/ACME%20Co:john.doe@email.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ\
+             &issuer=ACME%20Co&algorithm=SHA256&digits=8&period=60",
+        )
+        .unwrap();
+
+        assert_eq!(entry.label, "john.doe@email.com");
+        assert_eq!(entry.issuer.as_deref(), Some("ACME Co"));
+        assert_eq!(entry.key.kind, OtpKind::Totp);
+        assert_eq!(entry.key.params.algorithm, Algorithm::Sha256);
+        assert_eq!(entry.key.params.digits, 8);
+        assert_eq!(entry.key.params.period, 60);
+        assert_eq!(
+            encode_secret(entry.key.secret.expose()),
+            "HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ"
+        );
+    }
+
+    #[test]
+    fn parses_hotp_uri_with_defaults() {
+        let entry =
+            parse("otpauth://hotp/Example:alice?secret=jbsw y3dp ehpk 3pxp&counter=42").unwrap();
+
+        assert_eq!(entry.issuer.as_deref(), Some("Example"));
+        assert_eq!(entry.key.kind, OtpKind::Hotp);
+        assert_eq!(entry.key.counter, 42);
+        assert_eq!(entry.key.params, TotpParams::default());
+    }
+
+    #[test]
+    fn round_trips_through_to_uri() {
+        let entry =
+            parse("otpauth://totp/My%20Bank:bob?secret=JBSWY3DPEHPK3PXP&issuer=My%20Bank").unwrap();
+        let again = parse(&to_uri(&entry)).unwrap();
+
+        assert_eq!(again.label, entry.label);
+        assert_eq!(again.issuer, entry.issuer);
+        assert_eq!(again.key.secret, entry.key.secret);
+    }
+
+    #[test]
+    fn carries_steam_encoding() {
+        let entry =
+            parse("otpauth://totp/Steam:gaben?secret=GEZDGNBVGY3TQOJQ&encoder=steam").unwrap();
+        assert_eq!(entry.key.params.encoding, Encoding::Steam);
+        assert_eq!(entry.key.params.digits, 5);
+        assert!(to_uri(&entry).contains("&encoder=steam"));
+
+        let entry = parse("otpauth://steam/Steam:gaben?secret=GEZDGNBVGY3TQOJQ").unwrap();
+        assert_eq!(entry.key.params.encoding, Encoding::Steam);
+        assert_eq!(entry.key.kind, OtpKind::Totp);
+
+        let entry = parse(
+            "otpauth://totp/Vendor:me?secret=GEZDGNBVGY3TQOJQ&encoder=alphabet%3AABCDEFGH&digits=8",
+        )
+        .unwrap();
+        assert_eq!(
+            entry.key.params.encoding,
+            Encoding::Alphabet("ABCDEFGH".to_string())
+        );
+        assert_eq!(parse(&to_uri(&entry)).unwrap().key.params, entry.key.params);
+    }
+
+    #[test]
+    fn rejects_malformed_uris() {
+        let errors = [
+            ("https://totp/a?secret=JBSWY3DP", "must start with"),
+            ("otpauth://motp/a?secret=JBSWY3DP", "unsupported OTP type"),
+            ("otpauth://totp/a?issuer=x", "missing the secret"),
+            ("otpauth://totp/a?secret=not-base32!", "not valid base32"),
+            (
+                "otpauth://totp/a?secret=JBSWY3DP&digits=eight",
+                "digits must be a number",
+            ),
+            (
+                "otpauth://totp/a?secret=JBSWY3DP&algorithm=MD5",
+                "unknown algorithm",
+            ),
+            ("otpauth://hotp/a?secret=JBSWY3DP", "missing the counter"),
+            (
+                "otpauth://totp/Issuer:?secret=JBSWY3DP",
+                "must name an account",
+            ),
+        ];
+        for (uri, message) in errors {
+            let error = parse(uri).unwrap_err().to_string();
+            assert!(error.contains(message), "{}: {}", uri, error);
+        }
+    }
+}
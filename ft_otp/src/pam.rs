@@ -0,0 +1,65 @@
+//! `ft_otp pam`: enrolls logins for the PAM module and renews their recovery
+//! codes.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroizing;
+
+use ft_otp::{Secret, login, otpauth, secret, vault::Entry};
+
+use crate::{PamAction, keys::print_recovery_codes, qr};
+
+/// Keys for logins are encrypted under the system passphrase, not a user's,
+/// so the module can read them unattended.
+pub fn command(action: PamAction) -> anyhow::Result<()> {
+    match action {
+        PamAction::Enroll {
+            login: args,
+            force,
+            recovery,
+            params,
+        } => {
+            let passphrase = login::read_passphrase(&args.system_passphrase_file)?;
+            let path = login::key_path(&args.file, &args.user, || login::home_dir(&args.user))?;
+
+            let mut secret = vec![0u8; secret::RECOMMENDED_SECRET_LEN];
+            OsRng.fill_bytes(&mut secret);
+            let mut key = params.to_key(Secret::new(secret))?;
+            let codes = key.regenerate_recovery_codes(recovery.recovery_codes);
+            login::enroll(&path, &args.user, passphrase.expose(), &key, force)?;
+            println!("Key of {} saved in {}", args.user, path.display());
+
+            let manual = Zeroizing::new(otpauth::encode_secret(key.secret.expose()));
+            let entry = Entry {
+                label: args.user,
+                issuer: Some("ft_otp".to_string()),
+                key,
+                tags: Vec::new(),
+                icon: None,
+            };
+            let matrix = qr::Matrix::new(
+                &otpauth::to_uri(&entry),
+                qr::Level::default(),
+                qr::DEFAULT_QUIET_ZONE,
+            )?;
+            qr::preview(&matrix, qr::Preview::Auto)?;
+            println!("Or enter the secret by hand: {}", manual.as_str());
+            print_recovery_codes(&codes);
+        }
+        PamAction::Recovery {
+            login: args,
+            recovery,
+        } => {
+            let passphrase = login::read_passphrase(&args.system_passphrase_file)?;
+            let path = login::key_path(&args.file, &args.user, || login::home_dir(&args.user))?;
+            let codes = login::regenerate_recovery_codes(
+                &path,
+                &args.user,
+                passphrase.expose(),
+                recovery.recovery_codes,
+            )?
+            .ok_or_else(|| anyhow::anyhow!("{} has no key at {}.", args.user, path.display()))?;
+            print_recovery_codes(&codes);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use ft_otp::{Passphrase, cipher};
+use zeroize::Zeroizing;
+
+/// Where a passphrase comes from. Anything but the prompt yields its first
+/// line, so a trailing newline never ends up in the passphrase.
+pub enum Source {
+    File(PathBuf),
+    Fd(i32),
+    Cmd(String),
+    /// Always ask on the terminal.
+    Prompt,
+    /// `PASSPHRASE` (possibly loaded from `.env`) when set, the terminal otherwise.
+    Default,
+}
+
+fn first_line(data: Zeroizing<Vec<u8>>) -> anyhow::Result<Passphrase> {
+    let text = std::str::from_utf8(&data)
+        .map_err(|_| anyhow::anyhow!("passphrase is not valid UTF-8."))?;
+    let line = text.lines().next().unwrap_or_default();
+    Ok(Passphrase::new(line.to_string()))
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 3 && fd != 0 {
+        anyhow::bail!("--passphrase-fd cannot read from stdout or stderr.");
+    }
+    // The descriptor is handed over by the caller and closed once read.
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+    let mut data = Zeroizing::new(Vec::with_capacity(1024));
+    file.read_to_end(&mut data)
+        .map_err(|e| anyhow::anyhow!("reading passphrase from fd {}: {}", fd, e))?;
+    Ok(data)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    anyhow::bail!("--passphrase-fd is only supported on Unix.")
+}
+
+fn read_cmd(cmd: &str) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow::anyhow!("running passphrase command: {}", e))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        anyhow::bail!("passphrase command failed ({}).", output.status);
+    }
+    Ok(stdout)
+}
+
+fn prompt(name: &str, confirm: bool) -> anyhow::Result<Passphrase> {
+    let ask = |prompt: &str| {
+        rpassword::prompt_password(prompt).map(Passphrase::new).map_err(|_| match name {
+            "PIN" => anyhow::anyhow!("no PIN: no terminal to prompt on, use --pin-file or --pin-fd."),
+            _ => anyhow::anyhow!(
+                "no passphrase: no terminal to prompt on, set PASSPHRASE or use --passphrase-file, --passphrase-fd or --passphrase-cmd."
+            ),
+        })
+    };
+
+    let passphrase = ask(&format!("{}: ", name))?;
+    if confirm {
+        let again = ask(&format!("Confirm {}: ", name.to_lowercase()))?;
+        if passphrase.expose() != again.expose() {
+            anyhow::bail!("passphrases do not match.");
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Reads a passphrase. `confirm` asks twice when prompting, for passphrases
+/// that are about to encrypt something new.
+pub fn read(source: &Source, confirm: bool) -> anyhow::Result<Passphrase> {
+    read_named(source, confirm, "Passphrase")
+}
+
+/// Like `read`, for the store at `store`: files sealed to an age identity or
+/// a keyring entry are opened without asking.
+pub fn read_for(store: &Path, source: &Source, confirm: bool) -> anyhow::Result<Passphrase> {
+    if let Ok(data) = fs::read(store)
+        && let Some(passphrase) = cipher::unattended_passphrase(&data)?
+    {
+        return Ok(passphrase);
+    }
+    read(source, confirm)
+}
+
+/// Like `read`, with `name` in the terminal prompt, for passphrases other
+/// than the store's own.
+pub fn read_named(source: &Source, confirm: bool, name: &str) -> anyhow::Result<Passphrase> {
+    let passphrase = match source {
+        Source::File(path) => first_line(Zeroizing::new(
+            fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+        ))?,
+        Source::Fd(fd) => first_line(read_fd(*fd)?)?,
+        Source::Cmd(cmd) => first_line(read_cmd(cmd)?)?,
+        Source::Prompt => prompt(name, confirm)?,
+        Source::Default => match env::var("PASSPHRASE") {
+            Ok(passphrase) => Passphrase::new(passphrase),
+            Err(_) => prompt(name, confirm)?,
+        },
+    };
+
+    if passphrase.expose().is_empty() {
+        anyhow::bail!("passphrase must not be empty.");
+    }
+    Ok(passphrase)
+}
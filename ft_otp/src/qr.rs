@@ -0,0 +1,471 @@
+use std::{
+    env, fmt,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use image::{GrayImage, ImageFormat, Luma};
+use qrcode::{Color, EcLevel, QrCode};
+use zeroize::Zeroizing;
+
+/// Modules of white border the QR code specification asks for.
+pub const DEFAULT_QUIET_ZONE: u32 = 4;
+/// Pixels per module in images.
+pub const DEFAULT_SCALE: u32 = 8;
+
+/// Error-correction level: the share of the code that can be damaged and
+/// still read, traded against a bigger code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Level(pub EcLevel);
+
+impl Default for Level {
+    fn default() -> Self {
+        Level(EcLevel::M)
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.0 {
+            EcLevel::L => "L",
+            EcLevel::M => "M",
+            EcLevel::Q => "Q",
+            EcLevel::H => "H",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "L" => Ok(Level(EcLevel::L)),
+            "M" => Ok(Level(EcLevel::M)),
+            "Q" => Ok(Level(EcLevel::Q)),
+            "H" => Ok(Level(EcLevel::H)),
+            _ => anyhow::bail!(
+                "unknown error-correction level '{}': expected L, M, Q or H.",
+                s
+            ),
+        }
+    }
+}
+
+/// How a QR code is shown in the terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preview {
+    /// Kitty or sixel graphics when the terminal is known to support them.
+    #[default]
+    Auto,
+    Kitty,
+    Sixel,
+    Unicode,
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Preview::Auto => "auto",
+            Preview::Kitty => "kitty",
+            Preview::Sixel => "sixel",
+            Preview::Unicode => "unicode",
+        })
+    }
+}
+
+impl FromStr for Preview {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Preview::Auto),
+            "kitty" => Ok(Preview::Kitty),
+            "sixel" => Ok(Preview::Sixel),
+            "unicode" => Ok(Preview::Unicode),
+            _ => anyhow::bail!(
+                "unknown preview '{}': expected auto, kitty, sixel or unicode.",
+                s
+            ),
+        }
+    }
+}
+
+/// The modules of a QR code with its quiet zone, `true` for dark.
+pub struct Matrix {
+    /// Width and height in modules, quiet zone included.
+    size: usize,
+    dark: Vec<bool>,
+}
+
+impl Matrix {
+    pub fn new(payload: &str, level: Level, quiet_zone: u32) -> anyhow::Result<Matrix> {
+        let code = QrCode::with_error_correction_level(payload.as_bytes(), level.0)?;
+        let width = code.width();
+        let margin = quiet_zone as usize;
+        let size = width + 2 * margin;
+
+        let mut dark = vec![false; size * size];
+        for (i, color) in code.to_colors().into_iter().enumerate() {
+            let (x, y) = (i % width + margin, i / width + margin);
+            dark[y * size + x] = color == Color::Dark;
+        }
+        Ok(Matrix { size, dark })
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.dark[y * self.size + x]
+    }
+
+    /// Two rows of modules per line of half blocks, dark modules drawn in
+    /// the text color like the TUI's `Dense1x2` rendering.
+    pub fn to_unicode(&self) -> Zeroizing<String> {
+        let mut text = Zeroizing::new(String::with_capacity(
+            (self.size + 1) * self.size.div_ceil(2) * 3,
+        ));
+        for y in (0..self.size).step_by(2) {
+            for x in 0..self.size {
+                text.push(match (self.is_dark(x, y), self.is_dark(x, y + 1)) {
+                    (false, false) => ' ',
+                    (false, true) => '▄',
+                    (true, false) => '▀',
+                    (true, true) => '█',
+                });
+            }
+            if y + 2 < self.size {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    pub fn to_image(&self, scale: u32) -> GrayImage {
+        let pixels = self.size as u32 * scale;
+        GrayImage::from_fn(pixels, pixels, |x, y| {
+            let dark = self.is_dark((x / scale) as usize, (y / scale) as usize);
+            Luma([if dark { 0 } else { 255 }])
+        })
+    }
+
+    pub fn to_png(&self, scale: u32) -> anyhow::Result<Vec<u8>> {
+        let mut png = io::Cursor::new(Vec::new());
+        self.to_image(scale).write_to(&mut png, ImageFormat::Png)?;
+        Ok(png.into_inner())
+    }
+
+    /// One path of unit squares on a white background, scaled by the viewer.
+    pub fn to_svg(&self, scale: u32) -> String {
+        let pixels = self.size as u32 * scale;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{} {}h1v1h-1z", x, y));
+                }
+            }
+        }
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{px}\" height=\"{px}\" ",
+                "viewBox=\"0 0 {n} {n}\" shape-rendering=\"crispEdges\">\n",
+                "<rect width=\"{n}\" height=\"{n}\" fill=\"#fff\"/>\n",
+                "<path fill=\"#000\" d=\"{path}\"/>\n",
+                "</svg>\n"
+            ),
+            px = pixels,
+            n = self.size,
+            path = path
+        )
+    }
+
+    /// DEC sixel graphics: two registers, white and black, six pixel rows per
+    /// band, each run of identical sixels compressed with `!`.
+    pub fn to_sixel(&self, scale: u32) -> String {
+        let image = self.to_image(scale);
+        let (width, height) = image.dimensions();
+        let mut out = format!("\x1bPq\"1;1;{};{}#0;2;100;100;100#1;2;0;0;0", width, height);
+
+        for band in (0..height).step_by(6) {
+            for (register, dark) in [(0, false), (1, true)] {
+                out.push_str(&format!("#{}", register));
+                let sixels = (0..width).map(|x| {
+                    let bits = (0..6)
+                        .filter(|row| band + row < height)
+                        .filter(|row| (image.get_pixel(x, band + row).0[0] == 0) == dark)
+                        .fold(0u8, |bits, row| bits | 1 << row);
+                    (63 + bits) as char
+                });
+                push_runs(&mut out, sixels);
+                out.push('$');
+            }
+            out.push('-');
+        }
+        out.push_str("\x1b\\");
+        out
+    }
+
+    pub fn to_kitty(&self, scale: u32) -> anyhow::Result<String> {
+        Ok(kitty_escape(&self.to_png(scale)?))
+    }
+}
+
+/// Kitty graphics protocol: the PNG sent in base64 chunks of at most 4096
+/// bytes, `m=1` on every chunk but the last.
+fn kitty_escape(png: &[u8]) -> String {
+    let encoded = BASE64.encode(png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    let mut out = String::with_capacity(encoded.len() + chunks.len() * 32);
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let control = if i == 0 {
+            format!("a=T,f=100,m={}", more)
+        } else {
+            format!("m={}", more)
+        };
+        out.push_str(&format!(
+            "\x1b_G{};{}\x1b\\",
+            control,
+            std::str::from_utf8(chunk).unwrap()
+        ));
+    }
+    out.push('\n');
+    out
+}
+
+fn push_runs(out: &mut String, sixels: impl Iterator<Item = char>) {
+    let flush = |out: &mut String, c: char, count: usize| match count {
+        0 => {}
+        1..=3 => out.extend(std::iter::repeat_n(c, count)),
+        _ => out.push_str(&format!("!{}{}", count, c)),
+    };
+    let mut run: Option<(char, usize)> = None;
+    for c in sixels {
+        run = match run {
+            Some((last, count)) if last == c => Some((last, count + 1)),
+            Some((last, count)) => {
+                flush(out, last, count);
+                Some((c, 1))
+            }
+            None => Some((c, 1)),
+        };
+    }
+    if let Some((last, count)) = run {
+        flush(out, last, count);
+    }
+}
+
+/// Guesses the graphics protocol from the environment, since asking the
+/// terminal would need a round trip through raw mode.
+fn detect() -> Preview {
+    if !io::stdout().is_terminal() {
+        return Preview::Unicode;
+    }
+    let var = |name: &str| env::var(name).unwrap_or_default();
+    let term = var("TERM");
+    let program = var("TERM_PROGRAM");
+
+    if env::var_os("KITTY_WINDOW_ID").is_some()
+        || term == "xterm-kitty"
+        || ["WezTerm", "ghostty"].contains(&program.as_str())
+    {
+        Preview::Kitty
+    } else if term.contains("sixel")
+        || ["foot", "mlterm", "contour"]
+            .iter()
+            .any(|t| term.starts_with(t))
+    {
+        Preview::Sixel
+    } else {
+        Preview::Unicode
+    }
+}
+
+/// Prints the QR code with the best protocol the terminal supports.
+pub fn preview(matrix: &Matrix, preview: Preview) -> anyhow::Result<()> {
+    let preview = match preview {
+        Preview::Auto => detect(),
+        preview => preview,
+    };
+    // Terminal pixels are small, so graphics get a few per module.
+    let output = match preview {
+        Preview::Kitty => Zeroizing::new(matrix.to_kitty(4)?),
+        Preview::Sixel => Zeroizing::new(matrix.to_sixel(4)),
+        Preview::Unicode | Preview::Auto => {
+            let mut text = matrix.to_unicode();
+            text.push('\n');
+            text
+        }
+    };
+    let mut stdout = io::stdout();
+    stdout.write_all(output.as_bytes())?;
+    Ok(stdout.flush()?)
+}
+
+/// Writes a PNG or SVG file, picked by the extension. The code holds the
+/// secret, so the file is readable by its owner only.
+pub fn save(matrix: &Matrix, path: &Path, scale: u32) -> anyhow::Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    let data = match extension.as_deref() {
+        Some("png") => matrix.to_png(scale)?,
+        Some("svg") => matrix.to_svg(scale).into_bytes(),
+        _ => anyhow::bail!(
+            "{}: the QR code can be saved as .png or .svg.",
+            path.display()
+        ),
+    };
+    ft_otp::storage::write_private(path, &data)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
+
+pub fn render_unicode(payload: &str) -> anyhow::Result<String> {
+    let code = qrcode::QrCode::new(payload.as_bytes())?;
+    Ok(code.render::<qrcode::render::unicode::Dense1x2>().build())
+}
+
+/// Decodes every QR code found in a PNG or JPEG image.
+pub fn decode_image(path: &Path) -> anyhow::Result<Vec<String>> {
+    let image = image::open(path)
+        .map_err(|e| anyhow::anyhow!("cannot read image {}: {}", path.display(), e))?
+        .to_luma8();
+    decode(image)
+}
+
+pub fn decode(image: image::GrayImage) -> anyhow::Result<Vec<String>> {
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let grids = prepared.detect_grids();
+    if grids.is_empty() {
+        anyhow::bail!("no QR code found in the image.");
+    }
+
+    grids
+        .iter()
+        .map(|grid| {
+            grid.decode()
+                .map(|(_, content)| content)
+                .map_err(|e| anyhow::anyhow!("cannot decode QR code: {}", e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "otpauth://totp/ft_otp?secret=JBSWY3DPEHPK3PXP&issuer=ft_otp";
+
+    #[test]
+    fn decodes_rendered_code() {
+        let image = qrcode::QrCode::new(URI.as_bytes())
+            .unwrap()
+            .render::<image::Luma<u8>>()
+            .build();
+
+        assert_eq!(decode(image).unwrap(), vec![URI.to_string()]);
+    }
+
+    #[test]
+    fn png_round_trips_at_every_level() {
+        for level in ["L", "M", "Q", "H"] {
+            let matrix = Matrix::new(URI, level.parse().unwrap(), DEFAULT_QUIET_ZONE).unwrap();
+            let png = matrix.to_png(DEFAULT_SCALE).unwrap();
+            let image = image::load_from_memory(&png).unwrap().to_luma8();
+            assert_eq!(decode(image).unwrap(), vec![URI.to_string()]);
+        }
+        assert!("X".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn higher_levels_and_quiet_zones_grow_the_code() {
+        let low = Matrix::new(URI, Level(EcLevel::L), 0).unwrap();
+        let high = Matrix::new(URI, Level(EcLevel::H), 0).unwrap();
+        assert!(high.size > low.size);
+
+        let framed = Matrix::new(URI, Level(EcLevel::L), 2).unwrap();
+        assert_eq!(framed.size, low.size + 4);
+        assert!((0..framed.size).all(|i| !framed.is_dark(i, 0) && !framed.is_dark(1, i)));
+        assert!(framed.is_dark(2, 2));
+    }
+
+    #[test]
+    fn svg_draws_every_dark_module() {
+        let matrix = Matrix::new(URI, Level::default(), 1).unwrap();
+        let svg = matrix.to_svg(10);
+        let dark = matrix.dark.iter().filter(|&&d| d).count();
+        assert_eq!(svg.matches('z').count(), dark);
+        let size = matrix.size * 10;
+        assert!(svg.contains(&format!("width=\"{}\"", size)));
+    }
+
+    #[test]
+    fn unicode_packs_two_rows_per_line() {
+        let matrix = Matrix::new(URI, Level::default(), DEFAULT_QUIET_ZONE).unwrap();
+        let text = matrix.to_unicode();
+        assert_eq!(text.lines().count(), matrix.size.div_ceil(2));
+        assert!(text.lines().all(|line| line.chars().count() == matrix.size));
+        // The top quiet zone is two light rows.
+        assert!(text.lines().next().unwrap().chars().all(|c| c == ' '));
+        assert_eq!(*text, render_unicode(URI).unwrap());
+    }
+
+    #[test]
+    fn saved_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("ft_otp-qr-{}.png", std::process::id()));
+        let matrix = Matrix::new(URI, Level::default(), DEFAULT_QUIET_ZONE).unwrap();
+        save(&matrix, &path, 2).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sixel_has_one_band_per_six_rows() {
+        let matrix = Matrix::new(URI, Level::default(), 0).unwrap();
+        let sixel = matrix.to_sixel(1);
+        assert!(sixel.starts_with("\x1bPq\""));
+        assert!(sixel.ends_with("-\x1b\\"));
+        assert_eq!(sixel.matches('-').count(), matrix.size.div_ceil(6));
+
+        let mut runs = String::new();
+        push_runs(&mut runs, "??~~~~~@".chars());
+        assert_eq!(runs, "??!5~@");
+    }
+
+    #[test]
+    fn kitty_chunks_the_png() {
+        let png: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let kitty = kitty_escape(&png);
+        let chunks: Vec<&str> = kitty
+            .trim_end()
+            .split("\x1b\\")
+            .filter(|c| !c.is_empty())
+            .collect();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=100,m=1;"));
+        assert!(chunks[1].starts_with("\x1b_Gm=1;"));
+        assert!(chunks[3].starts_with("\x1b_Gm=0;"));
+
+        let payload: String = chunks
+            .iter()
+            .map(|chunk| {
+                let data = chunk.split_once(';').unwrap().1;
+                assert!(data.len() <= 4096);
+                data
+            })
+            .collect();
+        assert_eq!(BASE64.decode(payload).unwrap(), png);
+
+        let single = Matrix::new(URI, Level::default(), DEFAULT_QUIET_ZONE)
+            .unwrap()
+            .to_kitty(4)
+            .unwrap();
+        assert!(single.starts_with("\x1b_Ga=T,f=100,m=0;"));
+    }
+}
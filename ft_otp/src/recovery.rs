@@ -0,0 +1,141 @@
+//! Single-use recovery codes, the way back in when the authenticator is lost.
+//!
+//! A code is 12 characters of Crockford's base32 alphabet, shown in groups of
+//! four (`7k2m-q9xd-4rwp`, 60 bits). Only a salted SHA-256 of each is kept, in
+//! the key file, and a code is removed once it has been used.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+/// Number of codes handed out by default.
+pub const RECOVERY_CODES: usize = 10;
+
+const CODE_CHARS: usize = 12;
+const GROUP: usize = 4;
+/// Crockford's base32: no I, L, O or U to misread.
+const ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryCode {
+    #[serde(with = "hex")]
+    salt: [u8; 16],
+    #[serde(with = "hex")]
+    hash: [u8; 32],
+}
+
+impl RecoveryCode {
+    fn new(code: &str) -> RecoveryCode {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        RecoveryCode {
+            hash: digest(&salt, code),
+            salt,
+        }
+    }
+}
+
+fn digest(salt: &[u8], code: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Lowercase, without the dashes and spaces people type between groups, and
+/// with the letters Crockford's base32 reads as digits.
+fn normalize(code: &str) -> Option<Zeroizing<String>> {
+    let code: Zeroizing<String> = Zeroizing::new(
+        code.chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .map(|c| match c.to_ascii_lowercase() {
+                'o' => '0',
+                'i' | 'l' => '1',
+                c => c,
+            })
+            .collect(),
+    );
+    let valid = code.len() == CODE_CHARS && code.bytes().all(|b| ALPHABET.contains(&b));
+    valid.then_some(code)
+}
+
+/// `count` new codes, formatted for display, with what to store of them.
+pub fn generate(count: usize) -> (Vec<Zeroizing<String>>, Vec<RecoveryCode>) {
+    let mut codes = Vec::with_capacity(count);
+    let mut stored = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut random = Zeroizing::new([0u8; CODE_CHARS]);
+        OsRng.fill_bytes(random.as_mut());
+        let raw: Zeroizing<String> = Zeroizing::new(
+            random
+                .iter()
+                .map(|b| ALPHABET[(b & 31) as usize] as char)
+                .collect(),
+        );
+        stored.push(RecoveryCode::new(&raw));
+
+        let mut shown = Zeroizing::new(String::with_capacity(CODE_CHARS + 2));
+        for (i, c) in raw.chars().enumerate() {
+            if i > 0 && i % GROUP == 0 {
+                shown.push('-');
+            }
+            shown.push(c);
+        }
+        codes.push(shown);
+    }
+    (codes, stored)
+}
+
+/// Index of the stored code matching `code`. Every stored code is checked, so
+/// the time taken does not reveal which one matched.
+pub fn find(stored: &[RecoveryCode], code: &str) -> Option<usize> {
+    let code = normalize(code)?;
+    let mut matched = None;
+    for (i, candidate) in stored.iter().enumerate() {
+        let hash = digest(&candidate.salt, &code);
+        if bool::from(hash.ct_eq(&candidate.hash)) && matched.is_none() {
+            matched = Some(i);
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_codes_match_once_each() {
+        let (codes, stored) = generate(RECOVERY_CODES);
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        for (i, code) in codes.iter().enumerate() {
+            assert_eq!(code.len(), 14);
+            assert_eq!(code.matches('-').count(), 2);
+            assert_eq!(find(&stored, code), Some(i));
+        }
+        assert_ne!(stored[0].salt, stored[1].salt);
+        assert_eq!(find(&stored, "0000-0000-0000"), None);
+        assert_eq!(find(&stored, "123456"), None);
+    }
+
+    #[test]
+    fn typing_is_forgiving() {
+        let (codes, stored) = generate(1);
+        let typed = codes[0].to_uppercase().replace('-', " ");
+        assert_eq!(find(&stored, &typed), Some(0));
+
+        let stored = vec![RecoveryCode::new("0110abcdwxyz")];
+        assert_eq!(find(&stored, "OIL0-ABCD-WXYZ"), Some(0));
+    }
+
+    #[test]
+    fn stores_only_hashes() {
+        let (codes, stored) = generate(1);
+        let json = serde_json::to_string(&stored).unwrap();
+        assert!(!json.contains(&codes[0].replace('-', "")));
+        let back: Vec<RecoveryCode> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, stored);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{
+    error::{self, Error},
+    memory,
+};
+
+/// Shortest key stored without `allow_short`: RFC 4226 section 4 requires at
+/// least 128 bits.
+pub const MIN_SECRET_LEN: usize = 16;
+/// RFC 4226 recommends 160 bits, the output size of HMAC-SHA1.
+pub const RECOMMENDED_SECRET_LEN: usize = 20;
+
+/// Shared OTP secret. The bytes are locked in RAM, wiped when the value is
+/// dropped and never show up in `Debug` output.
+#[derive(Default)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Secret {
+        memory::lock(&bytes);
+        Secret(bytes)
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret::new(self.0.clone())
+    }
+}
+
+impl Zeroize for Secret {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // `Vec::zeroize` also clears the spare capacity and truncates, so
+        // unlock the original span first.
+        memory::unlock(&self.0);
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for Secret {}
+
+impl From<Vec<u8>> for Secret {
+    fn from(bytes: Vec<u8>) -> Secret {
+        Secret::new(bytes)
+    }
+}
+
+impl From<&[u8]> for Secret {
+    fn from(bytes: &[u8]) -> Secret {
+        Secret::new(bytes.to_vec())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([REDACTED; {} bytes])", self.0.len())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&Zeroizing::new(hex::encode(&self.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        hex::decode(&*encoded)
+            .map(Secret::new)
+            .map_err(|_| de::Error::custom("secret is not valid hexadecimal"))
+    }
+}
+
+/// Passphrase read from the environment, a prompt or a file. Held the same way
+/// as `Secret`: locked, redacted and wiped on drop.
+#[derive(Default)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn new(passphrase: String) -> Passphrase {
+        memory::lock(passphrase.as_bytes());
+        Passphrase(passphrase)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Passphrase {
+        Passphrase::new(passphrase)
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        memory::unlock(self.0.as_bytes());
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase([REDACTED])")
+    }
+}
+
+/// How a key given to `-g` is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// Hexadecimal or base32 text, or raw bytes when the input is not text.
+    #[default]
+    Auto,
+    Hex,
+    /// RFC 4648 base32, any case, with optional padding and spaces.
+    Base32,
+    /// The bytes as they are, nothing trimmed.
+    Raw,
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KeyFormat::Auto => "auto",
+            KeyFormat::Hex => "hex",
+            KeyFormat::Base32 => "base32",
+            KeyFormat::Raw => "raw",
+        })
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> error::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(KeyFormat::Auto),
+            "hex" => Ok(KeyFormat::Hex),
+            "base32" => Ok(KeyFormat::Base32),
+            "raw" => Ok(KeyFormat::Raw),
+            _ => Err(Error::InvalidParams(format!(
+                "unknown key format '{}': expected auto, hex, base32 or raw.",
+                s
+            ))),
+        }
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidSecret(message)
+}
+
+/// The text with every whitespace removed, which also drops a trailing newline.
+fn compact(data: &[u8]) -> error::Result<Zeroizing<String>> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| invalid("key is not text, use the raw format for binary keys.".to_string()))?;
+    Ok(Zeroizing::new(
+        text.chars().filter(|c| !c.is_whitespace()).collect(),
+    ))
+}
+
+fn decode_hex(text: &str) -> error::Result<Vec<u8>> {
+    if let Some((position, c)) = text.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        return Err(invalid(format!(
+            "'{}' at position {} is not a hexadecimal digit.",
+            c,
+            position + 1
+        )));
+    }
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid(format!(
+            "hexadecimal key has an odd number of digits ({}).",
+            text.len()
+        )));
+    }
+    hex::decode(text).map_err(|e| invalid(format!("invalid hexadecimal key: {}.", e)))
+}
+
+fn decode_base32(text: &str) -> error::Result<Vec<u8>> {
+    let data = text.trim_end_matches('=');
+    if let Some((position, c)) = data
+        .char_indices()
+        .find(|(_, c)| !matches!(c.to_ascii_uppercase(), 'A'..='Z' | '2'..='7'))
+    {
+        return Err(invalid(format!(
+            "'{}' at position {} is not a base32 character.",
+            c,
+            position + 1
+        )));
+    }
+    let padding = text.len() - data.len();
+    if padding > 0 && (padding > 6 || !text.len().is_multiple_of(8)) {
+        return Err(invalid("base32 key has misplaced padding.".to_string()));
+    }
+    // 1, 3 and 6 trailing characters cannot come out of any whole number of bytes.
+    if matches!(data.len() % 8, 1 | 3 | 6) {
+        return Err(invalid(format!(
+            "base32 key has an impossible length ({} characters).",
+            data.len()
+        )));
+    }
+    let upper = Zeroizing::new(data.to_ascii_uppercase());
+    base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &upper)
+        .ok_or_else(|| invalid("invalid base32 key.".to_string()))
+}
+
+fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+}
+
+/// Decodes a key given in `format` and checks it is long enough. The result
+/// holds the key bytes themselves, whatever the input encoding.
+pub fn parse_key(data: &[u8], format: KeyFormat) -> error::Result<Secret> {
+    let bytes = match format {
+        KeyFormat::Raw => data.to_vec(),
+        KeyFormat::Hex => decode_hex(&compact(data)?)?,
+        KeyFormat::Base32 => decode_base32(&compact(data)?)?,
+        KeyFormat::Auto if !is_text(data) => data.to_vec(),
+        KeyFormat::Auto => {
+            let text = compact(data)?;
+            let is_hex = text.chars().all(|c| c.is_ascii_hexdigit());
+            if is_hex && text.len().is_multiple_of(2) {
+                decode_hex(&text)?
+            } else {
+                decode_base32(&text).map_err(|e| match e {
+                    // Odd-length hexadecimal is the likelier mistake.
+                    _ if is_hex => invalid(format!(
+                        "hexadecimal key has an odd number of digits ({}).",
+                        text.len()
+                    )),
+                    Error::InvalidSecret(why) => {
+                        invalid(format!("key is neither hexadecimal nor base32: {}", why))
+                    }
+                    e => e,
+                })?
+            }
+        }
+    };
+
+    let secret = Secret::new(bytes);
+    if secret.is_empty() {
+        return Err(invalid("key is empty.".to_string()));
+    }
+    Ok(secret)
+}
+
+/// The length rule for a secret of `len` bytes about to be stored, whether
+/// it was read from a key file, an otpauth URI or a migration payload. Keys
+/// shorter than `MIN_SECRET_LEN` are refused unless `allow_short`, as many
+/// providers still hand out 80-bit ones; anything shorter than
+/// `RECOMMENDED_SECRET_LEN` comes back with a warning.
+pub fn check_length(len: usize, allow_short: bool) -> error::Result<Option<String>> {
+    if len == 0 {
+        return Err(invalid("key is empty.".to_string()));
+    }
+    if len < MIN_SECRET_LEN && !allow_short {
+        return Err(invalid(format!(
+            "key is {} bits long, RFC 4226 requires at least {} ({} recommended).",
+            len * 8,
+            MIN_SECRET_LEN * 8,
+            RECOMMENDED_SECRET_LEN * 8
+        )));
+    }
+    Ok((len < RECOMMENDED_SECRET_LEN).then(|| {
+        format!(
+            "{} bit key, RFC 4226 recommends at least {}.",
+            len * 8,
+            RECOMMENDED_SECRET_LEN * 8
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"12345678901234567890";
+
+    fn parse(data: &str, format: KeyFormat) -> error::Result<Vec<u8>> {
+        parse_key(data.as_bytes(), format).map(|secret| secret.expose().to_vec())
+    }
+
+    #[test]
+    fn accepts_hex() {
+        let hex = "3132333435363738393031323334353637383930";
+        assert_eq!(parse(hex, KeyFormat::Auto).unwrap(), KEY);
+        assert_eq!(parse(&format!("{}\n", hex), KeyFormat::Auto).unwrap(), KEY);
+        assert_eq!(parse(&hex.to_uppercase(), KeyFormat::Hex).unwrap(), KEY);
+        assert_eq!(
+            parse(
+                "31323334 35363738 39303132 33343536 37383930",
+                KeyFormat::Auto
+            )
+            .unwrap(),
+            KEY
+        );
+    }
+
+    #[test]
+    fn accepts_base32() {
+        let base32 = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(parse(base32, KeyFormat::Auto).unwrap(), KEY);
+        assert_eq!(
+            parse("gezd gnbv gy3t qojq gezd gnbv gy3t qojq\n", KeyFormat::Auto).unwrap(),
+            KEY
+        );
+        // 17 bytes need padding to a multiple of 8 characters.
+        let padded = "GEZDGNBVGY3TQOJQGEZDGNBVGY3Q====";
+        assert_eq!(parse(padded, KeyFormat::Base32).unwrap(), &KEY[..17]);
+        assert_eq!(parse(&padded[..28], KeyFormat::Auto).unwrap(), &KEY[..17]);
+    }
+
+    #[test]
+    fn accepts_raw_bytes() {
+        let binary: Vec<u8> = (0..32).map(|i| i * 8).collect();
+        assert_eq!(
+            parse_key(&binary, KeyFormat::Auto).unwrap().expose(),
+            binary.as_slice()
+        );
+        // Forced raw keeps every byte, newline included.
+        assert_eq!(
+            parse("1234567890123456\n", KeyFormat::Raw).unwrap(),
+            b"1234567890123456\n"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let error = |data: &str, format| parse(data, format).unwrap_err().to_string();
+
+        assert!(error("31323", KeyFormat::Hex).contains("odd number of digits"));
+        assert!(
+            error("3132333435363738393031323334353637383", KeyFormat::Auto)
+                .contains("odd number of digits")
+        );
+        assert!(error("zz", KeyFormat::Hex).contains("'z' at position 1"));
+        assert!(error("GEZDGNBVGY3TQOJ1", KeyFormat::Base32).contains("'1' at position 16"));
+        assert!(
+            error("not a key, surely!", KeyFormat::Auto).contains("neither hexadecimal nor base32")
+        );
+        assert!(error("GEZDGNBVG", KeyFormat::Base32).contains("impossible length"));
+        assert!(error("GEZDGNBVGY3TQOJQGE=ZDGNBV", KeyFormat::Base32).contains("position 19"));
+        assert!(error("GEZDGNBVGY3TQOJQGEZDGNBVGY3Q==", KeyFormat::Base32).contains("padding"));
+        assert!(error("  \n", KeyFormat::Auto).contains("empty"));
+    }
+
+    #[test]
+    fn enforces_the_minimum_length() {
+        // 16 base32 characters, as many providers hand out, are only 80 bits.
+        let short = parse("GEZDGNBVGY3TQOJQ", KeyFormat::Auto).unwrap();
+        let error = check_length(short.len(), false).unwrap_err();
+        assert!(error.to_string().contains("80 bits"));
+        let warning = check_length(short.len(), true).unwrap().unwrap();
+        assert!(warning.contains("80 bit key"));
+
+        assert!(check_length(15, false).is_err());
+        assert!(check_length(16, false).unwrap().is_some());
+        assert_eq!(check_length(RECOMMENDED_SECRET_LEN, false).unwrap(), None);
+    }
+}
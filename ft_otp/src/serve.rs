@@ -0,0 +1,828 @@
+//! `ft_otp serve`: enroll, verify and revoke over HTTP/JSON, for other
+//! services on the same machine. It keeps keys in the per-user files the PAM
+//! module reads, so whoever can reach it can enroll anyone.
+//!
+//! By default it listens on a Unix socket only its owner and group can open.
+//! A loopback port needs `--token-file`: every request must then carry
+//! `Authorization: Bearer TOKEN`, and answers 401 otherwise.
+//!
+//! ```text
+//! POST /users/USER/enroll  {"issuer": "wiki", "force": false}
+//!     201 {"user": "USER", "secret": "BASE32", "uri": "otpauth://...",
+//!          "recovery_codes": ["7k2m-q9xd-4rwp", ...]}
+//! POST /users/USER/verify  {"code": "123456"}
+//!     200 {"valid": true}
+//!     200 {"valid": true, "recovery_codes_left": 9}
+//!     200 {"valid": false, "reason": "invalid", "attempts_left": 4}
+//! POST /users/USER/recovery
+//!     200 {"recovery_codes": [...]}
+//! POST /users/USER/revoke
+//!     200 {"revoked": true}
+//! ```
+//!
+//! Verify takes a recovery code in place of the current code; it is used up.
+//!
+//! Errors answer `{"error": {"code": "...", "message": "..."}}`. Verification
+//! attempts are rate limited per user, and too many failures in a row lock the
+//! user out for a while; both answer 429 with a `Retry-After` header.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tiny_http::{Header, Response, Server};
+use zeroize::Zeroizing;
+
+use subtle::ConstantTimeEq;
+
+use ft_otp::{
+    Clock, Entry, KeyFile, Passphrase, Secret, TotpParams,
+    login::{self, Outcome},
+    otpauth,
+    secret::RECOMMENDED_SECRET_LEN,
+    storage, totp,
+};
+
+use crate::{ServeArgs, TimeArgs};
+
+const MAX_BODY: usize = 64 * 1024;
+
+pub const DEFAULT_SOCKET: &str = "/run/ft_otp-serve.sock";
+
+/// Users idle for this long are forgotten, along with their failures, unless
+/// they are still locked out.
+const FORGET_AFTER: u64 = 3600;
+
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "http://{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Verification attempts allowed per user and minute.
+    pub attempts_per_minute: u32,
+    /// Failed verifications in a row before a lockout.
+    pub max_failures: u32,
+    /// Length of a lockout, in seconds.
+    pub lockout: u64,
+}
+
+#[derive(Debug, Default)]
+struct Attempts {
+    window_start: u64,
+    count: u32,
+    failures: u32,
+    locked_until: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Refusal {
+    RateLimited { retry_after: u64 },
+    LockedOut { retry_after: u64 },
+}
+
+/// Attempts and failures per user, kept in memory only: a restart lifts
+/// every lockout.
+struct Limiter {
+    limits: Limits,
+    users: HashMap<String, Attempts>,
+}
+
+impl Limiter {
+    fn new(limits: Limits) -> Limiter {
+        Limiter {
+            limits,
+            users: HashMap::new(),
+        }
+    }
+
+    /// Counts an attempt by `user`, or says why it is refused.
+    fn attempt(&mut self, user: &str, now: u64) -> Result<(), Refusal> {
+        self.users.retain(|_, attempts| {
+            attempts.locked_until > now || now < attempts.window_start + FORGET_AFTER
+        });
+
+        let attempts = self.users.entry(user.to_string()).or_default();
+        if attempts.locked_until > now {
+            return Err(Refusal::LockedOut {
+                retry_after: attempts.locked_until - now,
+            });
+        }
+        if now >= attempts.window_start + 60 {
+            attempts.window_start = now;
+            attempts.count = 0;
+        }
+        if attempts.count >= self.limits.attempts_per_minute {
+            return Err(Refusal::RateLimited {
+                retry_after: attempts.window_start + 60 - now,
+            });
+        }
+        attempts.count += 1;
+        Ok(())
+    }
+
+    /// Records the outcome of an attempt and returns how many failures are
+    /// left before a lockout.
+    fn record(&mut self, user: &str, success: bool, now: u64) -> u32 {
+        let attempts = self.users.entry(user.to_string()).or_default();
+        if success {
+            attempts.failures = 0;
+            return self.limits.max_failures;
+        }
+        attempts.failures += 1;
+        if attempts.failures >= self.limits.max_failures {
+            attempts.failures = 0;
+            attempts.locked_until = now + self.limits.lockout;
+            return 0;
+        }
+        self.limits.max_failures - attempts.failures
+    }
+
+    fn forget(&mut self, user: &str) {
+        self.users.remove(user);
+    }
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub body: Value,
+    pub retry_after: Option<u64>,
+}
+
+impl Reply {
+    fn new(status: u16, body: Value) -> Reply {
+        Reply {
+            status,
+            body,
+            retry_after: None,
+        }
+    }
+
+    fn error(status: u16, code: &str, message: impl Into<String>) -> Reply {
+        Reply::new(
+            status,
+            json!({ "error": { "code": code, "message": message.into() } }),
+        )
+    }
+}
+
+impl From<Refusal> for Reply {
+    fn from(refusal: Refusal) -> Reply {
+        let (code, message, retry_after) = match refusal {
+            Refusal::RateLimited { retry_after } => {
+                ("rate_limited", "too many attempts, slow down.", retry_after)
+            }
+            Refusal::LockedOut { retry_after } => (
+                "locked_out",
+                "too many failed attempts, try again later.",
+                retry_after,
+            ),
+        };
+        let mut reply = Reply::error(429, code, message);
+        reply.body["error"]["retry_after"] = json!(retry_after);
+        reply.retry_after = Some(retry_after);
+        reply
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EnrollBody {
+    #[serde(default)]
+    force: bool,
+    issuer: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifyBody {
+    code: String,
+}
+
+/// An empty body reads as `{}`.
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Reply> {
+    let body = if body.iter().all(u8::is_ascii_whitespace) {
+        b"{}".as_slice()
+    } else {
+        body
+    };
+    serde_json::from_slice(body).map_err(|e| Reply::error(400, "bad_request", e.to_string()))
+}
+
+fn not_enrolled(user: &str) -> Reply {
+    Reply::error(404, "not_enrolled", format!("'{}' has no key.", user))
+}
+
+fn recovery_list(codes: &[Zeroizing<String>]) -> Vec<&str> {
+    codes.iter().map(|code| code.as_str()).collect()
+}
+
+/// What `ft_otp serve` was started with.
+pub struct Settings {
+    /// Key path template, as given to the PAM module with `file=`.
+    pub key_path: String,
+    /// System passphrase the key files are encrypted under.
+    pub passphrase: Passphrase,
+    /// Parameters of newly enrolled keys.
+    pub params: TotpParams,
+    /// Recovery codes handed out at enrollment and by `recovery`.
+    pub recovery_codes: usize,
+    pub window: u64,
+    pub limits: Limits,
+    /// Bearer token every request must carry, if any.
+    pub token: Option<Passphrase>,
+}
+
+pub struct Service {
+    key_path: String,
+    passphrase: Passphrase,
+    params: TotpParams,
+    recovery_codes: usize,
+    window: u64,
+    limiter: Limiter,
+    token: Option<Passphrase>,
+    clock: Box<dyn Clock>,
+    log: Box<dyn Write + Send>,
+}
+
+impl Service {
+    pub fn new(settings: Settings, clock: Box<dyn Clock>, log: Box<dyn Write + Send>) -> Service {
+        Service {
+            key_path: settings.key_path,
+            passphrase: settings.passphrase,
+            params: settings.params,
+            recovery_codes: settings.recovery_codes,
+            window: settings.window,
+            limiter: Limiter::new(settings.limits),
+            token: settings.token,
+            clock,
+            log,
+        }
+    }
+
+    fn log_line(&mut self, op: &str, user: &str, outcome: &str) {
+        let _ = writeln!(self.log, "{} {} {} {}", totp::now(), op, user, outcome);
+    }
+
+    fn path(&self, user: &str) -> Result<PathBuf, Reply> {
+        login::key_path(&self.key_path, user, || login::home_dir(user))
+            .map_err(|e| Reply::error(400, "invalid_user", e.to_string()))
+    }
+
+    /// Details stay in the server log, the client only learns it failed.
+    fn internal(&mut self, op: &str, user: &str, error: ft_otp::Error) -> Reply {
+        self.log_line(op, user, &format!("error: {}", error));
+        Reply::error(500, "internal", "internal error, see the server log.")
+    }
+
+    fn enroll(&mut self, user: &str, body: &[u8]) -> Result<Reply, Reply> {
+        let body: EnrollBody = parse(body)?;
+        let path = self.path(user)?;
+        if path.exists() && !body.force {
+            return Err(Reply::error(
+                409,
+                "already_enrolled",
+                format!("'{}' already has a key, set \"force\" to replace it.", user),
+            ));
+        }
+
+        let mut secret = vec![0u8; RECOMMENDED_SECRET_LEN];
+        OsRng.fill_bytes(&mut secret);
+        let mut key = KeyFile::new(Secret::new(secret), self.params.clone());
+        let codes = key.regenerate_recovery_codes(self.recovery_codes);
+        if let Err(e) = login::enroll(&path, user, self.passphrase.expose(), &key, body.force) {
+            return Err(self.internal("enroll", user, e));
+        }
+        self.limiter.forget(user);
+        self.log_line("enroll", user, "ok");
+
+        let secret = Zeroizing::new(otpauth::encode_secret(key.secret.expose()));
+        let entry = Entry {
+            label: user.to_string(),
+            issuer: Some(body.issuer.unwrap_or_else(|| "ft_otp".to_string())),
+            key,
+            tags: Vec::new(),
+            icon: None,
+        };
+        let uri = otpauth::to_uri(&entry);
+        Ok(Reply::new(
+            201,
+            json!({
+                "user": user,
+                "secret": secret.as_str(),
+                "uri": uri.as_str(),
+                "recovery_codes": recovery_list(&codes),
+            }),
+        ))
+    }
+
+    fn recovery(&mut self, user: &str) -> Result<Reply, Reply> {
+        let path = self.path(user)?;
+        let passphrase = self.passphrase.expose();
+        match login::regenerate_recovery_codes(&path, user, passphrase, self.recovery_codes) {
+            Ok(Some(codes)) => {
+                self.log_line("recovery", user, "ok");
+                Ok(Reply::new(
+                    200,
+                    json!({ "recovery_codes": recovery_list(&codes) }),
+                ))
+            }
+            Ok(None) => Err(not_enrolled(user)),
+            Err(e) => Err(self.internal("recovery", user, e)),
+        }
+    }
+
+    fn verify(&mut self, user: &str, body: &[u8]) -> Result<Reply, Reply> {
+        let body: VerifyBody = parse(body)?;
+        let path = self.path(user)?;
+        let now = self.clock.now();
+        if let Err(refusal) = self.limiter.attempt(user, now) {
+            self.log_line("verify", user, "refused");
+            return Err(refusal.into());
+        }
+
+        let outcome = login::verify(
+            &path,
+            user,
+            self.passphrase.expose(),
+            &body.code,
+            self.window,
+            self.clock.as_ref(),
+        );
+        let reason = match outcome {
+            Ok(Outcome::Accepted) => {
+                self.limiter.record(user, true, now);
+                self.log_line("verify", user, "accepted");
+                return Ok(Reply::new(200, json!({ "valid": true })));
+            }
+            Ok(Outcome::Recovered { remaining }) => {
+                self.limiter.record(user, true, now);
+                self.log_line("verify", user, "recovered");
+                return Ok(Reply::new(
+                    200,
+                    json!({ "valid": true, "recovery_codes_left": remaining }),
+                ));
+            }
+            Ok(Outcome::Rejected) => "invalid",
+            Ok(Outcome::Replayed) => "replayed",
+            Ok(Outcome::NotEnrolled) => return Err(not_enrolled(user)),
+            Err(e) => return Err(self.internal("verify", user, e)),
+        };
+        let attempts_left = self.limiter.record(user, false, now);
+        self.log_line("verify", user, reason);
+        Ok(Reply::new(
+            200,
+            json!({ "valid": false, "reason": reason, "attempts_left": attempts_left }),
+        ))
+    }
+
+    fn revoke(&mut self, user: &str) -> Result<Reply, Reply> {
+        let path = self.path(user)?;
+        match login::revoke(&path, user) {
+            Ok(true) => {
+                self.limiter.forget(user);
+                self.log_line("revoke", user, "ok");
+                Ok(Reply::new(200, json!({ "revoked": true })))
+            }
+            Ok(false) => Err(not_enrolled(user)),
+            Err(e) => Err(self.internal("revoke", user, e)),
+        }
+    }
+
+    /// Checks the `Authorization` header against the token, when there is
+    /// one, before anything else is looked at.
+    pub fn authorize(&mut self, authorization: Option<&str>) -> Result<(), Reply> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let given = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if bool::from(given.as_bytes().ct_eq(token.expose().as_bytes())) {
+            return Ok(());
+        }
+        self.log_line("authorize", "-", "refused");
+        Err(Reply::error(
+            401,
+            "unauthorized",
+            "missing or wrong bearer token.",
+        ))
+    }
+
+    /// Answers one request. Neither codes nor secrets are logged.
+    pub fn handle(&mut self, method: &str, url: &str, body: &[u8]) -> Reply {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let ["users", user, action] = segments.as_slice() else {
+            return Reply::error(404, "not_found", format!("no route for {}.", path));
+        };
+        if !matches!(*action, "enroll" | "verify" | "recovery" | "revoke") {
+            return Reply::error(404, "not_found", format!("no route for {}.", path));
+        }
+        if method != "POST" {
+            return Reply::error(405, "method_not_allowed", format!("{} needs POST.", path));
+        }
+        let user = match percent_decode_str(user).decode_utf8() {
+            Ok(user) => user.into_owned(),
+            Err(_) => return Reply::error(400, "invalid_user", "user names must be UTF-8."),
+        };
+
+        let result = match *action {
+            "enroll" => self.enroll(&user, body),
+            "verify" => self.verify(&user, body),
+            "recovery" => self.recovery(&user),
+            _ => self.revoke(&user),
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn read_body(request: &mut tiny_http::Request) -> Result<Zeroizing<Vec<u8>>, Reply> {
+    let too_large = || Reply::error(413, "body_too_large", "request body is too large.");
+    if request
+        .body_length()
+        .is_some_and(|length| length > MAX_BODY)
+    {
+        return Err(too_large());
+    }
+    let mut body = Zeroizing::new(Vec::new());
+    request
+        .as_reader()
+        .take(MAX_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| Reply::error(400, "bad_request", e.to_string()))?;
+    if body.len() > MAX_BODY {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+fn respond(request: tiny_http::Request, reply: Reply) -> io::Result<()> {
+    let body = serde_json::to_vec(&reply.body)?;
+    let mut response = Response::from_data(body)
+        .with_status_code(reply.status)
+        .with_header(header("Content-Type", "application/json"));
+    if let Some(seconds) = reply.retry_after {
+        response.add_header(header("Retry-After", &seconds.to_string()));
+    }
+    request.respond(response)
+}
+
+/// Creates the socket for its owner and group, so access can be granted to
+/// the apps' group. A leftover socket from a dead server is replaced,
+/// anything else at that path is left alone.
+///
+/// The socket is bound in a directory only we can enter and moved into place
+/// once its mode is set, so nobody connects through the mode it was born with.
+fn bind_unix(socket: &Path) -> anyhow::Result<Server> {
+    if UnixStream::connect(socket).is_ok() {
+        anyhow::bail!("a server is already listening on {}.", socket.display());
+    }
+    storage::remove_stale_socket(socket)?;
+
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let private = dir.join(format!(".ft_otp-{:08x}", OsRng.next_u32()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let listener = (|| {
+        let bound = private.join("s");
+        let listener = UnixListener::bind(&bound)?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o660))?;
+        fs::rename(&bound, socket)?;
+        Ok::<_, io::Error>(listener)
+    })();
+    let _ = fs::remove_dir_all(&private);
+    let listener =
+        listener.map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", socket.display(), e))?;
+    Server::from_listener(listener, None)
+        .map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", socket.display(), e))
+}
+
+/// Serves requests one at a time until the process is stopped.
+pub fn serve(mut service: Service, listen: &Listen) -> anyhow::Result<()> {
+    let server = match listen {
+        Listen::Tcp(addr) => {
+            if service.token.is_none() {
+                anyhow::bail!(
+                    "refusing to listen on {} without --token-file: any local user could enroll anyone.",
+                    addr
+                );
+            }
+            if !addr.ip().is_loopback() {
+                anyhow::bail!(
+                    "refusing to listen on {}, only loopback addresses are allowed.",
+                    addr
+                );
+            }
+            Server::http(addr).map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", addr, e))?
+        }
+        Listen::Unix(socket) => bind_unix(socket)?,
+    };
+
+    match server.server_addr().to_ip() {
+        Some(addr) => eprintln!("ft_otp serve listening on {}", Listen::Tcp(addr)),
+        None => eprintln!("ft_otp serve listening on {}", listen),
+    }
+    for mut request in server.incoming_requests() {
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| Zeroizing::new(header.value.as_str().to_string()));
+        let reply = match service.authorize(authorization.as_deref().map(String::as_str)) {
+            Ok(()) => match read_body(&mut request) {
+                Ok(body) => service.handle(request.method().as_str(), request.url(), &body),
+                Err(reply) => reply,
+            },
+            Err(reply) => reply,
+        };
+        if let Err(e) = respond(request, reply) {
+            eprintln!("client error: {}", e);
+        }
+    }
+    Ok(())
+}
+
+pub fn command(args: ServeArgs, time: &TimeArgs) -> anyhow::Result<()> {
+    if args.params.hotp {
+        anyhow::bail!("the service only enrolls time-based keys.");
+    }
+    let settings = Settings {
+        key_path: args.file,
+        passphrase: login::read_passphrase(&args.system_passphrase_file)?,
+        params: args.params.to_params()?,
+        recovery_codes: args.recovery.recovery_codes,
+        window: args.window,
+        limits: Limits {
+            attempts_per_minute: args.attempts_per_minute,
+            max_failures: args.max_failures,
+            lockout: args.lockout,
+        },
+        token: args
+            .token_file
+            .as_deref()
+            .map(login::read_passphrase)
+            .transpose()?,
+    };
+    let listen = match args.listen {
+        Some(addr) => Listen::Tcp(addr),
+        None => Listen::Unix(args.socket),
+    };
+    let service = Service::new(
+        settings,
+        Box::new(time.clock()?),
+        Box::new(std::io::stderr()),
+    );
+    serve(service, &listen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ft_otp::FixedClock;
+    use std::{env, sync::Arc};
+
+    const LIMITS: Limits = Limits {
+        attempts_per_minute: 5,
+        max_failures: 3,
+        lockout: 300,
+    };
+
+    fn service(name: &str) -> (Service, PathBuf, Arc<FixedClock>) {
+        let dir = env::temp_dir().join(format!("ft_otp-serve-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let clock = Arc::new(FixedClock::new(1_000_000));
+        let settings = Settings {
+            key_path: format!("{}/%u.key", dir.display()),
+            passphrase: Passphrase::new("system".into()),
+            params: TotpParams::default(),
+            recovery_codes: 3,
+            window: 1,
+            limits: LIMITS,
+            token: None,
+        };
+        let service = Service::new(settings, Box::new(clock.clone()), Box::new(io::sink()));
+        (service, dir, clock)
+    }
+
+    fn code(reply: &Reply, time: u64) -> String {
+        let secret = otpauth::decode_secret(reply.body["secret"].as_str().unwrap()).unwrap();
+        totp::totp_at(&secret, &TotpParams::default(), time).unwrap()
+    }
+
+    fn verify(service: &mut Service, user: &str, code: &str) -> Reply {
+        let body = json!({ "code": code }).to_string();
+        service.handle("POST", &format!("/users/{}/verify", user), body.as_bytes())
+    }
+
+    #[test]
+    fn limits_attempts_per_minute() {
+        let mut limiter = Limiter::new(Limits {
+            attempts_per_minute: 2,
+            ..LIMITS
+        });
+        assert_eq!(limiter.attempt("alice", 100), Ok(()));
+        assert_eq!(limiter.attempt("alice", 110), Ok(()));
+        assert_eq!(
+            limiter.attempt("alice", 130),
+            Err(Refusal::RateLimited { retry_after: 30 })
+        );
+        // Other users have their own budget.
+        assert_eq!(limiter.attempt("bob", 130), Ok(()));
+        assert_eq!(limiter.attempt("alice", 160), Ok(()));
+    }
+
+    #[test]
+    fn locks_out_after_failures_in_a_row() {
+        let mut limiter = Limiter::new(LIMITS);
+        assert_eq!(limiter.record("alice", false, 100), 2);
+        assert_eq!(limiter.record("alice", true, 100), 3);
+        assert_eq!(limiter.record("alice", false, 100), 2);
+        assert_eq!(limiter.record("alice", false, 100), 1);
+        assert_eq!(limiter.record("alice", false, 100), 0);
+        assert_eq!(
+            limiter.attempt("alice", 150),
+            Err(Refusal::LockedOut { retry_after: 250 })
+        );
+        assert_eq!(limiter.attempt("alice", 400), Ok(()));
+
+        limiter.record("bob", false, 100);
+        limiter.attempt("bob", 100).unwrap();
+        limiter.attempt("carol", 100 + FORGET_AFTER).unwrap();
+        assert!(!limiter.users.contains_key("bob"));
+    }
+
+    #[test]
+    fn enrolls_verifies_and_revokes() {
+        let (mut service, dir, clock) = service("flow");
+        let enrolled = service.handle("POST", "/users/alice/enroll", br#"{"issuer": "wiki"}"#);
+        assert_eq!(enrolled.status, 201, "{}", enrolled.body);
+        assert!(
+            enrolled.body["uri"]
+                .as_str()
+                .unwrap()
+                .starts_with("otpauth://totp/wiki:alice?")
+        );
+        assert_eq!(
+            service.handle("POST", "/users/alice/enroll", b"").status,
+            409
+        );
+
+        let now = clock.now();
+        let reply = verify(&mut service, "alice", &code(&enrolled, now));
+        assert_eq!(reply.body, json!({ "valid": true }));
+        let reply = verify(&mut service, "alice", &code(&enrolled, now));
+        assert_eq!(reply.body["reason"], "replayed");
+        assert_eq!(reply.body["attempts_left"], 2);
+
+        let recovery = enrolled.body["recovery_codes"].as_array().unwrap();
+        assert_eq!(recovery.len(), 3);
+        let reply = verify(&mut service, "alice", recovery[0].as_str().unwrap());
+        assert_eq!(
+            reply.body,
+            json!({ "valid": true, "recovery_codes_left": 2 })
+        );
+        let renewed = service.handle("POST", "/users/alice/recovery", b"");
+        assert_eq!(renewed.body["recovery_codes"].as_array().unwrap().len(), 3);
+        let reply = verify(&mut service, "alice", recovery[1].as_str().unwrap());
+        assert_eq!(reply.body["valid"], false);
+
+        let revoked = service.handle("POST", "/users/alice/revoke", b"");
+        assert_eq!(revoked.body, json!({ "revoked": true }));
+        let reply = verify(&mut service, "alice", &code(&enrolled, now + 30));
+        assert_eq!(reply.status, 404);
+        assert_eq!(reply.body["error"]["code"], "not_enrolled");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn locked_out_users_are_refused_even_with_a_good_code() {
+        let (mut service, dir, clock) = service("lockout");
+        let enrolled = service.handle("POST", "/users/alice/enroll", b"");
+        let now = clock.now();
+        let wrong = if code(&enrolled, now) == "000000" {
+            "111111"
+        } else {
+            "000000"
+        };
+        for _ in 0..LIMITS.max_failures {
+            assert_eq!(verify(&mut service, "alice", wrong).status, 200);
+        }
+
+        let reply = verify(&mut service, "alice", &code(&enrolled, now));
+        assert_eq!(reply.status, 429);
+        assert_eq!(reply.body["error"]["code"], "locked_out");
+        assert_eq!(reply.retry_after, Some(LIMITS.lockout));
+
+        clock.advance(LIMITS.lockout);
+        let reply = verify(
+            &mut service,
+            "alice",
+            &code(&enrolled, now + LIMITS.lockout),
+        );
+        assert_eq!(reply.body, json!({ "valid": true }));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_the_bearer_token() {
+        let (mut service, dir, _) = service("token");
+        assert!(service.authorize(None).is_ok());
+
+        service.token = Some(Passphrase::new("s3cret".into()));
+        assert!(service.authorize(Some("Bearer s3cret")).is_ok());
+        for authorization in [
+            None,
+            Some("Bearer s3cre"),
+            Some("bearer s3cret"),
+            Some("s3cret"),
+        ] {
+            let reply = service.authorize(authorization).unwrap_err();
+            assert_eq!(reply.status, 401);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn answers_errors_as_json() {
+        let (mut service, _dir, _) = service("errors");
+        let cases = [
+            (
+                "GET",
+                "/users/alice/verify",
+                "{}",
+                405,
+                "method_not_allowed",
+            ),
+            ("POST", "/users/alice/unlock", "{}", 404, "not_found"),
+            ("POST", "/health", "", 404, "not_found"),
+            ("POST", "/users/alice/verify", "{", 400, "bad_request"),
+            ("POST", "/users/alice/verify", "{}", 400, "bad_request"),
+            (
+                "POST",
+                "/users/alice/enroll",
+                r#"{"admin":1}"#,
+                400,
+                "bad_request",
+            ),
+            ("POST", "/users/..%2Froot/enroll", "", 400, "invalid_user"),
+            ("POST", "/users/alice/revoke", "", 404, "not_enrolled"),
+        ];
+        for (method, url, body, status, code) in cases {
+            let reply = service.handle(method, url, body.as_bytes());
+            assert_eq!(reply.status, status, "{} {}", method, url);
+            assert_eq!(reply.body["error"]["code"], code, "{} {}", method, url);
+            assert!(reply.body["error"]["message"].is_string());
+        }
+    }
+
+    #[test]
+    fn binds_private_sockets_and_leaves_other_files_alone() {
+        let dir = env::temp_dir().join(format!("ft_otp-serve-{}-bind", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let socket = dir.join("serve.sock");
+        let server = bind_unix(&socket).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(bind_unix(&socket).is_err());
+        drop(server);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let file = dir.join("notes");
+        fs::write(&file, b"keep").unwrap();
+        assert!(bind_unix(&file).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"keep");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! Shamir secret sharing over GF(2^8), byte by byte: any `threshold` of the
+//! shares rebuild the secret, fewer reveal nothing about it.
+//!
+//! A share file is `magic | version | threshold | index | set id | data`. The
+//! set id is random per split, so shares of different backups are never mixed.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+
+const MAGIC: &[u8; 4] = b"FOSH";
+const VERSION: u8 = 1;
+const ID_SIZE: usize = 8;
+const HEADER_SIZE: usize = MAGIC.len() + 3 + ID_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub threshold: u8,
+    /// The x coordinate of the share, never 0.
+    pub index: u8,
+    pub id: [u8; ID_SIZE],
+    pub data: Vec<u8>,
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial, without table
+/// lookups or data-dependent branches.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// a^254 is the inverse of a in GF(2^8).
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = mul(result, a);
+    }
+    result
+}
+
+pub fn is_share(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Splits `secret` into `shares` shares, `threshold` of which are needed to
+/// rebuild it.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(Error::InvalidParams(format!(
+            "the threshold must be between 2 and the number of shares ({}).",
+            shares
+        )));
+    }
+
+    let mut id = [0u8; ID_SIZE];
+    OsRng.fill_bytes(&mut id);
+    let mut result: Vec<Share> = (1..=shares)
+        .map(|index| Share {
+            threshold,
+            index,
+            id,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for &byte in secret {
+        coefficients[0] = byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in &mut result {
+            // Horner's rule, highest degree first.
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |y, &c| mul(y, share.index) ^ c);
+            share.data.push(y);
+        }
+    }
+    Ok(result)
+}
+
+/// Rebuilds the secret from at least `threshold` shares of the same split.
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>> {
+    let invalid = |message: &str| Err(Error::InvalidShare(message.to_string()));
+    let Some(first) = shares.first() else {
+        return invalid("no shares given.");
+    };
+    if shares
+        .iter()
+        .any(|s| s.id != first.id || s.threshold != first.threshold)
+    {
+        return invalid("the shares come from different backups.");
+    }
+    if first.threshold < 2 {
+        return invalid("the threshold is below 2.");
+    }
+    if shares.iter().any(|s| s.data.len() != first.data.len()) {
+        return invalid("the shares have different lengths.");
+    }
+    if shares.iter().any(|s| s.index == 0) {
+        return invalid("a share has index 0, which would be the secret itself.");
+    }
+    let mut indices: Vec<u8> = shares.iter().map(|s| s.index).collect();
+    indices.sort_unstable();
+    indices.dedup();
+    if indices.len() != shares.len() {
+        return invalid("the same share was given twice.");
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(Error::InvalidShare(format!(
+            "{} of the shares are needed, got {}.",
+            first.threshold,
+            shares.len()
+        )));
+    }
+
+    // Lagrange interpolation at x = 0, where subtraction is xor.
+    let shares = &shares[..first.threshold as usize];
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            shares
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold(1, |weight, other| {
+                    mul(weight, mul(other.index, inv(other.index ^ share.index)))
+                })
+        })
+        .collect();
+
+    let mut secret = Zeroizing::new(vec![0u8; first.data.len()]);
+    for (share, &weight) in shares.iter().zip(&weights) {
+        for (byte, &y) in secret.iter_mut().zip(&share.data) {
+            *byte ^= mul(y, weight);
+        }
+    }
+    Ok(secret)
+}
+
+impl Share {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.threshold);
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Share> {
+        if !is_share(bytes) || bytes.len() < HEADER_SIZE {
+            return Err(Error::InvalidShare("not a backup share.".to_string()));
+        }
+        if bytes[4] != VERSION {
+            return Err(Error::UnsupportedFormat(format!(
+                "share version {}",
+                bytes[4]
+            )));
+        }
+        let (threshold, index) = (bytes[5], bytes[6]);
+        if threshold < 2 {
+            return Err(Error::InvalidShare(format!(
+                "threshold {} is below 2.",
+                threshold
+            )));
+        }
+        if index == 0 {
+            return Err(Error::InvalidShare("index 0 is not a share.".to_string()));
+        }
+        Ok(Share {
+            threshold,
+            index,
+            id: bytes[7..HEADER_SIZE].try_into().unwrap(),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_arithmetic() {
+        // FIPS 197, section 4.2.
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        assert_eq!(mul(0x57, 0x13), 0xfe);
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn any_threshold_subset_rebuilds_the_secret() {
+        let secret = b"the encrypted backup archive";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        for subset in [[0, 1, 2], [0, 2, 4], [4, 3, 1], [1, 2, 3]] {
+            let picked: Vec<Share> = subset.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&picked).unwrap().as_slice(), secret);
+        }
+        assert_eq!(combine(&shares).unwrap().as_slice(), secret);
+    }
+
+    #[test]
+    fn refuses_bad_share_sets() {
+        let shares = split(b"secret", 2, 3).unwrap();
+        assert!(combine(&shares[..1]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+
+        let other = split(b"secret", 2, 3).unwrap();
+        assert!(combine(&[shares[0].clone(), other[1].clone()]).is_err());
+
+        assert!(split(b"secret", 1, 3).is_err());
+        assert!(split(b"secret", 4, 3).is_err());
+    }
+
+    #[test]
+    fn shares_round_trip_through_bytes() {
+        let shares = split(b"secret", 2, 2).unwrap();
+        let bytes = shares[1].to_bytes();
+        assert!(is_share(&bytes));
+        assert_eq!(Share::from_bytes(&bytes).unwrap(), shares[1]);
+        assert!(Share::from_bytes(b"FOTP").is_err());
+
+        for (offset, value) in [(5, 0), (5, 1), (6, 0)] {
+            let mut forged = bytes.clone();
+            forged[offset] = value;
+            assert!(Share::from_bytes(&forged).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_shares_that_would_reveal_the_data() {
+        let shares = split(b"secret", 2, 3).unwrap();
+        let mut zero = shares[0].clone();
+        zero.index = 0;
+        let error = combine(&[zero, shares[1].clone()]).unwrap_err();
+        assert!(error.to_string().contains("index 0"), "{}", error);
+
+        let lowered: Vec<Share> = shares
+            .iter()
+            .map(|share| Share {
+                threshold: 1,
+                ..share.clone()
+            })
+            .collect();
+        assert!(combine(&lowered[..1]).is_err());
+    }
+}
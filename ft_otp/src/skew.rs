@@ -0,0 +1,255 @@
+//! Local clock skew: measured against an SNTP server (RFC 4330) or a time
+//! given by hand, and corrected with an offset stored next to the keys.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    error::{Error, Result},
+    storage,
+};
+
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+pub const DEFAULT_OFFSET_FILE: &str = "ft_otp.offset";
+
+const NTP_PORT: u16 = 123;
+const PACKET_SIZE: usize = 48;
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_DELTA: f64 = 2_208_988_800.0;
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Where the correct time comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// `host` or `host:port` of an NTP/SNTP server.
+    Sntp(String),
+    /// A Unix time read off another device, taken as the time right now.
+    Time(u64),
+}
+
+fn resolve(server: &str) -> Result<SocketAddr> {
+    let addrs = match server.parse::<SocketAddr>() {
+        Ok(addr) => return Ok(addr),
+        Err(_) if server.contains(':') && !server.contains("::") => server.to_socket_addrs(),
+        Err(_) => (server, NTP_PORT).to_socket_addrs(),
+    };
+    addrs
+        .map_err(|e| Error::TimeSource(format!("cannot resolve {}: {}", server, e)))?
+        .next()
+        .ok_or_else(|| Error::TimeSource(format!("cannot resolve {}.", server)))
+}
+
+fn system_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// NTP short-format timestamps wrap in 2036; era 1 starts over from 0.
+fn from_ntp(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    let fraction = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+    let era = if seconds < 0x8000_0000 { 1u64 << 32 } else { 0 };
+    (era + seconds as u64) as f64 + fraction as f64 / 4_294_967_296.0 - NTP_UNIX_DELTA
+}
+
+fn to_ntp(unix: f64) -> [u8; 8] {
+    let ntp = unix + NTP_UNIX_DELTA;
+    let seconds = ntp.trunc() as u64 as u32;
+    let fraction = (ntp.fract() * 4_294_967_296.0) as u32;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&seconds.to_be_bytes());
+    bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+    bytes
+}
+
+/// Asks `server` for the time and returns how far ahead of the system clock
+/// it is, in seconds.
+fn sntp_offset(server: &str) -> Result<f64> {
+    let addr = resolve(server)?;
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(addr)?;
+
+    // Client request: no leap warning, version 4, mode 3.
+    let mut request = [0u8; PACKET_SIZE];
+    request[0] = 0b00_100_011;
+    let sent = system_seconds();
+    let transmit = to_ntp(sent);
+    request[40..48].copy_from_slice(&transmit);
+    socket.send(&request)?;
+
+    let mut response = [0u8; PACKET_SIZE];
+    let len = socket.recv(&mut response).map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            Error::TimeSource(format!("no answer from {}.", server))
+        }
+        _ => Error::TimeSource(format!("{}: {}", server, e)),
+    })?;
+    let received = system_seconds();
+
+    let invalid = |why: &str| Err(Error::TimeSource(format!("{}: {}", server, why)));
+    if len < PACKET_SIZE {
+        return invalid("short answer.");
+    }
+    let mode = response[0] & 0b111;
+    if mode != 4 && mode != 5 {
+        return invalid("not a server answer.");
+    }
+    if response[1] == 0 {
+        return invalid("the server refused to answer (kiss-o'-death).");
+    }
+    // The server echoes our transmit time, which ties the answer to this request.
+    if response[24..32] != transmit {
+        return invalid("answer does not match the request.");
+    }
+    if response[40..48] == [0; 8] {
+        return invalid("answer carries no time.");
+    }
+
+    let server_received = from_ntp(&response[32..40]);
+    let server_sent = from_ntp(&response[40..48]);
+    Ok(((server_received - sent) + (server_sent - received)) / 2.0)
+}
+
+/// Seconds to add to `clock` to match `reference`, rounded to the second.
+pub fn measure(reference: &Reference, clock: &dyn Clock) -> Result<i64> {
+    let local = clock.now() as i128;
+    let offset = match reference {
+        Reference::Time(time) => *time as i128 - local,
+        Reference::Sntp(server) => {
+            // SNTP is measured against the system clock with sub-second
+            // precision, then moved over to `clock`.
+            let offset = sntp_offset(server)?.round() as i128;
+            SystemClock.now() as i128 + offset - local
+        }
+    };
+    Ok(offset.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+}
+
+/// Whether an offset is large enough to break codes: more than a quarter of
+/// the period, past which a code is often rejected near the step edges.
+pub fn is_significant(offset: i64, period: u64) -> bool {
+    offset.unsigned_abs().saturating_mul(4) > period
+}
+
+/// "12s ahead", "3s behind" or "in sync", describing the local clock.
+pub fn describe(offset: i64) -> String {
+    match offset {
+        0 => "in sync".to_string(),
+        offset if offset < 0 => format!("{}s ahead", offset.unsigned_abs()),
+        offset => format!("{}s behind", offset),
+    }
+}
+
+/// The offset stored by `save_offset`, if any.
+pub fn load_offset(path: &Path) -> Result<Option<i64>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    text.trim().parse().map(Some).map_err(|_| {
+        Error::TimeSource(format!("{}: expected a number of seconds.", path.display()))
+    })
+}
+
+pub fn save_offset(path: &Path, offset: i64) -> Result<()> {
+    storage::write_atomic(path, format!("{}\n", offset).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::thread;
+
+    /// Answers one SNTP request as a server whose clock is `offset` seconds
+    /// ahead of ours, optionally breaking the answer with `tamper`.
+    fn stand_in(offset: f64, tamper: fn(&mut [u8; PACKET_SIZE])) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut request = [0u8; PACKET_SIZE];
+            let (_, client) = socket.recv_from(&mut request).unwrap();
+            let mut response = [0u8; PACKET_SIZE];
+            response[0] = 0b00_100_100;
+            response[1] = 1;
+            response[24..32].copy_from_slice(&request[40..48]);
+            let now = to_ntp(system_seconds() + offset);
+            response[32..40].copy_from_slice(&now);
+            response[40..48].copy_from_slice(&now);
+            tamper(&mut response);
+            socket.send_to(&response, client).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn ntp_timestamps_round_trip() {
+        for unix in [0.0, 1_700_000_000.5, 2_085_978_496.0, 4_000_000_000.25] {
+            assert!((from_ntp(&to_ntp(unix)) - unix).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn measures_against_an_sntp_stand_in() {
+        let server = stand_in(42.0, |_| {});
+        let offset = measure(&Reference::Sntp(server), &SystemClock).unwrap();
+        assert!((41..=43).contains(&offset), "offset {}", offset);
+
+        let server = stand_in(-90.0, |_| {});
+        let offset = measure(&Reference::Sntp(server), &SystemClock).unwrap();
+        assert!((-91..=-89).contains(&offset), "offset {}", offset);
+    }
+
+    #[test]
+    fn refuses_bad_answers() {
+        let unrelated = stand_in(0.0, |response| response[24] ^= 1);
+        assert!(measure(&Reference::Sntp(unrelated), &SystemClock).is_err());
+        let kiss_of_death = stand_in(0.0, |response| response[1] = 0);
+        assert!(measure(&Reference::Sntp(kiss_of_death), &SystemClock).is_err());
+    }
+
+    #[test]
+    fn measures_against_a_given_time() {
+        let clock = FixedClock::new(1_000);
+        assert_eq!(measure(&Reference::Time(1_012), &clock).unwrap(), 12);
+        assert_eq!(measure(&Reference::Time(990), &clock).unwrap(), -10);
+        assert_eq!(describe(12), "12s behind");
+        assert_eq!(describe(-10), "10s ahead");
+    }
+
+    #[test]
+    fn significance_depends_on_the_period() {
+        assert!(!is_significant(7, 30));
+        assert!(is_significant(8, 30));
+        assert!(is_significant(-8, 30));
+        assert!(!is_significant(14, 60));
+        assert!(is_significant(i64::MIN, 30));
+    }
+
+    #[test]
+    fn stores_the_offset() {
+        let path = std::env::temp_dir().join(format!("ft_otp-offset-{}", std::process::id()));
+        assert_eq!(load_offset(&path).unwrap(), None);
+        save_offset(&path, -17).unwrap();
+        assert_eq!(load_offset(&path).unwrap(), Some(-17));
+        fs::write(&path, "soon").unwrap();
+        assert!(load_offset(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    env,
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use sha2::{Digest, Sha256};
+
+/// Mode of files written for the first time: they hold secrets, or metadata
+/// about them.
+const NEW_FILE_MODE: u32 = 0o600;
+/// Where root keeps the lock files, out of reach of the users.
+const ROOT_LOCK_DIR: &str = "/run/ft_otp";
+
+/// Writes `data` next to `path` then renames it over, so a crash leaves
+/// either the old file or the new one, never a truncated mix of both. The
+/// file keeps the mode it had, or gets 0600 when new.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mode = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata.permissions().mode() & 0o777,
+        _ => NEW_FILE_MODE,
+    };
+    write_atomic_mode(path, data, mode)
+}
+
+/// Like `write_atomic`, always leaving the file with mode 0600.
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic_mode(path, data, NEW_FILE_MODE)
+}
+
+/// The temporary file is created under a unique name with `O_EXCL` and
+/// `O_NOFOLLOW`, so concurrent writers never share it and a planted link is
+/// never written through. Its mode is set before any data goes in.
+fn write_atomic_mode(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+
+    let (mut tmp, tmp_path) = create_tmp(dir, &file_name.to_string_lossy())?;
+    let result = (|| {
+        tmp.set_permissions(fs::Permissions::from_mode(mode))?;
+        tmp.write_all(data)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        File::open(dir)?.sync_all()
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn create_tmp(dir: &Path, file_name: &str) -> io::Result<(File, PathBuf)> {
+    loop {
+        let tmp_path = dir.join(format!(".{}.{:016x}.tmp", file_name, OsRng.next_u64()));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(NEW_FILE_MODE)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&tmp_path)
+        {
+            Ok(file) => return Ok((file, tmp_path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Removes the socket a dead server left at `path`, so a new one can bind
+/// there. Anything else found at `path` is refused rather than deleted: a
+/// mistyped path must not cost a file.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} exists and is not a socket, refusing to replace it.",
+                path.display()
+            ),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The directory holding the locks, created 0700 and refused unless it is
+/// still owned by this process and private to it.
+fn lock_dir() -> io::Result<PathBuf> {
+    let euid = unsafe { libc::geteuid() };
+    let dir = match euid {
+        0 => PathBuf::from(ROOT_LOCK_DIR),
+        _ => env::temp_dir().join(format!("ft_otp-locks-{}", euid)),
+    };
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory owned by uid {} with mode 700.",
+                dir.display(),
+                euid
+            ),
+        ));
+    }
+    Ok(dir)
+}
+
+/// An exclusive lock on a key file or vault, held until dropped. Whoever
+/// reads a store to write it back (HOTP counters, last accepted steps, ...)
+/// takes it first, so two processes never both work from the same copy. The
+/// lock file is named after the store path, in the lock directory rather
+/// than next to the store.
+pub struct Lock(File);
+
+impl Lock {
+    /// Blocks until no other process holds the lock of `path`.
+    pub fn acquire(path: &Path) -> io::Result<Lock> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let path = fs::canonicalize(dir)?.join(name);
+        let digest = Sha256::digest(path.as_os_str().as_bytes());
+        let lock_path = lock_dir()?.join(format!("{}.lock", hex::encode(&digest[..16])));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(NEW_FILE_MODE)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(lock_path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Lock(file))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::symlink, thread};
+
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ft_otp-storage-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn new_files_are_private_and_modes_are_kept() {
+        let dir = scratch("modes");
+        let path = dir.join("store");
+        write_atomic(&path, b"one").unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"two").unwrap();
+        assert_eq!(mode(&path), 0o640);
+        write_private(&path, b"three").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(fs::read(&path).unwrap(), b"three");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_links_instead_of_writing_through_them() {
+        let dir = scratch("links");
+        let target = dir.join("target");
+        fs::write(&target, b"untouched").unwrap();
+        let path = dir.join("store");
+        symlink(&target, &path).unwrap();
+
+        write_atomic(&path, b"data").unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"untouched");
+        assert!(
+            !fs::symlink_metadata(&path)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_sockets_are_removed_as_stale() {
+        let dir = scratch("sockets");
+        let socket = dir.join("server.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).unwrap();
+
+        let file = dir.join("notes");
+        fs::write(&file, b"keep").unwrap();
+        let link = dir.join("link.sock");
+        symlink(&file, &link).unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(remove_stale_socket(&link).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"keep");
+        assert!(fs::symlink_metadata(&link).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_do_not_mix() {
+        let dir = scratch("concurrent");
+        let path = dir.join("store");
+        let writers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || write_atomic(&path, &[i; 4096]).unwrap())
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 4096);
+        assert!(data.iter().all(|b| *b == data[0]));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! `ft_otp time`: measures the clock against a reference and stores the offset.
+
+use std::fs;
+
+use ft_otp::{
+    SystemClock,
+    skew::{self, Reference},
+};
+
+use crate::TimeArgs;
+
+pub fn command(time: &TimeArgs, save: bool, clear: bool, period: u64) -> anyhow::Result<()> {
+    if clear {
+        match fs::remove_file(&time.offset_file) {
+            Ok(()) => println!(
+                "Removed the offset stored in {}",
+                time.offset_file.display()
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => println!("No stored offset"),
+            Err(e) => return Err(e.into()),
+        }
+        return Ok(());
+    }
+
+    let reference = time
+        .reference()
+        .unwrap_or_else(|| Reference::Sntp(skew::DEFAULT_SERVER.to_string()));
+    let source = match &reference {
+        Reference::Sntp(server) => server.clone(),
+        Reference::Time(timestamp) => format!("the reference time {}", timestamp),
+    };
+    let offset = skew::measure(&reference, &SystemClock)?;
+    println!(
+        "Offset against {}: {:+}s, the system clock is {}",
+        source,
+        offset,
+        skew::describe(offset)
+    );
+
+    if save {
+        skew::save_offset(&time.offset_file, offset)?;
+        println!(
+            "Offset {:+}s saved to {}",
+            offset,
+            time.offset_file.display()
+        );
+        return Ok(());
+    }
+
+    let stored = skew::load_offset(&time.offset_file)?;
+    if let Some(stored) = stored {
+        println!("Stored offset: {:+}s", stored);
+    }
+    let residual = offset - stored.unwrap_or(0);
+    if skew::is_significant(residual, period) {
+        println!(
+            "Codes may be rejected: the corrected clock is {}, run with --save to fix it",
+            skew::describe(residual)
+        );
+    }
+    Ok(())
+}
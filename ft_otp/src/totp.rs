@@ -1,34 +1,633 @@
+use std::{fmt, str::FromStr};
+
 use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    error::{Error, Result},
+    secret::Secret,
+};
+
+pub const MIN_DIGITS: u32 = 6;
+pub const MAX_DIGITS: u32 = 10;
+
+/// Shortest code accepted for non-decimal encodings; Steam Guard uses 5.
+pub const MIN_CHARS: u32 = 5;
+
+const STEAM_ALPHABET: &str = "23456789BCDFGHJKMNPQRTVWXY";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().replace('-', "").as_str() {
+            "SHA1" => Ok(Algorithm::Sha1),
+            "SHA256" => Ok(Algorithm::Sha256),
+            "SHA512" => Ok(Algorithm::Sha512),
+            _ => Err(Error::InvalidParams(format!(
+                "unknown algorithm '{}' (expected SHA1, SHA256 or SHA512)",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the truncated HMAC value is turned into the code the user types.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Encoding {
+    /// RFC 4226 decimal codes, `digits` long.
+    #[default]
+    Decimal,
+    /// Steam Guard: 5 characters from a 26 letter and digit alphabet.
+    Steam,
+    /// Any alphabet, least significant character first like Steam.
+    Alphabet(String),
+}
+
+impl Encoding {
+    pub fn is_decimal(&self) -> bool {
+        *self == Encoding::Decimal
+    }
+
+    /// Code length used when none is given.
+    pub fn default_digits(&self) -> u32 {
+        match self {
+            Encoding::Decimal => 6,
+            Encoding::Steam | Encoding::Alphabet(_) => MIN_CHARS,
+        }
+    }
+
+    pub fn validate(&self, digits: u32) -> Result<()> {
+        match self {
+            Encoding::Decimal => validate_digits(digits),
+            Encoding::Steam | Encoding::Alphabet(_) => {
+                let alphabet: Vec<char> = self.alphabet().chars().collect();
+                let mut unique = alphabet.clone();
+                unique.sort_unstable();
+                unique.dedup();
+                if alphabet.len() < 2 || unique.len() != alphabet.len() {
+                    return Err(Error::InvalidParams(
+                        "an alphabet needs at least 2 distinct characters.".to_string(),
+                    ));
+                }
+                if !(MIN_CHARS..=MAX_DIGITS).contains(&digits) {
+                    return Err(Error::InvalidParams(format!(
+                        "code length must be between {} and {}.",
+                        MIN_CHARS, MAX_DIGITS
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn alphabet(&self) -> &str {
+        match self {
+            Encoding::Decimal => "0123456789",
+            Encoding::Steam => STEAM_ALPHABET,
+            Encoding::Alphabet(alphabet) => alphabet,
+        }
+    }
+
+    /// Encodes a dynamically truncated value (see [`truncate`]) as a code of
+    /// `digits` characters. Lengths and alphabets `validate` refuses are
+    /// errors.
+    pub fn encode(&self, value: u32, digits: u32) -> Result<String> {
+        self.validate(digits)?;
+        let code = match self {
+            Encoding::Decimal => {
+                let code = value as u64 % 10_u64.pow(digits);
+                format!("{:0width$}", code, width = digits as usize)
+            }
+            Encoding::Steam | Encoding::Alphabet(_) => {
+                let alphabet: Vec<char> = self.alphabet().chars().collect();
+                let base = alphabet.len() as u32;
+                let mut value = value;
+                (0..digits)
+                    .map(|_| {
+                        let c = alphabet[(value % base) as usize];
+                        value /= base;
+                        c
+                    })
+                    .collect()
+            }
+        };
+        Ok(code)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Decimal => f.write_str("decimal"),
+            Encoding::Steam => f.write_str("steam"),
+            Encoding::Alphabet(alphabet) => write!(f, "alphabet:{}", alphabet),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(alphabet) = s.strip_prefix("alphabet:") {
+            return Ok(Encoding::Alphabet(alphabet.to_string()));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "decimal" | "" => Ok(Encoding::Decimal),
+            "steam" => Ok(Encoding::Steam),
+            _ => Err(Error::InvalidParams(format!(
+                "unknown encoding '{}' (expected decimal, steam or alphabet:CHARS)",
+                s
+            ))),
+        }
+    }
+}
 
-fn hmac_sha1(key: &[u8], counter: &[u8]) -> anyhow::Result<[u8; 20]> {
-    let mut hasher: Hmac<Sha1> = Mac::new_from_slice(key)?;
+impl From<Encoding> for String {
+    fn from(encoding: Encoding) -> String {
+        encoding.to_string()
+    }
+}
+
+impl TryFrom<String> for Encoding {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
 
-    hasher.update(counter);
-    Ok(hasher.finalize().into_bytes().into())
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TotpParams {
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    pub period: u64,
+    pub t0: u64,
+    #[serde(skip_serializing_if = "Encoding::is_decimal")]
+    pub encoding: Encoding,
 }
 
-fn hotp(key: &[u8], counter: u64) -> u32 {
-    let hmac_result = hmac_sha1(key, &counter.to_be_bytes()).expect("hashing failed");
+impl Default for TotpParams {
+    fn default() -> Self {
+        TotpParams {
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+            t0: 0,
+            encoding: Encoding::Decimal,
+        }
+    }
+}
+
+impl TotpParams {
+    pub fn validate(&self) -> Result<()> {
+        self.encoding.validate(self.digits)?;
+        if self.period == 0 {
+            return Err(Error::InvalidParams(
+                "period must be at least 1 second.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn counter_at(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_sub(self.t0) / self.period
+    }
 
-    let offset = (hmac_result[19] & 0x0f) as usize;
+    pub fn time_remaining(&self, timestamp: u64) -> u64 {
+        self.period - timestamp.saturating_sub(self.t0) % self.period
+    }
+
+    /// Code for an HOTP counter or TOTP time step, in this account's encoding.
+    pub fn code(&self, key: &[u8], counter: u64) -> Result<String> {
+        let value = truncate(key, counter, self.algorithm)?;
+        self.encoding.encode(value, self.digits)
+    }
+}
+
+fn validate_digits(digits: u32) -> Result<()> {
+    if !(MIN_DIGITS..=MAX_DIGITS).contains(&digits) {
+        return Err(Error::InvalidParams(format!(
+            "digits must be between {} and {}.",
+            MIN_DIGITS, MAX_DIGITS
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn hmac(algorithm: Algorithm, key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let digest = match algorithm {
+        Algorithm::Sha1 => {
+            let mut hasher: Hmac<Sha1> = Mac::new_from_slice(key).map_err(|_| Error::InvalidKey)?;
+            hasher.update(message);
+            hasher.finalize().into_bytes().to_vec()
+        }
+        Algorithm::Sha256 => {
+            let mut hasher: Hmac<Sha256> =
+                Mac::new_from_slice(key).map_err(|_| Error::InvalidKey)?;
+            hasher.update(message);
+            hasher.finalize().into_bytes().to_vec()
+        }
+        Algorithm::Sha512 => {
+            let mut hasher: Hmac<Sha512> =
+                Mac::new_from_slice(key).map_err(|_| Error::InvalidKey)?;
+            hasher.update(message);
+            hasher.finalize().into_bytes().to_vec()
+        }
+    };
+    Ok(digest)
+}
 
-    let binary = ((hmac_result[offset] & 0x7f) as u32) << 24
+pub(crate) fn dynamic_truncate(hmac_result: &[u8]) -> u32 {
+    let offset = (hmac_result[hmac_result.len() - 1] & 0x0f) as usize;
+
+    ((hmac_result[offset] & 0x7f) as u32) << 24
         | (hmac_result[offset + 1] as u32) << 16
         | (hmac_result[offset + 2] as u32) << 8
-        | (hmac_result[offset + 3] as u32);
+        | (hmac_result[offset + 3] as u32)
+}
+
+/// RFC 4226 dynamic truncation: the 31-bit value every encoding starts from.
+pub fn truncate(key: &[u8], counter: u64, algorithm: Algorithm) -> Result<u32> {
+    let hmac_result = hmac(algorithm, key, &counter.to_be_bytes())?;
+    Ok(dynamic_truncate(&hmac_result))
+}
+
+pub fn hotp(key: &[u8], counter: u64, algorithm: Algorithm, digits: u32) -> Result<u32> {
+    validate_digits(digits)?;
+    let binary = truncate(key, counter, algorithm)?;
+    let modulo = 10_u64.pow(digits);
+    Ok((binary as u64 % modulo) as u32)
+}
+
+/// Current Unix time from the system clock.
+pub fn now() -> u64 {
+    SystemClock.now()
+}
+
+pub fn totp_at(key: &[u8], params: &TotpParams, timestamp: u64) -> Result<String> {
+    params.code(key, params.counter_at(timestamp))
+}
+
+pub fn totp_with(key: &[u8], params: &TotpParams, clock: &dyn Clock) -> Result<String> {
+    totp_at(key, params, clock.now())
+}
 
-    let modulo = 10_u32.pow(6);
-    binary % modulo
+pub fn totp(key: &[u8], params: &TotpParams) -> Result<String> {
+    totp_with(key, params, &SystemClock)
 }
 
-pub fn totp(key: &[u8]) -> u32 {
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+/// Time-based generator built with [`Totp::builder`].
+#[derive(Debug, Clone)]
+pub struct Totp {
+    secret: Secret,
+    params: TotpParams,
+}
+
+#[derive(Debug, Clone)]
+pub struct TotpBuilder {
+    secret: Secret,
+    params: TotpParams,
+}
+
+impl Totp {
+    pub fn builder(secret: impl Into<Secret>) -> TotpBuilder {
+        TotpBuilder {
+            secret: secret.into(),
+            params: TotpParams::default(),
+        }
+    }
+
+    pub fn params(&self) -> &TotpParams {
+        &self.params
+    }
+
+    pub fn generate_at(&self, timestamp: u64) -> Result<String> {
+        totp_at(self.secret.expose(), &self.params, timestamp)
+    }
+
+    pub fn generate_with(&self, clock: &dyn Clock) -> Result<String> {
+        self.generate_at(clock.now())
+    }
+
+    pub fn generate(&self) -> Result<String> {
+        self.generate_with(&SystemClock)
+    }
+}
+
+impl TotpBuilder {
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.params.algorithm = algorithm;
+        self
+    }
+
+    pub fn digits(mut self, digits: u32) -> Self {
+        self.params.digits = digits;
+        self
+    }
+
+    pub fn period(mut self, period: u64) -> Self {
+        self.params.period = period;
+        self
+    }
+
+    pub fn t0(mut self, t0: u64) -> Self {
+        self.params.t0 = t0;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.params.encoding = encoding;
+        self
+    }
+
+    pub fn params(mut self, params: TotpParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn build(self) -> Result<Totp> {
+        self.params.validate()?;
+        Ok(Totp {
+            secret: self.secret,
+            params: self.params,
+        })
+    }
+}
+
+/// Counter-based generator built with [`Hotp::builder`].
+#[derive(Debug, Clone)]
+pub struct Hotp {
+    secret: Secret,
+    params: TotpParams,
+}
+
+#[derive(Debug, Clone)]
+pub struct HotpBuilder {
+    secret: Secret,
+    params: TotpParams,
+}
+
+impl Hotp {
+    pub fn builder(secret: impl Into<Secret>) -> HotpBuilder {
+        HotpBuilder {
+            secret: secret.into(),
+            params: TotpParams::default(),
+        }
+    }
+
+    pub fn generate(&self, counter: u64) -> Result<String> {
+        self.params.code(self.secret.expose(), counter)
+    }
+}
+
+impl HotpBuilder {
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.params.algorithm = algorithm;
+        self
+    }
+
+    pub fn digits(mut self, digits: u32) -> Self {
+        self.params.digits = digits;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.params.encoding = encoding;
+        self
+    }
+
+    pub fn build(self) -> Result<Hotp> {
+        self.params.validate()?;
+        Ok(Hotp {
+            secret: self.secret,
+            params: self.params,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED_SHA1: &[u8] = b"12345678901234567890";
+    const SEED_SHA256: &[u8] = b"12345678901234567890123456789012";
+    const SEED_SHA512: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    // RFC 6238 Appendix B: (time, SHA1, SHA256, SHA512)
+    const VECTORS: [(u64, &str, &str, &str); 6] = [
+        (59, "94287082", "46119246", "90693936"),
+        (1111111109, "07081804", "68084774", "25091201"),
+        (1111111111, "14050471", "67062674", "99943326"),
+        (1234567890, "89005924", "91819424", "93441116"),
+        (2000000000, "69279037", "90698825", "38618901"),
+        (20000000000, "65353130", "77737706", "47863826"),
+    ];
+
+    fn code(seed: &[u8], algorithm: Algorithm, time: u64) -> String {
+        let params = TotpParams {
+            algorithm,
+            digits: 8,
+            ..TotpParams::default()
+        };
+        totp_at(seed, &params, time).unwrap()
+    }
+
+    #[test]
+    fn rfc6238_sha1() {
+        for (time, expected, _, _) in VECTORS {
+            assert_eq!(
+                code(SEED_SHA1, Algorithm::Sha1, time),
+                expected,
+                "T={}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6238_sha256() {
+        for (time, _, expected, _) in VECTORS {
+            assert_eq!(
+                code(SEED_SHA256, Algorithm::Sha256, time),
+                expected,
+                "T={}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn rfc6238_sha512() {
+        for (time, _, _, expected) in VECTORS {
+            assert_eq!(
+                code(SEED_SHA512, Algorithm::Sha512, time),
+                expected,
+                "T={}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn rfc4226_appendix_d() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(
+                hotp(SEED_SHA1, counter as u64, Algorithm::Sha1, 6).unwrap(),
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn builders_match_the_functions() {
+        let totp = Totp::builder(SEED_SHA256)
+            .algorithm(Algorithm::Sha256)
+            .digits(8)
+            .build()
+            .unwrap();
+        assert_eq!(totp.generate_at(59).unwrap(), "46119246");
+
+        let hotp = Hotp::builder(SEED_SHA1).build().unwrap();
+        assert_eq!(hotp.generate(1).unwrap(), "287082");
+
+        assert!(Totp::builder(SEED_SHA1).period(0).build().is_err());
+        assert!(Hotp::builder(SEED_SHA1).digits(4).build().is_err());
+    }
+
+    // RFC 4226 Appendix D truncated values for counters 0 to 9, so the
+    // encodings below are checked against published intermediate results.
+    const TRUNCATED: [u32; 10] = [
+        1284755224, 1094287082, 137359152, 1726969429, 1640338314, 868254676, 1918287922, 82162583,
+        673399871, 645520489,
+    ];
+
+    #[test]
+    fn truncation_matches_rfc4226() {
+        for (counter, expected) in TRUNCATED.iter().enumerate() {
+            assert_eq!(
+                truncate(SEED_SHA1, counter as u64, Algorithm::Sha1).unwrap(),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_encoding() {
+        let codes: Vec<String> = TRUNCATED
+            .iter()
+            .map(|value| Encoding::Decimal.encode(*value, 6).unwrap())
+            .collect();
+        assert_eq!(codes[..3], ["755224", "287082", "359152"]);
+        assert_eq!(Encoding::Decimal.encode(82162583, 8).unwrap(), "82162583");
+        assert_eq!(
+            Encoding::Decimal.encode(1284755224, 10).unwrap(),
+            "1284755224"
+        );
+        assert!(Encoding::Decimal.encode(1284755224, 20).is_err());
+        assert!(hotp(SEED_SHA1, 0, Algorithm::Sha1, 20).is_err());
+    }
+
+    #[test]
+    fn steam_encoding() {
+        let codes: Vec<String> = TRUNCATED
+            .iter()
+            .map(|value| Encoding::Steam.encode(*value, 5).unwrap())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "GG5F5", "PV9M4", "B26KJ", "5H85C", "6Y9J3", "MD224", "P2GRF", "C9PRW", "3NKKN",
+                "5YCKB"
+            ]
+        );
+
+        let totp = Totp::builder(SEED_SHA1)
+            .encoding(Encoding::Steam)
+            .digits(5)
+            .build()
+            .unwrap();
+        assert_eq!(totp.generate_at(59).unwrap(), "PV9M4");
+    }
+
+    #[test]
+    fn custom_alphabet_encoding() {
+        let hex = Encoding::Alphabet("0123456789ABCDEF".to_string());
+        assert_eq!(hex.encode(TRUNCATED[0], 6).unwrap(), "81FC39");
+        assert_eq!(hex.encode(TRUNCATED[1], 6).unwrap(), "AEE793");
+
+        // A decimal alphabet gives the decimal code, least significant digit first.
+        let digits = Encoding::Alphabet("0123456789".to_string());
+        assert_eq!(digits.encode(TRUNCATED[0], 6).unwrap(), "422557");
+
+        assert!(Encoding::Alphabet("AA".to_string()).validate(6).is_err());
+        assert!(Encoding::Alphabet("A".to_string()).validate(6).is_err());
+        assert!(Encoding::Alphabet(String::new()).encode(1, 6).is_err());
+        assert!(Encoding::Steam.validate(4).is_err());
+    }
+
+    #[test]
+    fn encoding_names_round_trip() {
+        for name in ["decimal", "steam", "alphabet:ABC123"] {
+            assert_eq!(name.parse::<Encoding>().unwrap().to_string(), name);
+        }
+        assert_eq!("STEAM".parse::<Encoding>().unwrap(), Encoding::Steam);
+        assert!("base64".parse::<Encoding>().is_err());
 
-    let counter = timestamp / 30;
+        let params = TotpParams {
+            encoding: Encoding::Steam,
+            digits: 5,
+            ..TotpParams::default()
+        };
+        let json = serde_json::to_string(&params).unwrap();
+        assert!(json.contains("\"encoding\":\"steam\""));
+        assert_eq!(serde_json::from_str::<TotpParams>(&json).unwrap(), params);
+        assert!(
+            !serde_json::to_string(&TotpParams::default())
+                .unwrap()
+                .contains("encoding")
+        );
+    }
 
-    hotp(&key, counter)
+    #[test]
+    fn period_and_t0_shift_the_counter() {
+        let params = TotpParams {
+            period: 60,
+            t0: 30,
+            ..TotpParams::default()
+        };
+        assert_eq!(params.counter_at(89), 0);
+        assert_eq!(params.counter_at(90), 1);
+        assert_eq!(params.time_remaining(90), 60);
+        assert_eq!(params.time_remaining(149), 1);
+    }
 }
@@ -1,150 +1,535 @@
 use std::{
-    io,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    io::{self, Write},
+    time::Duration,
 };
 
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
     layout::{Alignment, Constraint, Layout, Rect},
     style::{Color, Style, Stylize},
-    widgets::{Block, Borders, Gauge, Paragraph, Widget},
+    text::{Line, Span},
+    widgets::{Block, Borders, LineGauge, Paragraph, Widget},
 };
+use zeroize::Zeroizing;
 
-use crate::totp;
+use ft_otp::{
+    Clock,
+    keyfile::OtpKind,
+    otpauth,
+    totp::{self, TotpParams},
+    vault::Entry,
+};
+
+use crate::qr;
 
-const TOTP_PERIOD: u64 = 30;
+/// Height of one account in the list: name and code, then its period gauge.
+const ROW_HEIGHT: u16 = 2;
 
-pub fn run_tui(key: &[u8], qr_string: &str) -> io::Result<()> {
-    let mut app = App::new(key, qr_string);
+/// Opens the account browser. `show_qr` starts with the QR code of the first
+/// account displayed, as after `-g -t`. `warning` stays on the status line
+/// until something else is shown there.
+pub fn run_tui(
+    entries: Vec<Entry>,
+    show_qr: bool,
+    clock: Box<dyn Clock>,
+    warning: Option<String>,
+) -> io::Result<()> {
+    let mut app = App::new(entries, clock);
+    app.warning = warning;
+    if show_qr {
+        app.toggle_qr();
+    }
     ratatui::run(|terminal| app.run(terminal))
 }
 
-#[derive(Debug, Default)]
+/// Scores `text` against a fuzzy `query`: every query character must appear in
+/// order. Consecutive matches and matches at word starts score higher.
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut last_match: Option<usize> = None;
+
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = (pos..text.len()).find(|&i| text[i] == q)?;
+        score += 1;
+        if last_match.is_some_and(|last| last + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        score -= (found - pos) as i64;
+        last_match = Some(found);
+        pos = found + 1;
+    }
+    Some(score)
+}
+
+fn display_name(entry: &Entry) -> String {
+    match &entry.issuer {
+        Some(issuer) => format!("{}: {}", issuer, entry.label),
+        None => entry.label.clone(),
+    }
+}
+
+/// Copies `text` through the terminal with an OSC 52 escape, which also works
+/// over SSH as long as the terminal emulator allows it.
+fn copy_osc52(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", BASE64.encode(text))?;
+    stdout.flush()
+}
+
 struct App {
-    key: Vec<u8>,
-    qr_string: String,
-    time_remaining: u64,
-    progress: u16,
-    otp_code: u32,
+    entries: Vec<Entry>,
+    /// Indices into `entries` that match the search, best match first.
+    visible: Vec<usize>,
+    /// Position of the selected account in `visible`.
+    selected: usize,
+    query: String,
+    searching: bool,
+    show_qr: bool,
+    /// QR code of the selected account, with the entry it was rendered for.
+    qr: Option<(usize, Zeroizing<String>)>,
+    show_next: bool,
+    status: String,
+    /// Clock skew warning, shown while the status line is otherwise empty.
+    warning: Option<String>,
+    clock: Box<dyn Clock>,
+    /// Time the current frame is drawn for, read once from `clock`.
+    now: u64,
     exit: bool,
 }
 
 impl App {
-    fn new(key: &[u8], qr_string: &str) -> App {
+    fn new(entries: Vec<Entry>, clock: Box<dyn Clock>) -> App {
+        let visible = (0..entries.len()).collect();
         App {
-            key: key.into(),
-            qr_string: qr_string.into(),
-            time_remaining: 0,
-            progress: 0,
-            otp_code: 0,
+            entries,
+            visible,
+            selected: 0,
+            query: String::new(),
+            searching: false,
+            show_qr: false,
+            qr: None,
+            show_next: false,
+            status: String::new(),
+            warning: None,
+            now: clock.now(),
+            clock,
             exit: false,
         }
     }
 
     fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
         while !self.exit {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-
-            self.time_remaining = TOTP_PERIOD - (now % TOTP_PERIOD);
-            self.progress =
-                ((TOTP_PERIOD - self.time_remaining) as f64 / TOTP_PERIOD as f64 * 100.0) as u16;
-
-            self.otp_code = totp::totp(&self.key);
-
+            self.tick();
             terminal.draw(|frame| self.draw(frame))?;
             self.handle_events()?;
         }
         Ok(())
     }
 
+    fn tick(&mut self) {
+        self.now = self.clock.now();
+    }
+
     fn draw(&self, frame: &mut Frame) {
         frame.render_widget(self, frame.area());
     }
 
     fn handle_events(&mut self) -> io::Result<()> {
-        if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    self.handle_key_event(key)
-                }
-            }
+        if event::poll(Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            self.handle_key_event(key)?
         }
         Ok(())
     }
 
-    fn handle_key_event(&mut self, key_event: KeyEvent) {
+    fn handle_key_event(&mut self, key_event: KeyEvent) -> io::Result<()> {
+        if key_event.modifiers.contains(KeyModifiers::CONTROL)
+            && key_event.code == KeyCode::Char('c')
+        {
+            self.exit();
+            return Ok(());
+        }
+
+        if self.searching {
+            match key_event.code {
+                KeyCode::Esc => {
+                    self.query.clear();
+                    self.searching = false;
+                    self.filter();
+                }
+                KeyCode::Enter => self.searching = false,
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.filter();
+                }
+                KeyCode::Up => self.select_previous(),
+                KeyCode::Down => self.select_next(),
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.filter();
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
         match key_event.code {
             KeyCode::Char('q') => self.exit(),
+            KeyCode::Esc if !self.query.is_empty() => {
+                self.query.clear();
+                self.filter();
+            }
             KeyCode::Esc => self.exit(),
+            KeyCode::Char('/') => self.searching = true,
+            KeyCode::Up | KeyCode::Char('k') => self.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.select_next(),
+            KeyCode::Char('c') | KeyCode::Char('y') | KeyCode::Enter => self.copy()?,
+            KeyCode::Char('v') => self.toggle_qr(),
+            KeyCode::Char('n') => self.show_next = !self.show_next,
             _ => {}
         }
+        Ok(())
+    }
+
+    fn filter(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let text = format!("{} {}", display_name(entry), entry.tags.join(" "));
+                fuzzy_score(&self.query, &text).map(|score| (score, i))
+            })
+            .collect();
+        // Stable sort keeps the vault order between equal scores.
+        scored.sort_by_key(|(score, _)| -score);
+        self.visible = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = 0;
+        self.refresh_qr();
+    }
+
+    fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+        self.refresh_qr();
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.visible.len() {
+            self.selected += 1;
+        }
+        self.refresh_qr();
+    }
+
+    fn current(&self) -> Option<usize> {
+        self.visible.get(self.selected).copied()
+    }
+
+    /// Returns the current code and the one after it, or `None` for HOTP
+    /// accounts: generating those would burn counter values.
+    fn codes(&self, entry: &Entry) -> Option<(String, String)> {
+        if entry.key.kind == OtpKind::Hotp {
+            return None;
+        }
+        let params = &entry.key.params;
+        let code_at = |time: u64| totp::totp_at(entry.key.secret.expose(), params, time).ok();
+        Some((
+            code_at(self.now)?,
+            code_at(self.now.saturating_add(params.period))?,
+        ))
+    }
+
+    fn copy(&mut self) -> io::Result<()> {
+        let Some(index) = self.current() else {
+            return Ok(());
+        };
+        let entry = &self.entries[index];
+        self.status = match self.codes(entry) {
+            Some((current, next)) => {
+                let code = if self.show_next { next } else { current };
+                copy_osc52(&code)?;
+                format!("Copied code for {}", display_name(entry))
+            }
+            None => "HOTP codes are not generated here, use -k or --label".to_string(),
+        };
+        Ok(())
+    }
+
+    fn toggle_qr(&mut self) {
+        self.show_qr = !self.show_qr;
+        self.refresh_qr();
+    }
+
+    /// Re-renders the QR code when it is shown and the selection moved.
+    fn refresh_qr(&mut self) {
+        let index = match self.current() {
+            Some(index) if self.show_qr => index,
+            _ => {
+                self.qr = None;
+                return;
+            }
+        };
+        if self.qr.as_ref().is_some_and(|(shown, _)| *shown == index) {
+            return;
+        }
+
+        let uri = otpauth::to_uri(&self.entries[index]);
+        let rendered = qr::render_unicode(&uri).unwrap_or_else(|e| e.to_string());
+        self.qr = Some((index, Zeroizing::new(rendered)));
     }
 
     fn exit(&mut self) {
         self.exit = true;
     }
-}
 
-impl Widget for &App {
-    fn render(self, area: Rect, buf: &mut Buffer) {
-        let chunks = Layout::vertical([
-            Constraint::Min(10),   // QR Code
-            Constraint::Length(3), // OTP code
-            Constraint::Length(3), // Progress bar
-            Constraint::Length(2), // Instructions
-        ])
-        .split(area);
+    fn render_row(&self, entry: &Entry, selected: bool, area: Rect, buf: &mut Buffer) {
+        let [line, gauge] = Layout::vertical([Constraint::Length(1); 2]).areas(area);
+        let marker = if selected { "> " } else { "  " };
+        let name_style = if selected {
+            Style::default().fg(Color::Cyan).bold()
+        } else {
+            Style::default()
+        };
+
+        let Some((current, next)) = self.codes(entry) else {
+            Paragraph::new(Line::from(vec![
+                Span::styled(marker, name_style),
+                Span::styled(display_name(entry), name_style),
+            ]))
+            .render(line, buf);
+            Paragraph::new(format!("counter-based, next counter {}", entry.key.counter))
+                .style(Style::default().fg(Color::DarkGray))
+                .alignment(Alignment::Right)
+                .render(line, buf);
+            return;
+        };
 
-        let qr_code = Block::default()
+        let params: &TotpParams = &entry.key.params;
+        let remaining = params.time_remaining(self.now);
+        let color = if remaining <= 5 {
+            Color::Red
+        } else if remaining <= 10 {
+            Color::Yellow
+        } else {
+            Color::Green
+        };
+
+        Paragraph::new(Line::from(vec![
+            Span::styled(marker, name_style),
+            Span::styled(display_name(entry), name_style),
+        ]))
+        .render(line, buf);
+
+        let mut code = vec![Span::styled(current, Style::default().fg(color).bold())];
+        if self.show_next {
+            code.push(Span::raw("  next "));
+            code.push(Span::styled(next, Style::default().fg(Color::Gray)));
+        }
+        Paragraph::new(Line::from(code))
+            .alignment(Alignment::Right)
+            .render(line, buf);
+
+        LineGauge::default()
+            .filled_style(Style::default().fg(color))
+            .unfilled_style(Style::default().fg(Color::DarkGray))
+            .label(format!("  {:>3}s ", remaining))
+            .ratio(remaining as f64 / params.period as f64)
+            .render(gauge, buf);
+    }
+
+    fn render_list(&self, area: Rect, buf: &mut Buffer) {
+        let block = Block::default()
             .borders(Borders::ALL)
             .title(" ft_otp ".bold())
             .title_alignment(Alignment::Center)
             .style(Style::default().fg(Color::Cyan));
+        let inner = block.inner(area);
+        block.render(area, buf);
 
-        let _qr_paragraph = Paragraph::new(self.qr_string.as_str())
-            .block(qr_code)
-            .alignment(Alignment::Center)
-            .style(Style::default().fg(Color::White))
-            .render(chunks[0], buf);
+        if self.visible.is_empty() {
+            Paragraph::new("No matching account")
+                .alignment(Alignment::Center)
+                .style(Style::default().fg(Color::DarkGray))
+                .render(inner, buf);
+            return;
+        }
 
-        let code_color = if self.time_remaining <= 5 {
-            Color::Red
-        } else if self.time_remaining <= 10 {
-            Color::Yellow
+        // Scroll just enough to keep the selection on screen.
+        let rows = (inner.height / ROW_HEIGHT).max(1) as usize;
+        let offset = (self.selected + 1).saturating_sub(rows);
+        for (slot, &index) in self.visible.iter().skip(offset).take(rows).enumerate() {
+            let row = Rect {
+                y: inner.y + slot as u16 * ROW_HEIGHT,
+                height: ROW_HEIGHT,
+                ..inner
+            };
+            let entry = &self.entries[index];
+            self.render_row(entry, offset + slot == self.selected, row, buf);
+        }
+    }
+}
+
+impl Widget for &App {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [main, search, help] = Layout::vertical([
+            Constraint::Min(ROW_HEIGHT + 2),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(area);
+
+        if self.show_qr {
+            let [list, qr_area] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(main);
+            self.render_list(list, buf);
+            let qr_string = self.qr.as_ref().map_or("", |(_, qr)| qr.as_str());
+            Paragraph::new(qr_string)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" QR code ".bold())
+                        .title_alignment(Alignment::Center),
+                )
+                .alignment(Alignment::Center)
+                .style(Style::default().fg(Color::White))
+                .render(qr_area, buf);
         } else {
-            Color::Green
+            self.render_list(main, buf);
+        }
+
+        let (search_line, color) = if self.searching {
+            (format!("/{}_", self.query), Color::Yellow)
+        } else if !self.query.is_empty() {
+            (format!("/{}", self.query), Color::Yellow)
+        } else if let (true, Some(warning)) = (self.status.is_empty(), &self.warning) {
+            (warning.clone(), Color::Red)
+        } else {
+            (self.status.clone(), Color::Yellow)
         };
+        Paragraph::new(search_line)
+            .style(Style::default().fg(color))
+            .render(search, buf);
 
-        let _otp_paragraph = Paragraph::new(format!("{:06}", self.otp_code))
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title(" Current Code ".bold())
-                    .title_alignment(Alignment::Center),
-            )
-            .alignment(Alignment::Center)
-            .style(Style::default().fg(code_color).bold())
-            .render(chunks[1], buf);
-
-        let _gauge = Gauge::default()
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title(format!(" Next code in {}s ", self.time_remaining))
-                    .title_alignment(Alignment::Center),
-            )
-            .gauge_style(Style::default().fg(code_color).bg(Color::DarkGray))
-            .percent(self.progress)
-            .render(chunks[2], buf);
-
-        let _instruction = Paragraph::new("Press 'q' or 'Esc' to quit")
+        Paragraph::new("/ search  ↑↓ select  c copy  v QR  n next code  q quit")
             .alignment(Alignment::Center)
             .style(Style::default().fg(Color::DarkGray))
-            .render(chunks[3], buf);
+            .render(help, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ft_otp::{FixedClock, KeyFile};
+    use std::sync::Arc;
+
+    fn entry(label: &str, issuer: Option<&str>, kind: OtpKind) -> Entry {
+        let mut key = KeyFile::new(b"12345678901234567890".to_vec(), TotpParams::default());
+        key.kind = kind;
+        Entry {
+            label: label.to_string(),
+            issuer: issuer.map(String::from),
+            key,
+            tags: Vec::new(),
+            icon: None,
+        }
+    }
+
+    fn screen(app: &App) -> String {
+        let area = Rect::new(0, 0, 60, 12);
+        let mut buf = Buffer::empty(area);
+        app.render(area, &mut buf);
+        (0..area.height)
+            .map(|y| {
+                (0..area.width)
+                    .map(|x| buf[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn lists_accounts_with_codes() {
+        let mut app = App::new(
+            vec![
+                entry("alice", Some("GitHub"), OtpKind::Totp),
+                entry("bank", None, OtpKind::Hotp),
+            ],
+            Box::new(FixedClock::new(59)),
+        );
+        let text = screen(&app);
+        assert!(text.contains("> GitHub: alice"));
+        assert!(text.contains("287082"));
+        assert!(text.contains("1s"));
+        assert!(text.contains("counter-based"));
+
+        app.show_next = true;
+        assert!(screen(&app).contains("next 359152"));
+    }
+
+    #[test]
+    fn codes_follow_the_clock() {
+        let clock = Arc::new(FixedClock::new(59));
+        let mut app = App::new(
+            vec![entry("alice", None, OtpKind::Totp)],
+            Box::new(Arc::clone(&clock)),
+        );
+        assert!(screen(&app).contains("287082"));
+
+        clock.advance(1);
+        app.tick();
+        let text = screen(&app);
+        assert!(text.contains("359152"));
+        assert!(text.contains("30s"));
+
+        app.warning = Some("warning: the clock is 12s behind".to_string());
+        assert!(screen(&app).contains("12s behind"));
+    }
+
+    #[test]
+    fn search_filters_and_reorders() {
+        let mut app = App::new(
+            vec![
+                entry("gitlab", None, OtpKind::Totp),
+                entry("github", None, OtpKind::Totp),
+                entry("bank", None, OtpKind::Totp),
+            ],
+            Box::new(FixedClock::new(59)),
+        );
+        app.query = "ghub".to_string();
+        app.filter();
+        assert_eq!(app.visible, vec![1]);
+
+        app.query = "g".to_string();
+        app.filter();
+        assert_eq!(app.visible, vec![0, 1]);
+        assert!(!screen(&app).contains("bank"));
+    }
+
+    #[test]
+    fn fuzzy_matches_in_order() {
+        assert!(fuzzy_score("gh", "GitHub: alice").is_some());
+        assert!(fuzzy_score("hg", "github").is_none());
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn fuzzy_prefers_tight_matches() {
+        let tight = fuzzy_score("git", "github").unwrap();
+        let loose = fuzzy_score("git", "google maps italy").unwrap();
+        assert!(tight > loose);
+
+        let word_start = fuzzy_score("b", "my bank").unwrap();
+        let inside = fuzzy_score("b", "hub").unwrap();
+        assert!(word_start > inside);
     }
 }
@@ -0,0 +1,131 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    cipher,
+    error::{Error, Result},
+    keyfile::KeyFile,
+    storage,
+};
+
+pub const DEFAULT_VAULT: &str = "ft_otp.vault";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(flatten)]
+    pub key: KeyFile,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// A `data:` URI, or the name of an icon in the exporting app's set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Vault {
+    pub entries: Vec<Entry>,
+}
+
+impl Vault {
+    /// A missing vault file is treated as an empty vault so `add` can create it.
+    pub fn load(path: &Path, passphrase: &str) -> Result<Vault> {
+        if !path.exists() {
+            return Ok(Vault::default());
+        }
+
+        let data = fs::read(path)?;
+        let plaintext = cipher::decrypt_key(&data, passphrase)?;
+        let vault: Vault = serde_json::from_slice(&plaintext)?;
+        for entry in &vault.entries {
+            entry.key.params.validate()?;
+        }
+        Ok(vault)
+    }
+
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<()> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(self)?);
+        let encrypted = cipher::encrypt_for(path, &plaintext, passphrase)?;
+        storage::write_atomic(path, &encrypted)?;
+        Ok(())
+    }
+
+    pub fn position(&self, label: &str) -> Result<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.label == label)
+            .ok_or_else(|| Error::NotFound(label.to_string()))
+    }
+
+    pub fn get(&self, label: &str) -> Result<&Entry> {
+        Ok(&self.entries[self.position(label)?])
+    }
+
+    pub fn add(&mut self, entry: Entry) -> Result<()> {
+        if self.entries.iter().any(|e| e.label == entry.label) {
+            return Err(Error::AlreadyExists(entry.label));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, label: &str) -> Result<Entry> {
+        let index = self.position(label)?;
+        Ok(self.entries.remove(index))
+    }
+
+    pub fn rename(&mut self, label: &str, new_label: &str) -> Result<()> {
+        if self.entries.iter().any(|e| e.label == new_label) {
+            return Err(Error::AlreadyExists(new_label.to_string()));
+        }
+        let index = self.position(label)?;
+        self.entries[index].label = new_label.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::totp::TotpParams;
+
+    fn entry(label: &str) -> Entry {
+        Entry {
+            label: label.to_string(),
+            issuer: None,
+            key: KeyFile::new(vec![0; 20], TotpParams::default()),
+            tags: Vec::new(),
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn labels_stay_unique() {
+        let mut vault = Vault::default();
+        vault.add(entry("github")).unwrap();
+        vault.add(entry("gitlab")).unwrap();
+
+        assert!(vault.add(entry("github")).is_err());
+        assert!(vault.rename("gitlab", "github").is_err());
+
+        vault.rename("gitlab", "work").unwrap();
+        assert!(vault.get("gitlab").is_err());
+        assert_eq!(vault.remove("work").unwrap().label, "work");
+        assert_eq!(vault.entries.len(), 1);
+    }
+
+    #[test]
+    fn serializes_flat_entries() {
+        let mut vault = Vault::default();
+        vault.add(entry("github")).unwrap();
+
+        let json = serde_json::to_string(&vault).unwrap();
+        let restored: Vault = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.get("github").unwrap().key.params.digits, 6);
+        assert!(json.contains("\"algorithm\":\"SHA1\""));
+    }
+}
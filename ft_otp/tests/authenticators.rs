@@ -0,0 +1,275 @@
+//! Importers for other authenticator apps, run against fixture exports built
+//! with each app's documented layout and cryptography. The fixtures hold the
+//! RFC test seeds, so imported accounts must produce the RFC codes.
+
+use std::{fs, path::Path};
+
+use ft_otp::{
+    Algorithm, Entry, FixedClock, OtpKind,
+    authenticators::{Format, Imported},
+    totp::Encoding,
+};
+
+const PASSWORD: &str = "correct horse";
+
+fn fixture(name: &str) -> (std::path::PathBuf, Vec<u8>) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let data = fs::read(&path).unwrap();
+    (path, data)
+}
+
+fn read(name: &str, format: Format, password: Option<&str>) -> Imported {
+    let (path, data) = fixture(name);
+    assert_eq!(Format::detect(&path, &data), Some(format), "{}", name);
+    assert_eq!(format.is_encrypted(&data).unwrap(), password.is_some());
+    format.read(&data, password).unwrap()
+}
+
+fn find<'a>(imported: &'a Imported, label: &str) -> &'a Entry {
+    imported
+        .entries
+        .iter()
+        .find(|entry| entry.label == label)
+        .unwrap_or_else(|| panic!("no account '{}'", label))
+}
+
+fn code_at(entry: &Entry, time: u64) -> String {
+    let mut key = entry.key.clone();
+    key.next_code_with(&FixedClock::new(time)).unwrap()
+}
+
+fn labels(imported: &Imported) -> Vec<&str> {
+    imported
+        .entries
+        .iter()
+        .map(|entry| entry.label.as_str())
+        .collect()
+}
+
+fn check_aegis(imported: &Imported) {
+    assert_eq!(
+        labels(imported),
+        ["alice@example.com", "bob", "vpn", "gamer"]
+    );
+    assert_eq!(imported.skipped.len(), 1);
+    assert!(imported.skipped[0].contains("'legacy'"));
+    assert!(imported.skipped[0].contains("motp"));
+
+    let alice = find(imported, "alice@example.com");
+    assert_eq!(alice.issuer.as_deref(), Some("GitHub"));
+    assert_eq!(alice.tags, ["Work"]);
+    assert!(
+        alice
+            .icon
+            .as_deref()
+            .unwrap()
+            .starts_with("data:image/svg+xml;base64,")
+    );
+    assert_eq!(code_at(alice, 59), "94287082");
+
+    let bob = find(imported, "bob");
+    assert_eq!(bob.key.params.algorithm, Algorithm::Sha256);
+    assert_eq!(bob.key.params.period, 60);
+    assert_eq!(code_at(bob, 119), "46119246");
+
+    let vpn = find(imported, "vpn");
+    assert_eq!(vpn.key.kind, OtpKind::Hotp);
+    assert_eq!(vpn.key.counter, 7);
+    assert_eq!(vpn.issuer, None);
+    assert_eq!(code_at(vpn, 0), "162583");
+
+    let gamer = find(imported, "gamer");
+    assert_eq!(gamer.key.params.encoding, Encoding::Steam);
+    assert_eq!(gamer.key.params.digits, 5);
+}
+
+#[test]
+fn aegis_plain_vault() {
+    check_aegis(&read("aegis_plain.json", Format::Aegis, None));
+}
+
+#[test]
+fn aegis_encrypted_vault() {
+    check_aegis(&read("aegis_encrypted.json", Format::Aegis, Some(PASSWORD)));
+
+    let (_, data) = fixture("aegis_encrypted.json");
+    let error = Format::Aegis.read(&data, Some("wrong")).unwrap_err();
+    assert!(error.to_string().contains("wrong password"), "{}", error);
+    assert!(Format::Aegis.read(&data, None).is_err());
+}
+
+fn check_andotp(imported: &Imported) {
+    assert_eq!(labels(imported), ["carol", "door", "player"]);
+    assert!(imported.skipped.is_empty());
+
+    let carol = find(imported, "carol");
+    assert_eq!(carol.issuer.as_deref(), Some("GitLab"));
+    assert_eq!(carol.tags, ["work", "dev"]);
+    assert_eq!(carol.icon.as_deref(), Some("Gitlab"));
+    assert_eq!(code_at(carol, 59), "287082");
+
+    let door = find(imported, "door");
+    assert_eq!(door.key.kind, OtpKind::Hotp);
+    assert_eq!(door.key.counter, 3);
+    assert_eq!(door.icon, None);
+    assert_eq!(code_at(door, 0), "969429");
+
+    let player = find(imported, "player");
+    assert_eq!(player.key.params.encoding, Encoding::Steam);
+}
+
+#[test]
+fn andotp_plain_backup() {
+    check_andotp(&read("andotp.json", Format::AndOtp, None));
+}
+
+#[test]
+fn andotp_encrypted_backup() {
+    check_andotp(&read("andotp.json.aes", Format::AndOtp, Some(PASSWORD)));
+
+    let (_, data) = fixture("andotp.json.aes");
+    assert!(Format::AndOtp.read(&data, Some("wrong")).is_err());
+}
+
+fn check_twofas(imported: &Imported) {
+    assert_eq!(labels(imported), ["dave@example.com", "Forum", "root"]);
+    assert!(imported.skipped.is_empty());
+
+    let dave = find(imported, "dave@example.com");
+    assert_eq!(dave.issuer.as_deref(), Some("Dropbox"));
+    assert_eq!(dave.tags, ["Personal"]);
+    assert_eq!(code_at(dave, 59), "287082");
+
+    let forum = find(imported, "Forum");
+    assert_eq!(forum.issuer.as_deref(), Some("Forum"));
+    assert_eq!(forum.key.params.algorithm, Algorithm::Sha512);
+    assert_eq!(forum.key.params.digits, 8);
+    assert!(forum.tags.is_empty());
+
+    let root = find(imported, "root");
+    assert_eq!(root.key.kind, OtpKind::Hotp);
+    assert_eq!(root.key.counter, 11);
+}
+
+#[test]
+fn twofas_plain_backup() {
+    check_twofas(&read("2fas_plain.2fas", Format::TwoFas, None));
+}
+
+#[test]
+fn twofas_encrypted_backup() {
+    check_twofas(&read("2fas_encrypted.2fas", Format::TwoFas, Some(PASSWORD)));
+
+    let (_, data) = fixture("2fas_encrypted.2fas");
+    assert!(Format::TwoFas.read(&data, Some("wrong")).is_err());
+}
+
+fn check_bitwarden(imported: &Imported) {
+    // The login without a TOTP secret and the secure note are not accounts.
+    assert_eq!(labels(imported), ["erin", "frank@example.com", "Steam"]);
+    assert_eq!(imported.skipped.len(), 1);
+    assert!(imported.skipped[0].contains("'Broken'"));
+
+    let erin = find(imported, "erin");
+    assert_eq!(erin.issuer.as_deref(), Some("AWS"));
+    assert_eq!(erin.tags, ["Infra"]);
+    assert_eq!(code_at(erin, 59), "287082");
+
+    let frank = find(imported, "frank@example.com");
+    assert_eq!(frank.issuer.as_deref(), Some("Mail"));
+    assert_eq!(code_at(frank, 59), "287082");
+
+    let steam = find(imported, "Steam");
+    assert_eq!(steam.issuer, None);
+    assert_eq!(steam.key.params.encoding, Encoding::Steam);
+}
+
+#[test]
+fn bitwarden_json_export() {
+    check_bitwarden(&read("bitwarden.json", Format::Bitwarden, None));
+}
+
+#[test]
+fn bitwarden_password_protected_export() {
+    check_bitwarden(&read(
+        "bitwarden_encrypted.json",
+        Format::Bitwarden,
+        Some(PASSWORD),
+    ));
+
+    let (_, data) = fixture("bitwarden_encrypted.json");
+    let error = Format::Bitwarden.read(&data, Some("wrong")).unwrap_err();
+    assert!(error.to_string().contains("wrong password"), "{}", error);
+}
+
+/// Reads `name` with `change` applied to its JSON, expecting a refusal that
+/// mentions `message`.
+fn refused(name: &str, format: Format, change: impl Fn(&mut serde_json::Value), message: &str) {
+    let (_, data) = fixture(name);
+    let mut json: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    change(&mut json);
+    let data = serde_json::to_vec(&json).unwrap();
+    let error = format.read(&data, Some(PASSWORD)).unwrap_err();
+    assert!(error.to_string().contains(message), "{}", error);
+}
+
+#[test]
+fn oversized_aegis_scrypt_parameters_are_refused() {
+    for (field, value) in [("n", 1u64 << 30), ("r", 64), ("p", 17)] {
+        refused(
+            "aegis_encrypted.json",
+            Format::Aegis,
+            |json| json["header"]["slots"][1][field] = value.into(),
+            "exceed the limit",
+        );
+    }
+}
+
+#[test]
+fn oversized_bitwarden_kdf_parameters_are_refused() {
+    let name = "bitwarden_encrypted.json";
+    refused(
+        name,
+        Format::Bitwarden,
+        |json| json["kdfIterations"] = u32::MAX.into(),
+        "PBKDF2 iterations",
+    );
+    let argon2 = |iterations: u32, memory: u32, parallelism: u32| {
+        move |json: &mut serde_json::Value| {
+            json["kdfType"] = 1.into();
+            json["kdfIterations"] = iterations.into();
+            json["kdfMemory"] = memory.into();
+            json["kdfParallelism"] = parallelism.into();
+        }
+    };
+    refused(
+        name,
+        Format::Bitwarden,
+        argon2(u32::MAX, 64, 4),
+        "Argon2 iterations",
+    );
+    refused(
+        name,
+        Format::Bitwarden,
+        argon2(3, 1 << 22, 4),
+        "Argon2 memory",
+    );
+    refused(
+        name,
+        Format::Bitwarden,
+        argon2(3, 64, 1 << 16),
+        "Argon2 parallelism",
+    );
+}
+
+#[test]
+fn other_files_are_not_detected() {
+    let path = Path::new("accounts.txt");
+    assert_eq!(Format::detect(path, b"otpauth://totp/x?secret=AAAA"), None);
+    assert_eq!(Format::detect(path, br#"{"entries": []}"#), None);
+    assert_eq!(Format::detect(path, b""), None);
+    assert_eq!("2FAS".parse::<Format>().unwrap(), Format::TwoFas);
+    assert!("keepass".parse::<Format>().is_err());
+}
@@ -0,0 +1,217 @@
+//! RFC 4226 (HOTP) and RFC 6238 (TOTP) conformance: the published vectors,
+//! time step edges, far-future timestamps and every key length from 1 to 128
+//! bytes, checked against a reference written straight from the RFCs.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use ft_otp::{
+    Algorithm, Clock, FixedClock, Hotp, KeyFile, OffsetClock, Totp, TotpParams, Verification, totp,
+};
+
+const SHA1_SEED: &[u8] = b"12345678901234567890";
+const SHA256_SEED: &[u8] = b"12345678901234567890123456789012";
+const SHA512_SEED: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha512];
+
+/// RFC 4226 section 5.3, independent of the library's HMAC plumbing.
+fn reference(key: &[u8], counter: u64, algorithm: Algorithm, digits: u32) -> String {
+    let message = counter.to_be_bytes();
+    let hash = match algorithm {
+        Algorithm::Sha1 => {
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+            mac.update(&message);
+            mac.finalize().into_bytes().to_vec()
+        }
+        Algorithm::Sha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(&message);
+            mac.finalize().into_bytes().to_vec()
+        }
+        Algorithm::Sha512 => {
+            let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+            mac.update(&message);
+            mac.finalize().into_bytes().to_vec()
+        }
+    };
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    let code = binary as u64 % 10_u64.pow(digits);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+fn params(algorithm: Algorithm, digits: u32) -> TotpParams {
+    TotpParams {
+        algorithm,
+        digits,
+        ..TotpParams::default()
+    }
+}
+
+#[test]
+fn rfc4226_appendix_d() {
+    let truncated = [
+        1284755224, 1094287082, 137359152, 1726969429, 1640338314, 868254676, 1918287922, 82162583,
+        673399871, 645520489,
+    ];
+    let codes = [
+        "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871",
+        "520489",
+    ];
+
+    let hotp = Hotp::builder(SHA1_SEED).build().unwrap();
+    for counter in 0..10 {
+        let i = counter as usize;
+        assert_eq!(
+            totp::truncate(SHA1_SEED, counter, Algorithm::Sha1).unwrap(),
+            truncated[i]
+        );
+        assert_eq!(hotp.generate(counter).unwrap(), codes[i]);
+        assert_eq!(reference(SHA1_SEED, counter, Algorithm::Sha1, 6), codes[i]);
+    }
+}
+
+#[test]
+fn rfc6238_appendix_b() {
+    let vectors: [(u64, [&str; 3]); 6] = [
+        (59, ["94287082", "46119246", "90693936"]),
+        (1111111109, ["07081804", "68084774", "25091201"]),
+        (1111111111, ["14050471", "67062674", "99943326"]),
+        (1234567890, ["89005924", "91819424", "93441116"]),
+        (2000000000, ["69279037", "90698825", "38618901"]),
+        (20000000000, ["65353130", "77737706", "47863826"]),
+    ];
+    let seeds = [SHA1_SEED, SHA256_SEED, SHA512_SEED];
+
+    for (time, expected) in vectors {
+        for ((algorithm, seed), code) in ALGORITHMS.iter().zip(seeds).zip(expected) {
+            let clock = FixedClock::new(time);
+            let params = params(*algorithm, 8);
+            assert_eq!(totp::totp_with(seed, &params, &clock).unwrap(), code);
+
+            let generator = Totp::builder(seed)
+                .algorithm(*algorithm)
+                .digits(8)
+                .build()
+                .unwrap();
+            assert_eq!(generator.generate_with(&clock).unwrap(), code);
+            assert_eq!(generator.generate_at(time).unwrap(), code);
+        }
+    }
+}
+
+#[test]
+fn codes_change_exactly_at_step_edges() {
+    let params = TotpParams::default();
+    let code = |time| totp::totp_at(SHA1_SEED, &params, time).unwrap();
+
+    for step in 1..=5u64 {
+        let edge = step * 30;
+        assert_eq!(params.counter_at(edge - 1), step - 1);
+        assert_eq!(params.counter_at(edge), step);
+        assert_eq!(params.time_remaining(edge - 1), 1);
+        assert_eq!(params.time_remaining(edge), 30);
+        assert_eq!(code(edge - 30), code(edge - 1));
+        assert_ne!(code(edge - 1), code(edge));
+        assert_eq!(code(edge), reference(SHA1_SEED, step, Algorithm::Sha1, 6));
+    }
+}
+
+#[test]
+fn t0_shifts_the_steps() {
+    let params = TotpParams {
+        t0: 10,
+        ..TotpParams::default()
+    };
+    // Before T0 the first step is used rather than wrapping around.
+    assert_eq!(params.counter_at(0), 0);
+    assert_eq!(params.counter_at(39), 0);
+    assert_eq!(params.counter_at(40), 1);
+    assert_eq!(params.time_remaining(40), 30);
+    assert_eq!(totp::totp_at(SHA1_SEED, &params, 40).unwrap(), "287082");
+}
+
+#[test]
+fn far_future_timestamps() {
+    let times = [
+        u32::MAX as u64,
+        253402300799, // 9999-12-31T23:59:59Z
+        1 << 63,
+        u64::MAX - 1,
+        u64::MAX,
+    ];
+    for time in times {
+        for period in [1, 30, 60, u64::MAX] {
+            let params = TotpParams {
+                period,
+                ..TotpParams::default()
+            };
+            let counter = params.counter_at(time);
+            assert_eq!(counter, time / period);
+            assert!((1..=period).contains(&params.time_remaining(time)));
+            assert_eq!(
+                totp::totp_at(SHA1_SEED, &params, time).unwrap(),
+                reference(SHA1_SEED, counter, Algorithm::Sha1, 6)
+            );
+        }
+    }
+
+    let hotp = Hotp::builder(SHA1_SEED).build().unwrap();
+    assert_eq!(
+        hotp.generate(u64::MAX).unwrap(),
+        reference(SHA1_SEED, u64::MAX, Algorithm::Sha1, 6)
+    );
+}
+
+#[test]
+fn every_key_length_from_1_to_128_bytes() {
+    let counters = [0, 1, 1 << 32, u64::MAX];
+    for len in 1..=128usize {
+        let key: Vec<u8> = (0..len).map(|i| (i * 7 + len) as u8).collect();
+        for algorithm in ALGORITHMS {
+            for digits in [6, 8, 10] {
+                let hotp = Hotp::builder(key.clone())
+                    .algorithm(algorithm)
+                    .digits(digits)
+                    .build()
+                    .unwrap();
+                for counter in counters {
+                    assert_eq!(
+                        hotp.generate(counter).unwrap(),
+                        reference(&key, counter, algorithm, digits),
+                        "{} byte key, {}, {} digits, counter {}",
+                        len,
+                        algorithm,
+                        digits,
+                        counter
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn clocks_drive_generation_and_verification() {
+    let clock = FixedClock::new(59);
+    let mut key = KeyFile::new(SHA1_SEED.to_vec(), TotpParams::default());
+    assert_eq!(key.next_code_with(&clock).unwrap(), "287082");
+
+    clock.advance(1);
+    assert_eq!(key.next_code_with(&clock).unwrap(), "359152");
+    assert_eq!(
+        key.verify_with("359152", 0, &clock).unwrap(),
+        Verification::Accepted { offset: 0 }
+    );
+    assert_eq!(
+        key.verify_with("359152", 0, &clock).unwrap(),
+        Verification::Replayed
+    );
+
+    // A clock running 30 seconds behind sees the previous step.
+    let behind = OffsetClock::new(&clock, -30);
+    assert_eq!(behind.now(), 30);
+    assert_eq!(key.next_code_with(&behind).unwrap(), "287082");
+}
@@ -0,0 +1,45 @@
+//! Runs `ft_otp -g` the way a user enrolls a key: the recovery codes it
+//! prints must work once in place of a code.
+
+use std::{env, fs, path::Path, process::Command};
+
+fn ft_otp(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ft_otp"))
+        .args(args)
+        .current_dir(dir)
+        .env("PASSPHRASE", "passphrase")
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn generate_prints_recovery_codes() {
+    let dir = env::temp_dir().join(format!("ft_otp-generate-it-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("key.hex"),
+        "3132333435363738393031323334353637383930\n",
+    )
+    .unwrap();
+
+    let stdout = ft_otp(&dir, &["-g", "key.hex", "--recovery-codes", "2"]);
+    let codes: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("  "))
+        .collect();
+    assert_eq!(codes.len(), 2, "{}", stdout);
+
+    let accepted = ft_otp(&dir, &["verify", codes[0], "-k", "ft_otp.key"]);
+    assert_eq!(accepted.trim(), "Recovery code accepted, 1 left");
+    let status = ft_otp(&dir, &["recovery", "status", "-k", "ft_otp.key"]);
+    assert_eq!(status.trim(), "1 recovery codes left");
+    fs::remove_dir_all(dir).unwrap();
+}
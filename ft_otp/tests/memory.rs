@@ -0,0 +1,126 @@
+//! Checks that secret buffers are wiped before their memory is handed back to
+//! the allocator. The bytes are inspected inside `dealloc`, while the block is
+//! still owned, so nothing reads freed memory.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use ft_otp::{Entry, KeyFile, Passphrase, Secret, TotpParams, cipher, migration};
+
+struct Watcher;
+
+/// Address of the block to inspect, 0 when nothing is watched.
+static WATCHED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicBool = AtomicBool::new(false);
+static ZEROED: AtomicBool = AtomicBool::new(false);
+
+/// The allocator state is global, so the tests take turns.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Watcher {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if ptr as usize == WATCHED.load(Ordering::SeqCst) {
+            let block = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+            ZEROED.store(block.iter().all(|&b| b == 0), Ordering::SeqCst);
+            FREED.store(true, Ordering::SeqCst);
+            WATCHED.store(0, Ordering::SeqCst);
+        }
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Watcher = Watcher;
+
+/// Drops `value` and reports whether the block at `ptr` was all zeroes when it
+/// was freed.
+fn zeroed_on_drop<T>(value: T, ptr: *const u8) -> bool {
+    FREED.store(false, Ordering::SeqCst);
+    WATCHED.store(ptr as usize, Ordering::SeqCst);
+    drop(value);
+    assert!(FREED.load(Ordering::SeqCst), "buffer was not freed");
+    ZEROED.load(Ordering::SeqCst)
+}
+
+#[test]
+fn secret_is_zeroed_after_drop() {
+    let _serial = SERIAL.lock().unwrap();
+    let secret = Secret::new(vec![0xa5; 32]);
+    let ptr = secret.expose().as_ptr();
+    assert!(zeroed_on_drop(secret, ptr));
+}
+
+#[test]
+fn passphrase_is_zeroed_after_drop() {
+    let _serial = SERIAL.lock().unwrap();
+    let passphrase = Passphrase::new("correct horse battery staple".to_string());
+    let ptr = passphrase.expose().as_ptr();
+    assert!(zeroed_on_drop(passphrase, ptr));
+}
+
+#[test]
+fn key_file_buffers_are_zeroed_after_drop() {
+    let _serial = SERIAL.lock().unwrap();
+    let key = KeyFile::new(vec![0x5a; 20], TotpParams::default());
+
+    let plaintext = key.to_bytes().unwrap();
+    let ptr = plaintext.as_ptr();
+    assert!(zeroed_on_drop(plaintext, ptr));
+
+    let ptr = key.secret.expose().as_ptr();
+    assert!(zeroed_on_drop(key, ptr));
+}
+
+#[test]
+fn decrypted_plaintext_is_zeroed_after_drop() {
+    let _serial = SERIAL.lock().unwrap();
+    let kdf = cipher::KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    let encrypted = cipher::encrypt_key_with(b"secret", "passphrase", &kdf).unwrap();
+
+    let plaintext = cipher::decrypt_key(&encrypted, "passphrase").unwrap();
+    let ptr = plaintext.as_ptr();
+    assert!(zeroed_on_drop(plaintext, ptr));
+}
+
+#[test]
+fn migration_uris_are_zeroed_after_drop() {
+    let _serial = SERIAL.lock().unwrap();
+    let entry = Entry {
+        label: "alice".to_string(),
+        issuer: Some("Example".to_string()),
+        key: KeyFile::new(vec![0x5a; 20], TotpParams::default()),
+        tags: Vec::new(),
+        icon: None,
+    };
+
+    let mut uris = migration::encode_uris(&[&entry], 1, 7);
+    let uri = uris.pop().unwrap();
+    assert_eq!(
+        migration::decode_uri(&uri).unwrap()[0].key.secret,
+        entry.key.secret
+    );
+    let ptr = uri.as_ptr();
+    assert!(zeroed_on_drop(uri, ptr));
+}
+
+#[test]
+fn plain_vec_is_not_zeroed() {
+    // Sanity check that the watcher can tell the difference.
+    let _serial = SERIAL.lock().unwrap();
+    let bytes = vec![0xa5u8; 32];
+    let ptr = bytes.as_ptr();
+    assert!(!zeroed_on_drop(bytes, ptr));
+}
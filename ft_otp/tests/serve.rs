@@ -0,0 +1,307 @@
+//! Runs `ft_otp serve` and talks to it the way an internal app would: with an
+//! HTTP client and a bearer token on a loopback port, and with plain HTTP/1.1
+//! on a Unix socket.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+
+use ft_otp::{TotpParams, otpauth, totp};
+
+const TOKEN: &str = "app token";
+
+fn private_file(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+}
+
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    /// `http://127.0.0.1:PORT` or `unix:PATH`, as announced on stderr.
+    address: String,
+}
+
+impl Server {
+    fn start(name: &str, args: &[&str]) -> Server {
+        let dir = env::temp_dir().join(format!("ft_otp-serve-it-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let passphrase = dir.join("pam.passphrase");
+        private_file(&passphrase, "system passphrase\n");
+        let token = dir.join("token");
+        private_file(&token, &format!("{}\n", TOKEN));
+
+        // Only the loopback port takes the token; the socket is guarded by
+        // its permissions.
+        let mut command = Command::new(env!("CARGO_BIN_EXE_ft_otp"));
+        command
+            .arg("serve")
+            .arg("--file")
+            .arg(dir.join("users/%u.key"))
+            .arg("--system-passphrase-file")
+            .arg(&passphrase);
+        if args.contains(&"--listen") {
+            command.arg("--token-file").arg(&token);
+        }
+        let mut child = command
+            .args(args)
+            .current_dir(&dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let address = line
+            .trim()
+            .strip_prefix("ft_otp serve listening on ")
+            .unwrap_or_else(|| panic!("unexpected output: {}", line))
+            .to_string();
+        // Keep reading the request log so the server never blocks on it.
+        thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        Server {
+            child,
+            dir,
+            address,
+        }
+    }
+
+    /// Posts `body` with the HTTP client, returning the status, the JSON
+    /// body and the `Retry-After` header.
+    fn post(&self, path: &str, body: Value) -> (u16, Value, Option<String>) {
+        self.post_as(&format!("Bearer {}", TOKEN), path, body)
+    }
+
+    fn post_as(
+        &self,
+        authorization: &str,
+        path: &str,
+        body: Value,
+    ) -> (u16, Value, Option<String>) {
+        let request =
+            ureq::post(&format!("{}{}", self.address, path)).set("Authorization", authorization);
+        let response = match request.send_json(body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => panic!("{}: {}", path, e),
+        };
+        let status = response.status();
+        assert_eq!(response.content_type(), "application/json");
+        let retry_after = response.header("Retry-After").map(str::to_string);
+        (status, response.into_json().unwrap(), retry_after)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn code(enrolled: &Value) -> String {
+    let secret = otpauth::decode_secret(enrolled["secret"].as_str().unwrap()).unwrap();
+    totp::totp(&secret, &TotpParams::default()).unwrap()
+}
+
+fn wrong_code(enrolled: &Value) -> &'static str {
+    match code(enrolled).as_str() {
+        "000000" => "111111",
+        _ => "000000",
+    }
+}
+
+#[test]
+fn enroll_verify_and_revoke() {
+    let server = Server::start("flow", &["--listen", "127.0.0.1:0"]);
+
+    let (status, enrolled, _) = server.post("/users/alice/enroll", json!({ "issuer": "wiki" }));
+    assert_eq!(status, 201, "{}", enrolled);
+    assert_eq!(enrolled["user"], "alice");
+    assert!(
+        enrolled["uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/wiki:alice?secret=")
+    );
+    let key = server.dir.join("users/alice.key");
+    assert_eq!(
+        fs::metadata(&key).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    let (status, again, _) = server.post("/users/alice/enroll", json!({}));
+    assert_eq!(status, 409);
+    assert_eq!(again["error"]["code"], "already_enrolled");
+
+    let code = code(&enrolled);
+    let (status, body, _) = server.post("/users/alice/verify", json!({ "code": code }));
+    assert_eq!((status, body), (200, json!({ "valid": true })));
+    let (_, body, _) = server.post("/users/alice/verify", json!({ "code": code }));
+    assert_eq!(body["valid"], false);
+    assert_eq!(body["reason"], "replayed");
+
+    let (status, body, _) = server.post("/users/alice/revoke", json!({}));
+    assert_eq!((status, body), (200, json!({ "revoked": true })));
+    assert!(!key.exists());
+    let (status, body, _) = server.post("/users/alice/verify", json!({ "code": code }));
+    assert_eq!(status, 404);
+    assert_eq!(body["error"]["code"], "not_enrolled");
+
+    let (status, body, _) = server.post("/users/alice/verify", json!({ "pin": 1234 }));
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["code"], "bad_request");
+}
+
+#[test]
+fn tcp_needs_the_token() {
+    let server = Server::start("token", &["--listen", "127.0.0.1:0"]);
+    for authorization in ["", "Bearer wrong", "Basic YXBwOnRva2Vu"] {
+        let (status, body, _) = server.post_as(
+            authorization,
+            "/users/root/enroll",
+            json!({ "force": true }),
+        );
+        assert_eq!(status, 401);
+        assert_eq!(body["error"]["code"], "unauthorized");
+    }
+    assert!(!server.dir.join("users/root.key").exists());
+}
+
+#[test]
+fn lockout_after_failures() {
+    let server = Server::start(
+        "lockout",
+        &[
+            "--listen",
+            "127.0.0.1:0",
+            "--max-failures",
+            "2",
+            "--lockout",
+            "2",
+        ],
+    );
+    let (_, enrolled, _) = server.post("/users/bob/enroll", json!({}));
+    let wrong = wrong_code(&enrolled);
+
+    let (_, body, _) = server.post("/users/bob/verify", json!({ "code": wrong }));
+    assert_eq!(body["attempts_left"], 1);
+    let (_, body, _) = server.post("/users/bob/verify", json!({ "code": wrong }));
+    assert_eq!(body["attempts_left"], 0);
+
+    // Even the right code is refused while locked out.
+    let (status, body, retry_after) =
+        server.post("/users/bob/verify", json!({ "code": code(&enrolled) }));
+    assert_eq!(status, 429);
+    assert_eq!(body["error"]["code"], "locked_out");
+    assert!(retry_after.is_some());
+
+    // Other users are not affected.
+    let (_, carol, _) = server.post("/users/carol/enroll", json!({}));
+    let (_, body, _) = server.post("/users/carol/verify", json!({ "code": code(&carol) }));
+    assert_eq!(body["valid"], true);
+
+    thread::sleep(Duration::from_millis(2100));
+    let (_, body, _) = server.post("/users/bob/verify", json!({ "code": code(&enrolled) }));
+    assert_eq!(body["valid"], true);
+}
+
+#[test]
+fn rate_limits_attempts() {
+    let server = Server::start(
+        "rate",
+        &["--listen", "127.0.0.1:0", "--attempts-per-minute", "2"],
+    );
+    let (_, enrolled, _) = server.post("/users/dave/enroll", json!({}));
+    let wrong = wrong_code(&enrolled);
+    for _ in 0..2 {
+        let (status, _, _) = server.post("/users/dave/verify", json!({ "code": wrong }));
+        assert_eq!(status, 200);
+    }
+    let (status, body, retry_after) = server.post("/users/dave/verify", json!({ "code": wrong }));
+    assert_eq!(status, 429);
+    assert_eq!(body["error"]["code"], "rate_limited");
+    let seconds: u64 = retry_after.unwrap().parse().unwrap();
+    assert!((1..=60).contains(&seconds));
+}
+
+/// Sends one request over the Unix socket and reads the whole response.
+fn unix_post(socket: &str, path: &str, body: &str) -> (u16, Value) {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn serves_on_a_unix_socket() {
+    let socket = env::temp_dir().join(format!("ft_otp-serve-it-{}.sock", std::process::id()));
+    let server = Server::start("unix", &["--socket", socket.to_str().unwrap()]);
+    let socket = server.address.strip_prefix("unix:").unwrap().to_string();
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+
+    let (status, enrolled) = unix_post(&socket, "/users/erin/enroll", "");
+    assert_eq!(status, 201);
+    let body = json!({ "code": code(&enrolled) }).to_string();
+    let (status, body) = unix_post(&socket, "/users/erin/verify", &body);
+    assert_eq!((status, body), (200, json!({ "valid": true })));
+    drop(server);
+    let _ = fs::remove_file(socket);
+}
+
+fn refused(args: &[&str]) -> String {
+    let passphrase = env::temp_dir().join(format!("ft_otp-serve-it-{}.pass", std::process::id()));
+    private_file(&passphrase, "system passphrase\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_ft_otp"))
+        .arg("serve")
+        .args(args)
+        .arg("--system-passphrase-file")
+        .arg(&passphrase)
+        .current_dir(env::temp_dir())
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    fs::remove_file(passphrase).unwrap();
+    assert!(!output.status.success());
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn refuses_other_interfaces() {
+    let token = env::temp_dir().join(format!("ft_otp-serve-it-{}.token", std::process::id()));
+    private_file(&token, TOKEN);
+    let stderr = refused(&[
+        "--listen",
+        "0.0.0.0:0",
+        "--token-file",
+        token.to_str().unwrap(),
+    ]);
+    fs::remove_file(token).unwrap();
+    assert!(stderr.contains("only loopback"), "{}", stderr);
+
+    let stderr = refused(&["--listen", "127.0.0.1:0"]);
+    assert!(stderr.contains("--token-file"), "{}", stderr);
+}